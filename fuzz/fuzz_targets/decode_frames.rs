@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use unixconn_rust::fuzzing;
+
+// The first byte picks the framing, the second how many bytes arrive per
+// read, and the rest is the incoming stream.
+fuzz_target!(|data: &[u8]| {
+    let (framing, data) = fuzzing::split_framing(data);
+    let Some((&chunk, stream)) = data.split_first() else { return };
+    fuzzing::decode_frames(framing, stream, usize::from(chunk));
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use unixconn_rust::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    fuzzing::parse_message(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use unixconn_rust::fuzzing;
+
+// The first byte picks the framing, the rest is the incoming stream.
+fuzz_target!(|data: &[u8]| {
+    let (framing, stream) = fuzzing::split_framing(data);
+    fuzzing::read_frames(framing, stream);
+});
@@ -0,0 +1,60 @@
+//! systemd socket activation: the listener is bound by systemd and handed
+//! to the process as descriptor 3 (`sd_listen_fds(3)`).
+
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+/// The first descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the unix listener systemd passed to this process, if it was socket
+/// activated. The variables describing it are removed, so child processes
+/// do not mistake it for theirs.
+pub(crate) fn listener() -> io::Result<Option<UnixListener>> {
+    let count = listen_fds(env::var("LISTEN_PID").ok().as_deref(), env::var("LISTEN_FDS").ok().as_deref(), std::process::id())?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("systemd passed {} sockets, expected one", count)));
+    }
+
+    // SAFETY: an fcntl on a descriptor number; an unused one fails with EBADF.
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: systemd hands descriptor 3 to this process to own, and the
+    // variables naming it are gone, so nothing else will take it.
+    Ok(Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) }))
+}
+
+/// How many descriptors `LISTEN_PID` and `LISTEN_FDS` say were passed to the
+/// process `pid`; none if they are unset or meant for another process.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<usize> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(0);
+    };
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        return Ok(0);
+    }
+    listen_fds.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid LISTEN_FDS: {:?}", listen_fds)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_are_only_taken_when_meant_for_this_process() {
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42).unwrap(), 1);
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(None, Some("1"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42).unwrap(), 0);
+        assert!(listen_fds(Some("42"), Some("one"), 42).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Runtime-agnostic async client.
+//!
+//! The protocol code here only needs a connection implementing [`AsyncRead`]
+//! and [`AsyncWrite`], so it can be driven by any executor. The traits have
+//! the same shape as the ones in `futures::io`, which makes adapting tokio,
+//! async-std or custom transports a matter of forwarding the poll methods.
+//!
+//! There is no timer here: wrap calls in your runtime's timeout if needed.
+
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::protocol::{Decoder, Encoder, Framing, Message};
+
+const READ_CHUNK: usize = 4096;
+
+pub trait AsyncRead {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+}
+
+pub trait AsyncWrite {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>;
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+impl<T: AsyncRead + Unpin + ?Sized> AsyncRead for &mut T {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> AsyncWrite for &mut T {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut **self).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut **self).poll_close(cx)
+    }
+}
+
+/// Async counterpart of [`crate::Client`] over any connection.
+pub struct AsyncClient<T> {
+    conn: T,
+    encoder: Encoder,
+    decoder: Decoder,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncClient<T> {
+    pub fn new(conn: T) -> Self {
+        Self::with_framing(conn, Framing::default())
+    }
+
+    pub fn with_framing(conn: T, framing: Framing) -> Self {
+        AsyncClient { conn, encoder: Encoder::new(framing), decoder: Decoder::new(framing, None) }
+    }
+
+    /// Bounds the size of a response frame, as
+    /// [`crate::Client::set_max_message_size`] does. `None`, the default,
+    /// accepts any size.
+    pub fn set_max_message_size(&mut self, max_len: Option<usize>) {
+        self.decoder.set_max_len(max_len);
+    }
+
+    pub async fn close(&mut self) -> io::Result<()> {
+        poll_fn(|cx| Pin::new(&mut self.conn).poll_close(cx)).await
+    }
+
+    pub fn into_inner(self) -> T {
+        self.conn
+    }
+
+    pub async fn do_request(&mut self, method_name: &str, request_body: &[u8]) -> Result<Bytes> {
+        let request = Message::request(method_name, request_body);
+
+        self.encoder.encode(&request);
+        write_all(&mut self.conn, &mut self.encoder).await?;
+
+        let message = read_message(&mut self.conn, &mut self.decoder).await?;
+
+        message.into_response(&request.request_id)
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(writer: &mut W, encoder: &mut Encoder) -> io::Result<()> {
+    while !encoder.pending().is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, encoder.pending())).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        encoder.advance(n);
+    }
+
+    poll_fn(|cx| Pin::new(&mut *writer).poll_flush(cx)).await
+}
+
+/// Reads one frame, keeping bytes that arrived past its end in `decoder`
+/// for the next call.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R, decoder: &mut Decoder) -> Result<Message> {
+    let mut chunk = [0u8; READ_CHUNK];
+
+    loop {
+        if let Some(message) = decoder.decode()? {
+            return Ok(message);
+        }
+
+        let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut chunk)).await?;
+        if n == 0 {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        decoder.feed(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::task::Waker;
+
+    /// Answers every request frame written to it with an echo of its body,
+    /// handing the response out a few bytes at a time.
+    struct EchoConn {
+        written: Decoder,
+        pending: Encoder,
+    }
+
+    impl EchoConn {
+        fn new(framing: Framing) -> Self {
+            EchoConn { written: Decoder::new(framing, None), pending: Encoder::new(framing) }
+        }
+    }
+
+    impl AsyncRead for EchoConn {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            if self.pending.pending().is_empty() {
+                let request = self.written.decode().unwrap().unwrap();
+                self.pending.encode(&request);
+            }
+            let n = buf.len().min(self.pending.pending().len()).min(3);
+            buf[..n].copy_from_slice(&self.pending.pending()[..n]);
+            self.pending.advance(n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for EchoConn {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.written.feed(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn do_request_round_trip() {
+        let mut client = AsyncClient::new(EchoConn::new(Framing::default()));
+
+        let first = block_on(client.do_request("echo", b"hello")).unwrap();
+        let second = block_on(client.do_request("echo", b"world")).unwrap();
+
+        assert_eq!(&first[..], b"hello");
+        assert_eq!(&second[..], b"world");
+    }
+
+    #[test]
+    fn do_request_with_length_prefixed_framing() {
+        let mut client = AsyncClient::with_framing(EchoConn::new(Framing::LengthPrefixed), Framing::LengthPrefixed);
+
+        let response = block_on(client.do_request("echo", &[0x1F, 0x1E])).unwrap();
+
+        assert_eq!(&response[..], &[0x1F, 0x1E]);
+    }
+
+    #[test]
+    fn responses_over_the_limit_are_refused() {
+        for framing in [Framing::Delimited, Framing::LengthPrefixed] {
+            let mut client = AsyncClient::with_framing(EchoConn::new(framing), framing);
+            client.set_max_message_size(Some(64));
+
+            let result = block_on(client.do_request("echo", &[b'x'; 100]));
+
+            assert!(matches!(result, Err(Error::MessageTooLarge { limit: 64 })), "{:?}", framing);
+        }
+    }
+}
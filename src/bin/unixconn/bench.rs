@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use unixconn_rust::{Client, Error};
+
+use crate::call::read_body;
+use crate::{parse_duration, parse_framing, Args, Failure};
+
+/// What one connection saw over the run.
+#[derive(Default)]
+struct Tally {
+    latencies: Vec<Duration>,
+    errors: BTreeMap<String, u64>,
+}
+
+/// `unixconn bench SOCKET METHOD`: sends requests back to back on
+/// `--concurrency` connections for `--duration`, then reports throughput
+/// and latency percentiles of the calls that succeeded.
+pub(crate) fn run(mut args: Args) -> Result<(), Failure> {
+    let socket = args.positional("SOCKET")?;
+    let method = args.positional("METHOD")?;
+    let mut body = Vec::new();
+    let mut concurrency = 1;
+    let mut duration = Duration::from_secs(10);
+    let mut builder = Client::builder(&socket).timeout(Some(Duration::from_secs(5))).auto_reconnect(true);
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--body" => body = read_body(&args.value(&flag)?)?,
+            "--concurrency" => {
+                let value = args.value(&flag)?;
+                concurrency = value.parse().ok().filter(|&n| n > 0).ok_or_else(|| Failure::Usage(format!("invalid concurrency: {}", value)))?;
+            }
+            "--duration" => duration = parse_duration(&args.value(&flag)?)?,
+            "--timeout" => builder = builder.timeout(Some(parse_duration(&args.value(&flag)?)?)),
+            "--framing" => builder = builder.framing(parse_framing(&args.value(&flag)?)?),
+            _ => return Err(Failure::Usage(format!("unexpected argument: {}", flag))),
+        }
+    }
+
+    // Connecting up front fails the run early if the socket is not there.
+    let clients = (0..concurrency).map(|_| builder.connect()).collect::<Result<Vec<_>, _>>()?;
+    eprintln!("unixconn: calling {} on {} for {:?} over {} connections", method, socket, duration, concurrency);
+    let started = Instant::now();
+    let deadline = started + duration;
+    let workers: Vec<_> = clients
+        .into_iter()
+        .map(|mut client| {
+            let (method, body) = (method.clone(), body.clone());
+            thread::spawn(move || {
+                let mut tally = Tally::default();
+                while Instant::now() < deadline {
+                    let sent = Instant::now();
+                    match client.do_request(&method, &body) {
+                        Ok(_) => tally.latencies.push(sent.elapsed()),
+                        Err(e) => *tally.errors.entry(error_kind(&e)).or_default() += 1,
+                    }
+                }
+                tally
+            })
+        })
+        .collect();
+
+    let mut total = Tally::default();
+    for worker in workers {
+        let tally = worker.join().map_err(|_| Failure::Io("a benchmark thread panicked".to_string()))?;
+        total.latencies.extend(tally.latencies);
+        for (kind, count) in tally.errors {
+            *total.errors.entry(kind).or_default() += count;
+        }
+    }
+    print!("{}", report(&mut total, started.elapsed()));
+    Ok(())
+}
+
+/// Groups errors by kind rather than message, which may hold request IDs.
+fn error_kind(error: &Error) -> String {
+    match error {
+        Error::RemoteError { code, .. } => format!("remote {}", code),
+        Error::Io(e) => format!("io {:?}", e.kind()),
+        Error::Protocol(_) => "protocol".to_string(),
+        Error::RequestIdMismatch { .. } => "request id mismatch".to_string(),
+        Error::Timeout => "timeout".to_string(),
+        Error::MessageTooLarge { .. } => "message too large".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn report(tally: &mut Tally, elapsed: Duration) -> String {
+    tally.latencies.sort_unstable();
+    let succeeded = tally.latencies.len() as u64;
+    let failed: u64 = tally.errors.values().sum();
+    let mut report = format!(
+        "requests:   {} ok, {} failed in {:.2?}\nthroughput: {:.1} req/s\n",
+        succeeded,
+        failed,
+        elapsed,
+        succeeded as f64 / elapsed.as_secs_f64()
+    );
+    if !tally.latencies.is_empty() {
+        report += "latency:   ";
+        for (name, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)] {
+            report += &format!(" {} {:.2?}", name, percentile(&tally.latencies, quantile));
+        }
+        report += &format!(" max {:.2?}\n", tally.latencies[tally.latencies.len() - 1]);
+    }
+    for (kind, count) in &tally.errors {
+        report += &format!("errors:     {} {}\n", count, kind);
+    }
+    report
+}
+
+/// The latency below which `quantile` of the sorted `latencies` fall, by
+/// the nearest-rank method.
+fn percentile(latencies: &[Duration], quantile: f64) -> Duration {
+    let rank = (quantile * latencies.len() as f64).ceil() as usize;
+    latencies[rank.clamp(1, latencies.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let latencies: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 0.999), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies[..1], 0.5), Duration::from_millis(1));
+    }
+}
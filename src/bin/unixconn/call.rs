@@ -0,0 +1,49 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use unixconn_rust::Client;
+
+use crate::{parse_duration, parse_framing, Args, Failure};
+
+/// `unixconn call SOCKET METHOD`: sends one request and prints the
+/// response body as is.
+pub(crate) fn run(mut args: Args) -> Result<(), Failure> {
+    let socket = args.positional("SOCKET")?;
+    let method = args.positional("METHOD")?;
+    let mut body = Vec::new();
+    let mut headers = Vec::new();
+    let mut builder = Client::builder(&socket).timeout(Some(Duration::from_secs(5)));
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--body" => body = read_body(&args.value(&flag)?)?,
+            "--header" => {
+                let header = args.value(&flag)?;
+                let (key, value) = header.split_once('=').ok_or_else(|| Failure::Usage(format!("header without '=': {}", header)))?;
+                headers.push((key.to_string(), value.to_string()));
+            }
+            "--timeout" => builder = builder.timeout(Some(parse_duration(&args.value(&flag)?)?)),
+            "--framing" => builder = builder.framing(parse_framing(&args.value(&flag)?)?),
+            _ => return Err(Failure::Usage(format!("unexpected argument: {}", flag))),
+        }
+    }
+
+    let mut client = builder.connect()?;
+    let headers: Vec<_> = headers.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+    let response = client.do_request_with_headers(&method, &body, &headers)?;
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&response).and_then(|_| stdout.flush()).map_err(|e| Failure::Io(format!("writing the response: {}", e)))
+}
+
+/// `@FILE` reads the body from a file and `@-` from stdin; anything else
+/// is the body itself.
+pub(crate) fn read_body(value: &str) -> Result<Vec<u8>, Failure> {
+    let read = match value.strip_prefix('@') {
+        Some("-") => {
+            let mut body = Vec::new();
+            io::stdin().read_to_end(&mut body).map(|_| body)
+        }
+        Some(path) => std::fs::read(path),
+        None => return Ok(value.as_bytes().to_vec()),
+    };
+    read.map_err(|e| Failure::Io(format!("reading the body from {}: {}", value, e)))
+}
@@ -0,0 +1,157 @@
+//! `unixconn`, for talking to unixconn daemons from a shell.
+
+mod bench;
+mod call;
+mod serve;
+
+use std::fmt;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use unixconn_rust::{Error, Framing};
+
+const USAGE: &str = "\
+usage: unixconn call SOCKET METHOD [--body @FILE|@-|STRING] [--header KEY=VALUE]...
+                     [--timeout DURATION] [--framing delimited|escaped|length-prefixed]
+       unixconn serve SOCKET [--echo] [--responses FILE] [--workers N]
+       unixconn bench SOCKET METHOD [--body @FILE|@-|STRING] [--concurrency N]
+                      [--duration DURATION] [--timeout DURATION] [--framing ...]
+
+serve answers the methods listed in FILE, one `METHOD = BODY` or
+`METHOD ! [CODE:] ERROR` per line, and with --echo any other method with
+its request body.
+
+bench calls METHOD back to back on N connections (1 by default) for the
+duration (10s by default) and reports throughput and latency percentiles.
+
+Durations are given as e.g. 500ms, 5s or 2m; a bare number is seconds.
+
+Exit status: 0 on success, 1 if the server answered with an error, 64 on
+bad usage, 69 if the socket could not be reached, 75 on a timeout and 76
+for any other failure talking to the server.";
+
+/// Why the command failed, which decides its exit status.
+pub(crate) enum Failure {
+    Usage(String),
+    Client(Error),
+    Io(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Usage(_) => 64,
+            Failure::Client(Error::RemoteError { .. }) => 1,
+            Failure::Client(Error::Io(e)) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => 69,
+            Failure::Client(Error::Timeout) => 75,
+            Failure::Client(_) | Failure::Io(_) => 76,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            Failure::Client(e) => write!(f, "{}", e),
+            Failure::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Client(e)
+    }
+}
+
+/// The command line after the subcommand, taken apart one argument at a
+/// time.
+pub(crate) struct Args {
+    args: std::vec::IntoIter<String>,
+}
+
+impl Args {
+    pub(crate) fn new(args: Vec<String>) -> Self {
+        Args { args: args.into_iter() }
+    }
+
+    pub(crate) fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+
+    /// The next positional argument, named `name` in the error if missing.
+    pub(crate) fn positional(&mut self, name: &str) -> Result<String, Failure> {
+        match self.args.next() {
+            Some(arg) if !arg.starts_with("--") => Ok(arg),
+            _ => Err(Failure::Usage(format!("missing {}", name))),
+        }
+    }
+
+    /// The value following `flag`.
+    pub(crate) fn value(&mut self, flag: &str) -> Result<String, Failure> {
+        self.args.next().ok_or_else(|| Failure::Usage(format!("{} needs a value", flag)))
+    }
+}
+
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, Failure> {
+    let invalid = || Failure::Usage(format!("invalid duration: {}", value));
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(at) => value.split_at(at),
+        None => (value, "s"),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+pub(crate) fn parse_framing(value: &str) -> Result<Framing, Failure> {
+    match value {
+        "delimited" => Ok(Framing::Delimited),
+        "escaped" => Ok(Framing::Escaped),
+        "length-prefixed" => Ok(Framing::LengthPrefixed),
+        _ => Err(Failure::Usage(format!("unknown framing: {}", value))),
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("call") => call::run(Args::new(args.collect())),
+        Some("serve") => serve::run(Args::new(args.collect())),
+        Some("bench") => bench::run(Args::new(args.collect())),
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(command) => Err(Failure::Usage(format!("unknown command: {}", command))),
+        None => Err(Failure::Usage("missing command".to_string())),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("unixconn: {}", failure);
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_a_unit_or_default_to_seconds() {
+        assert_eq!(parse_duration("250ms").ok(), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s").ok(), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m").ok(), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("30").ok(), Some(Duration::from_secs(30)));
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}
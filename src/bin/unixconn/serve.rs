@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use unixconn_rust::{CallLogger, Code, Server, Status};
+
+use crate::{Args, Failure};
+
+type Response = Result<Vec<u8>, Status>;
+
+/// `unixconn serve SOCKET`: a stand-in server answering with canned
+/// responses, echoing the request body for other methods with `--echo`.
+pub(crate) fn run(mut args: Args) -> Result<(), Failure> {
+    let socket = args.positional("SOCKET")?;
+    let mut echo = false;
+    let mut responses = HashMap::new();
+    let mut workers = 4;
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--echo" => echo = true,
+            "--responses" => {
+                let path = args.value(&flag)?;
+                let config = std::fs::read_to_string(&path).map_err(|e| Failure::Io(format!("reading {}: {}", path, e)))?;
+                responses.extend(parse_responses(&config).map_err(|e| Failure::Usage(format!("{}: {}", path, e)))?);
+            }
+            "--workers" => {
+                let value = args.value(&flag)?;
+                workers = value.parse().ok().filter(|&n| n > 0).ok_or_else(|| Failure::Usage(format!("invalid worker count: {}", value)))?;
+            }
+            _ => return Err(Failure::Usage(format!("unexpected argument: {}", flag))),
+        }
+    }
+    if !echo && responses.is_empty() {
+        return Err(Failure::Usage("serve needs --echo or --responses".to_string()));
+    }
+
+    let mut server = Server::bind(&socket)?;
+    server.set_workers(workers);
+    server.add_middleware(CallLogger::new().log_bodies(64));
+    for (method, response) in responses {
+        server.register(&method, move |_| response.clone());
+    }
+    if echo {
+        server.register_fallback(|_, body| Ok(body.to_vec()));
+    }
+    // Stopping on Ctrl-C, rather than being killed by it, removes the socket file.
+    server.shutdown_handle().shutdown_on_signals(Duration::from_secs(1)).map_err(|e| Failure::Io(format!("handling signals: {}", e)))?;
+    eprintln!("unixconn: listening on {}", socket);
+    server.run()?;
+    Ok(())
+}
+
+/// Reads canned responses, one method per line:
+///
+/// ```text
+/// # comments and blank lines are skipped
+/// whoami = gateway-1
+/// lookup ! NOT_FOUND: no such user
+/// flaky ! warming up
+/// ```
+///
+/// `=` answers with the rest of the line as the body; `!` fails with it,
+/// under the status code named before a colon or as an unknown error.
+fn parse_responses(config: &str) -> Result<Vec<(String, Response)>, String> {
+    let mut responses = Vec::new();
+    for (number, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = line.find(['=', '!']).ok_or_else(|| format!("line {}: expected METHOD = BODY or METHOD ! ERROR", number + 1))?;
+        let (method, rest) = (line[..at].trim(), line[at + 1..].trim());
+        if method.is_empty() {
+            return Err(format!("line {}: missing method name", number + 1));
+        }
+        let response = match &line[at..at + 1] {
+            "=" => Ok(rest.as_bytes().to_vec()),
+            _ => Err(parse_status(rest)),
+        };
+        responses.push((method.to_string(), response));
+    }
+    Ok(responses)
+}
+
+fn parse_status(error: &str) -> Status {
+    let coded = error.split_once(':').and_then(|(name, message)| {
+        let code = (1..=16).filter_map(Code::from_u16).find(|code| code.as_str() == name.trim())?;
+        Some(Status::new(code, message.trim()))
+    });
+    coded.unwrap_or_else(|| Status::from(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canned_responses_are_bodies_or_errors() {
+        let config = "# stand-in for the user daemon\n\nwhoami = gateway-1\nlookup ! NOT_FOUND: no such user\nflaky ! warming up: 2s\n";
+        let responses = parse_responses(config).unwrap();
+        assert_eq!(responses[0], ("whoami".to_string(), Ok(b"gateway-1".to_vec())));
+        assert_eq!(responses[1].1, Err(Status::new(Code::NotFound, "no such user")));
+        assert_eq!(responses[2].1, Err(Status::from("warming up: 2s")));
+
+        assert!(parse_responses("just a method\n").unwrap_err().starts_with("line 1"));
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+/// Makes a client fail fast while its server looks dead, instead of every
+/// call waiting out its own timeout.
+///
+/// After `failure_threshold` calls in a row fail without an answer, e.g.
+/// on timeouts or a refused connection, calls fail with
+/// [`Error::CircuitOpen`] without being sent until `cool_down` has passed.
+/// The next call is then let through as a probe: an answer, even an error
+/// one, closes the circuit again, while another failure reopens it for a
+/// further `cool_down`.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+        CircuitBreaker { failure_threshold: failure_threshold.max(1), cool_down }
+    }
+}
+
+/// A client's circuit: how many calls failed in a row, and until when it
+/// stays open.
+#[derive(Debug)]
+pub(crate) struct Circuit {
+    breaker: CircuitBreaker,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Circuit {
+    pub(crate) fn new(breaker: CircuitBreaker) -> Self {
+        Circuit { breaker, failures: 0, open_until: None }
+    }
+
+    /// Fails while the circuit is open.
+    pub(crate) fn check(&self) -> Result<()> {
+        let retry_in = self.open_until.map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()));
+        match retry_in.is_zero() {
+            true => Ok(()),
+            false => Err(Error::CircuitOpen { retry_in }),
+        }
+    }
+
+    /// Counts the outcome of a call that was let through.
+    pub(crate) fn record<T>(&mut self, result: &Result<T>) {
+        match result {
+            Ok(_) | Err(Error::RemoteError { .. }) => {
+                self.failures = 0;
+                self.open_until = None;
+            }
+            Err(e) if e.breaks_connection() => {
+                self.failures = self.failures.saturating_add(1);
+                if self.failures >= self.breaker.failure_threshold {
+                    self.open_until = Some(Instant::now() + self.breaker.cool_down);
+                }
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::Client;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn circuits_open_after_consecutive_failures_and_probe_after_cool_down() {
+        let mut circuit = Circuit::new(CircuitBreaker::new(2, Duration::from_millis(50)));
+        circuit.record::<()>(&Err(Error::Timeout));
+        assert!(circuit.check().is_ok());
+        circuit.record::<()>(&Err(Error::Timeout));
+        assert!(matches!(circuit.check(), Err(Error::CircuitOpen { .. })));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(circuit.check().is_ok());
+        circuit.record::<()>(&Err(Error::Timeout));
+        assert!(circuit.check().is_err(), "a failed probe reopens the circuit");
+
+        std::thread::sleep(Duration::from_millis(60));
+        circuit.record(&Ok(()));
+        circuit.record::<()>(&Err(Error::Timeout));
+        assert!(circuit.check().is_ok());
+    }
+
+    #[test]
+    fn clients_stop_waiting_on_a_silent_server() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            // Accepted and never answered.
+            let mut hung = Vec::new();
+            for conn in listener.incoming() {
+                hung.push(conn);
+            }
+        });
+
+        let builder = Client::builder(&path).timeout(Some(Duration::from_millis(50))).auto_reconnect(true);
+        let mut client = builder.circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(60))).connect().unwrap();
+        for _ in 0..2 {
+            assert!(matches!(client.do_request("echo", b""), Err(Error::Timeout)));
+        }
+        let started = Instant::now();
+        assert!(matches!(client.do_request("echo", b""), Err(Error::CircuitOpen { .. })));
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
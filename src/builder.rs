@@ -0,0 +1,250 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::breaker::CircuitBreaker;
+use crate::compression::{Compression, Compressor};
+use crate::error::Result;
+use crate::events::{ConnectionEvent, Observers};
+use crate::interceptor::{Interceptor, Interceptors};
+use crate::mux::MuxClient;
+use crate::protocol::Framing;
+use crate::retry::RetryPolicy;
+use crate::signing::SigningKey;
+use crate::transport::{Dialer, Transport};
+use crate::Client;
+
+/// Configures a [`Client`] before connecting it.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use unixconn_rust::{Client, RetryPolicy};
+/// let client = Client::builder("/tmp/salt-ssd.sock")
+///     .timeout(Some(Duration::from_millis(250)))
+///     .retry_policy(RetryPolicy::new(3))
+///     .connect()?;
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    pub(crate) address: String,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) framing: Framing,
+    pub(crate) auto_reconnect: bool,
+    pub(crate) retry_policy: Option<RetryPolicy>,
+    pub(crate) cancel_frames: bool,
+    pub(crate) propagate_deadline: bool,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) checksums: bool,
+    pub(crate) dialer: Option<Dialer>,
+    pub(crate) handshake: bool,
+    pub(crate) compression: Vec<Compression>,
+    pub(crate) bearer_token: Option<String>,
+    pub(crate) signing_key: Option<SigningKey>,
+    pub(crate) interceptors: Interceptors,
+    pub(crate) observers: Observers,
+    pub(crate) keepalive: Option<Keepalive>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) circuit_breaker: Option<CircuitBreaker>,
+}
+
+/// Heartbeat settings, see [`ClientBuilder::keepalive`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Keepalive {
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+}
+
+impl ClientBuilder {
+    pub(crate) fn new(address: &str) -> Self {
+        ClientBuilder {
+            address: address.to_string(),
+            timeout: None,
+            write_timeout: None,
+            framing: Framing::default(),
+            auto_reconnect: false,
+            retry_policy: None,
+            cancel_frames: false,
+            propagate_deadline: false,
+            max_message_size: None,
+            checksums: false,
+            dialer: None,
+            handshake: false,
+            compression: Vec::new(),
+            bearer_token: None,
+            signing_key: None,
+            interceptors: Interceptors::default(),
+            observers: Observers::default(),
+            keepalive: None,
+            idle_timeout: None,
+            circuit_breaker: None,
+        }
+    }
+
+    /// How long to wait for each response; `None`, the default, waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// See [`Client::set_write_timeout`].
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// See [`Client::set_auto_reconnect`].
+    pub fn auto_reconnect(mut self, enabled: bool) -> Self {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// See [`Client::set_idle_timeout`].
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// See [`Client::set_retry_policy`].
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// See [`Client::set_circuit_breaker`].
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// See [`Client::set_cancel_frames`].
+    pub fn cancel_frames(mut self, enabled: bool) -> Self {
+        self.cancel_frames = enabled;
+        self
+    }
+
+    /// See [`Client::set_propagate_deadline`].
+    pub fn propagate_deadline(mut self, enabled: bool) -> Self {
+        self.propagate_deadline = enabled;
+        self
+    }
+
+    /// See [`Client::set_max_message_size`].
+    pub fn max_message_size(mut self, max_len: usize) -> Self {
+        self.max_message_size = Some(max_len);
+        self
+    }
+
+    /// See [`Client::set_checksums`].
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// See [`Client::set_signing_key`].
+    pub fn signing_key(mut self, key: &[u8]) -> Self {
+        self.signing_key = Some(SigningKey::new(key));
+        self
+    }
+
+    /// Connects through whatever `dial` returns instead of the unix socket
+    /// at the builder's address, on connect and on every reconnect. The
+    /// address is then unused. [`ClientBuilder::connect_multiplexed`] still
+    /// dials the unix socket, and leaves out the other options it lists.
+    pub fn transport<F, T>(mut self, dial: F) -> Self
+    where
+        F: Fn() -> io::Result<T> + Send + Sync + 'static,
+        T: Transport + 'static,
+    {
+        self.dialer = Some(Dialer::new(dial));
+        self
+    }
+
+    /// Negotiates protocol version, framing and features with the server on
+    /// every connect, falling back to the configured framing and no features
+    /// if the server does not take part. See [`Client::negotiated`].
+    pub fn handshake(mut self, enabled: bool) -> Self {
+        self.handshake = enabled;
+        self
+    }
+
+    /// Compresses large request bodies with `compressor` and accepts
+    /// responses compressed with it. The server needs the same codec, see
+    /// [`crate::Server::add_compression`].
+    ///
+    /// Call again to offer more codecs, in order of preference. With the
+    /// [`ClientBuilder::handshake`] the client uses the first one the server
+    /// also has, or none; without it, always the first.
+    pub fn compression(mut self, compressor: Arc<dyn Compressor>) -> Self {
+        self.compression.push(Compression(compressor));
+        self
+    }
+
+    /// See [`Client::set_bearer_token`].
+    pub fn bearer_token(mut self, token: &str) -> Self {
+        self.bearer_token = Some(token.to_string());
+        self
+    }
+
+    /// Runs `interceptor` around every request of the clients built, after
+    /// any added before. Clients built from one builder share it.
+    pub fn interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Calls `observer` whenever a client built connects, loses or replaces
+    /// its connection, or has a call fail, e.g. to alert a supervisor. It
+    /// runs on the thread using the client, which it must not block long.
+    pub fn on_event<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&ConnectionEvent<'_>) + Send + Sync + 'static,
+    {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Checks that a connection idle for `interval` is still answered
+    /// before trusting it again, by a `__ping` that must be answered within
+    /// `timeout`. A missed answer counts as a dead connection, so a server
+    /// that crashed or hung is noticed before a real request is lost to it.
+    ///
+    /// A [`Client`] pings when it is next used, and with
+    /// [`ClientBuilder::auto_reconnect`] sends the request on a fresh
+    /// connection if the ping went unanswered. A
+    /// [`MuxClient`] pings from a background thread, and fails the calls
+    /// waiting on a connection it finds dead.
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some(Keepalive { interval, timeout });
+        self
+    }
+
+    pub fn connect(&self) -> Result<Client> {
+        Client::from_builder(self)
+    }
+
+    /// Connects a [`MuxClient`], which matches responses to requests by ID in
+    /// the background instead of reading them in order.
+    ///
+    /// It takes the address, timeouts, framing, message size limit,
+    /// checksums, signing key, bearer token, interceptors and keepalive
+    /// from the builder, and only the first compression codec. The
+    /// transport, handshake, reconnecting, retries, cancel frames, deadline
+    /// propagation, idle timeout, circuit breaker and event observers are
+    /// ignored.
+    pub fn connect_multiplexed(&self) -> Result<MuxClient> {
+        MuxClient::from_builder(self)
+    }
+
+    /// Builds the client without dialling the socket; the connection is made
+    /// by the first request, so the peer does not have to exist yet.
+    pub fn connect_lazy(&self) -> Client {
+        Client::lazy_from_builder(self)
+    }
+}
@@ -0,0 +1,92 @@
+//! Optional CRC32 of message bodies, to catch corruption by a buggy peer or
+//! a framing mix-up instead of handing garbage to the application.
+//!
+//! A sender with checksums enabled puts the CRC32 (IEEE) of every body it
+//! writes in a header, as eight hex digits. A receiver with checksums enabled
+//! verifies the header wherever it is present, so peers that do not send one
+//! keep working.
+
+use crate::error::{Error, Result};
+use crate::protocol::Message;
+
+const CHECKSUM_HEADER: &str = "__crc32";
+
+/// Lookup table for the reflected IEEE polynomial, one entry per byte value.
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// A copy of `message` with its body's checksum added, ready to be encoded.
+pub(crate) fn sign(message: &Message) -> Message {
+    let mut signed = message.clone();
+    signed.headers.retain(|(key, _)| key != CHECKSUM_HEADER);
+    signed.headers.push((CHECKSUM_HEADER.to_string(), format!("{:08x}", crc32(&message.body))));
+    signed
+}
+
+/// Checks the body of `message` against its checksum, if it carries one.
+pub(crate) fn verify(message: &Message) -> Result<()> {
+    let expected = match message.header(CHECKSUM_HEADER) {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    let actual = crc32(&message.body);
+    match u32::from_str_radix(expected, 16) {
+        Ok(expected) if expected == actual => Ok(()),
+        _ => Err(Error::Protocol(format!("body checksum mismatch: header says {}, body has {:08x}", expected, actual))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Server, Status};
+    use bytes::Bytes;
+    use std::thread;
+
+    #[test]
+    fn crc32_matches_the_ieee_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn corrupted_bodies_are_detected() {
+        let signed = sign(&Message::request("echo", b"payload"));
+        assert!(verify(&signed).is_ok());
+        assert!(verify(&Message::request("echo", b"unsigned")).is_ok());
+
+        let corrupted = signed.with_body(Bytes::from_static(b"paYload"));
+        assert!(matches!(verify(&corrupted), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn checksummed_requests_round_trip() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.set_checksums(true);
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::builder(&path).checksums(true).connect().unwrap();
+        assert_eq!(&client.do_request("echo", b"intact").unwrap()[..], b"intact");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
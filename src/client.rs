@@ -0,0 +1,1289 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, IoSlice, Read};
+use std::net::TcpStream;
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::breaker::{Circuit, CircuitBreaker};
+use crate::builder::{ClientBuilder, Keepalive};
+use crate::cancel::CancelToken;
+use crate::duplex::DuplexCall;
+use crate::codec::Codec;
+use crate::compression::{self, Compression};
+use crate::error::{Error, Result};
+use crate::events::{ConnectionEvent, Observers};
+use crate::fds::{self, FdStream};
+use crate::handshake::{offer, Negotiated, HANDSHAKE};
+use crate::interceptor::{CallOutcome, Interceptor, Interceptors};
+use crate::middleware::AUTHORIZATION_HEADER;
+use crate::pubsub::{publish_body, PUBLISH, SUBSCRIBE};
+use crate::info::{self, ServerInfo, INFO};
+use crate::reflection::{self, MethodInfo, METHODS};
+use crate::protocol::{check_header, check_header_value, Frame, Framing, Message, CANCEL, DEADLINE_HEADER, PING, STREAM_END, TRACE_HEADER};
+use crate::retry::RetryPolicy;
+use crate::signing::{Integrity, SigningKey};
+use crate::stats::{ClientStats, Stats};
+use crate::stream::ResponseStream;
+use crate::subscription::Subscription;
+use crate::trace::{TraceContext, TRACEPARENT_HEADER};
+use crate::transport::{connect_unix, Dialer, Transport};
+
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+/// How long to wait for a handshake answer when no timeout is configured.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a cancellable request checks its token while waiting.
+const CANCEL_POLL: Duration = Duration::from_millis(20);
+
+struct Connection {
+    stream: Box<dyn Transport>,
+    reader: BufReader<Box<dyn Transport>>,
+    /// Frames are read into, one after another.
+    buffer: BytesMut,
+}
+
+pub struct Client {
+    conn: Option<Connection>,
+    address: String,
+    dialer: Option<Dialer>,
+    timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    framing: Framing,
+    /// The configured framing, which `framing` returns to on reconnect.
+    base_framing: Framing,
+    handshake: bool,
+    negotiated: Option<Negotiated>,
+    broken: bool,
+    auto_reconnect: bool,
+    retry_policy: Option<RetryPolicy>,
+    /// Requests written on the current connection and not yet handed back,
+    /// with their response if it was read while waiting for another one.
+    outstanding: HashMap<String, Option<Message>>,
+    /// Cancelled requests whose response is skipped when it arrives.
+    discarded: HashSet<String>,
+    cancel_frames: bool,
+    propagate_deadline: bool,
+    max_message_size: Option<usize>,
+    integrity: Integrity,
+    /// Codecs configured on the builder, by preference.
+    compressors: Vec<Compression>,
+    /// The codec used on the current connection.
+    compression: Option<Compression>,
+    /// Descriptors that came with responses not yet handed back.
+    passed_fds: HashMap<String, Vec<OwnedFd>>,
+    bearer_token: Option<String>,
+    trace_id: Option<String>,
+    trace_context: Option<TraceContext>,
+    interceptors: Interceptors,
+    observers: Observers,
+    stats: Stats,
+    keepalive: Option<Keepalive>,
+    idle_timeout: Option<Duration>,
+    circuit: Option<Circuit>,
+    /// When a frame was last written or read on the current connection.
+    last_active: Instant,
+}
+
+impl Client {
+    /// Connects with a response timeout of `timeout` seconds.
+    pub fn new(address: &str, timeout: u64) -> Result<Self> {
+        Self::with_framing(address, timeout, Framing::default())
+    }
+
+    /// Connects with a response timeout of any precision; `None` waits for
+    /// responses indefinitely.
+    pub fn with_timeout(address: &str, timeout: Option<Duration>) -> Result<Self> {
+        Self::builder(address).timeout(timeout).connect()
+    }
+
+    /// Connects using `framing` instead of the Go-compatible delimited format.
+    /// The server must be configured with the same framing.
+    pub fn with_framing(address: &str, timeout: u64, framing: Framing) -> Result<Self> {
+        Self::builder(address)
+            .timeout(Some(Duration::from_secs(timeout)))
+            .framing(framing)
+            .connect()
+    }
+
+    /// Connects to a server listening on TCP at `address`, e.g.
+    /// `"127.0.0.1:7070"`, where no unix socket can be shared.
+    pub fn connect_tcp(address: &str, timeout: u64) -> Result<Self> {
+        let target = address.to_string();
+        Self::builder(address)
+            .timeout(Some(Duration::from_secs(timeout)))
+            .transport(move || {
+                let stream = TcpStream::connect(&target)?;
+                stream.set_nodelay(true)?;
+                Ok(stream)
+            })
+            .connect()
+    }
+
+    /// Connects to a server listening on a SOCK_SEQPACKET unix socket at
+    /// `path`, which sends every frame as a packet of its own. See
+    /// [`crate::SeqpacketStream`].
+    #[cfg(target_os = "linux")]
+    pub fn connect_seqpacket(path: &str, timeout: u64, framing: Framing) -> Result<Self> {
+        let target = path.to_string();
+        Self::builder(path)
+            .timeout(Some(Duration::from_secs(timeout)))
+            .framing(framing)
+            .transport(move || crate::SeqpacketStream::connect(&target))
+            .connect()
+    }
+
+    /// Connects to a server listening on vsock `port` of the machine with
+    /// context ID `cid`, such as [`crate::VMADDR_CID_HOST`] from a guest.
+    #[cfg(all(target_os = "linux", feature = "vsock"))]
+    pub fn connect_vsock(cid: u32, port: u32, timeout: u64) -> Result<Self> {
+        Self::builder(&format!("vsock:{}:{}", cid, port))
+            .timeout(Some(Duration::from_secs(timeout)))
+            .transport(move || crate::VsockStream::connect(cid, port))
+            .connect()
+    }
+
+    /// Starts configuring a client for the socket at `address`, which on
+    /// Linux may be an abstract name starting with `@`, as with
+    /// [`crate::Server::bind`].
+    pub fn builder(address: &str) -> ClientBuilder {
+        ClientBuilder::new(address)
+    }
+
+    pub(crate) fn from_builder(builder: &ClientBuilder) -> Result<Self> {
+        let mut client = Self::lazy_from_builder(builder);
+        client.reconnect()?;
+        Ok(client)
+    }
+
+    pub(crate) fn lazy_from_builder(builder: &ClientBuilder) -> Self {
+        Client {
+            conn: None,
+            dialer: builder.dialer.clone(),
+            address: builder.address.clone(),
+            timeout: builder.timeout,
+            write_timeout: builder.write_timeout,
+            framing: builder.framing,
+            base_framing: builder.framing,
+            handshake: builder.handshake,
+            negotiated: None,
+            broken: false,
+            auto_reconnect: builder.auto_reconnect,
+            retry_policy: builder.retry_policy.clone(),
+            outstanding: HashMap::new(),
+            discarded: HashSet::new(),
+            cancel_frames: builder.cancel_frames,
+            propagate_deadline: builder.propagate_deadline,
+            max_message_size: builder.max_message_size,
+            integrity: Integrity { checksums: builder.checksums, key: builder.signing_key.clone() },
+            compressors: builder.compression.clone(),
+            compression: builder.compression.first().cloned(),
+            passed_fds: HashMap::new(),
+            bearer_token: builder.bearer_token.clone(),
+            trace_id: None,
+            trace_context: None,
+            stats: Stats::default(),
+            interceptors: builder.interceptors.clone(),
+            observers: builder.observers.clone(),
+            keepalive: builder.keepalive,
+            idle_timeout: builder.idle_timeout,
+            circuit: builder.circuit_breaker.map(Circuit::new),
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Bounds how long sending a request may block on a peer that stopped
+    /// reading. `None`, the default, blocks indefinitely.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    /// Drops a connection that has been idle for `timeout` when the client
+    /// is next used, and dials a fresh one for the request; set it below a
+    /// server's [`crate::Server::set_idle_timeout`] to never send on a
+    /// connection the server is about to close. A connection with responses
+    /// still unread is kept. `None`, the default, keeps connections open.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Fails calls fast with [`Error::CircuitOpen`] while the server looks
+    /// dead, as `breaker` says. Streams and subscriptions are held back
+    /// while the circuit is open but do not count towards opening it. `None`,
+    /// the default, sends every call.
+    pub fn set_circuit_breaker(&mut self, breaker: Option<CircuitBreaker>) {
+        self.circuit = breaker.map(Circuit::new);
+    }
+
+    /// When enabled, a connection found closed by the peer (e.g. after a
+    /// server restart) is replaced by a fresh one to the same path and the
+    /// request is written again, once. Requests whose response was lost are
+    /// never resent, as the server may already have acted on them.
+    pub fn set_auto_reconnect(&mut self, enabled: bool) {
+        self.auto_reconnect = enabled;
+    }
+
+    /// Retries failed requests according to `policy`. A connection broken by
+    /// the failed attempt is re-established before the next one.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry_policy = policy;
+    }
+
+    /// When enabled, cancelling a request also sends the server a cancel
+    /// frame naming it, so a [`crate::Server`] can skip it or stop streaming.
+    /// Off by default, as servers that do not know the frame answer it with
+    /// an error.
+    pub fn set_cancel_frames(&mut self, enabled: bool) {
+        self.cancel_frames = enabled;
+    }
+
+    /// When enabled, requests made with a timeout tell the server how long
+    /// the client will wait, available to handlers as
+    /// [`crate::RequestContext::deadline`]. Off by default, as it adds a
+    /// header field that servers predating headers cannot parse.
+    pub fn set_propagate_deadline(&mut self, enabled: bool) {
+        self.propagate_deadline = enabled;
+    }
+
+    /// Bounds the size of a response frame, and of a compressed body once
+    /// restored, so a misbehaving server cannot make the client buffer
+    /// without limit. A longer one fails with
+    /// [`Error::MessageTooLarge`] and breaks the connection. `None`, the
+    /// default, accepts any size.
+    pub fn set_max_message_size(&mut self, max_len: Option<usize>) {
+        self.max_message_size = max_len;
+    }
+
+    /// When enabled, every frame sent carries a CRC32 of its body, and
+    /// responses carrying one are verified, failing with [`Error::Protocol`]
+    /// if the body was corrupted. Off by default, as it adds a header field
+    /// that servers predating headers cannot parse.
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.integrity.checksums = enabled;
+    }
+
+    /// Signs every frame sent with HMAC-SHA256 under `key`, and refuses
+    /// responses not signed with it, failing with [`Error::Protocol`]. The
+    /// server must have the same key, see [`crate::Server::set_signing_key`].
+    /// `None`, the default, neither signs nor checks.
+    pub fn set_signing_key(&mut self, key: Option<&[u8]>) {
+        self.integrity.key = key.map(SigningKey::new);
+    }
+
+    /// Sends `token` with every request as an `authorization: Bearer` header,
+    /// for a server checking it with [`crate::BearerAuth`]. `None` stops
+    /// sending one. A token containing the bytes 0x1D to 0x1F fails each
+    /// request with [`Error::Protocol`].
+    pub fn set_bearer_token(&mut self, token: Option<&str>) {
+        self.bearer_token = token.map(str::to_string);
+    }
+
+    /// Sends `trace_id` with every request from now on, for the server to
+    /// read with [`crate::RequestContext::trace_id`]; `None` stops sending
+    /// one. A server relaying requests can pass on the ID it was given, so
+    /// a trace follows the request through each process.
+    ///
+    /// Fails with [`Error::Protocol`] if the ID contains the bytes 0x1D to
+    /// 0x1F.
+    pub fn set_trace_id(&mut self, trace_id: Option<&str>) -> Result<()> {
+        if let Some(trace_id) = trace_id {
+            check_header_value(TRACE_HEADER, trace_id)?;
+        }
+        self.trace_id = trace_id.map(str::to_string);
+        Ok(())
+    }
+
+    /// Sends every request from now on as a new span under `context`, in a
+    /// W3C `traceparent` header the server reads with
+    /// [`crate::RequestContext::trace_context`]. `None` stops sending one.
+    pub fn set_trace_context(&mut self, context: Option<TraceContext>) {
+        self.trace_context = context;
+    }
+
+    /// Runs `interceptor` around every request from now on, after those
+    /// added before; see [`crate::ClientBuilder::interceptor`].
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    /// Calls `observer` on every [`ConnectionEvent`] from now on; see
+    /// [`crate::ClientBuilder::on_event`].
+    pub fn on_event<F>(&mut self, observer: F)
+    where
+        F: Fn(&ConnectionEvent<'_>) + Send + Sync + 'static,
+    {
+        self.observers.push(Arc::new(observer));
+    }
+
+    pub fn close(&self) -> io::Result<()> {
+        match &self.conn {
+            Some(conn) => conn.stream.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    /// What the handshake on the current connection agreed on, if one was
+    /// enabled with [`ClientBuilder::handshake`] and the server took part.
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.negotiated.as_ref()
+    }
+
+    /// Counts of what the client sent and received since it was built, and
+    /// how long recent calls took to be answered.
+    pub fn stats(&self) -> ClientStats {
+        self.stats.snapshot()
+    }
+
+    /// Whether a previous request left the connection unusable, e.g. after an
+    /// I/O error or a timeout that may leave a late response unread.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    pub fn do_request(&mut self, method_name: &str, request_body: &[u8]) -> Result<Bytes> {
+        self.request(method_name, request_body, &[], &[], self.timeout, None).map(|(body, _)| body)
+    }
+
+    /// Like [`Client::do_request`], but waits up to `timeout` for this one
+    /// response instead of the client-wide timeout.
+    pub fn do_request_with_timeout(&mut self, method_name: &str, request_body: &[u8], timeout: Duration) -> Result<Bytes> {
+        self.request(method_name, request_body, &[], &[], Some(timeout), None).map(|(body, _)| body)
+    }
+
+    /// Like [`Client::do_request`], with headers for the server to read
+    /// through [`crate::RequestContext::header`], e.g. an auth token or a
+    /// trace ID. Names must be non-empty, must not contain `=` or start with
+    /// `__`, and neither names nor values may contain the bytes 0x1D to 0x1F.
+    ///
+    /// Servers that predate headers cannot parse such requests.
+    pub fn do_request_with_headers(&mut self, method_name: &str, request_body: &[u8], headers: &[(&str, &str)]) -> Result<Bytes> {
+        for (key, value) in headers {
+            check_header(key, value)?;
+        }
+        self.request(method_name, request_body, headers, &[], self.timeout, None).map(|(body, _)| body)
+    }
+
+    /// Like [`Client::do_request`], but gives up with [`Error::Cancelled`]
+    /// soon after `cancel` is cancelled. The late response is skipped when it
+    /// arrives, so the client stays usable.
+    pub fn do_request_cancellable(&mut self, method_name: &str, request_body: &[u8], cancel: &CancelToken) -> Result<Bytes> {
+        self.request(method_name, request_body, &[], &[], self.timeout, Some(cancel)).map(|(body, _)| body)
+    }
+
+    /// Like [`Client::do_request`], passing `fds` to the server along with the
+    /// request (SCM_RIGHTS) and returning the descriptors it passed back. The
+    /// server reads them with [`crate::RequestContext::take_fds`].
+    ///
+    /// Needs a unix socket, either dialed by the client itself or from a
+    /// transport that implements [`Transport::write_with_fds`].
+    pub fn do_request_with_fds(&mut self, method_name: &str, request_body: &[u8], fds: &[BorrowedFd<'_>]) -> Result<(Bytes, Vec<OwnedFd>)> {
+        if fds.len() > fds::MAX_FDS {
+            return Err(Error::Protocol(format!("at most {} descriptors can be passed at once", fds::MAX_FDS)));
+        }
+        self.request(method_name, request_body, &[], fds, self.timeout, None)
+    }
+
+    /// Checks that the server is alive, returning the round-trip time. Every
+    /// server answers without a handler being registered; one that
+    /// predates this answers with an error, which counts as alive too.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let started = Instant::now();
+        match self.request(PING, b"", &[], &[], self.timeout, None) {
+            Ok(_) | Err(Error::RemoteError { .. }) => Ok(started.elapsed()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The methods the server has registered, by name, for debugging and
+    /// generic tools. Servers that predate this answer with
+    /// [`crate::Code::Unimplemented`]. It passes server middleware like any
+    /// other request.
+    pub fn methods(&mut self) -> Result<Vec<MethodInfo>> {
+        reflection::parse(&self.do_request(METHODS, b"")?)
+    }
+
+    /// The server's library version, protocol version and features, for
+    /// telling apart servers of different builds. Servers that predate this
+    /// answer with [`crate::Code::Unimplemented`].
+    pub fn server_info(&mut self) -> Result<ServerInfo> {
+        info::parse(&self.do_request(INFO, b"")?)
+    }
+
+    /// Sends `request` encoded with `codec` and decodes the response with it.
+    /// A response that does not decode fails with [`Error::Protocol`], but
+    /// leaves the connection usable.
+    pub fn call<C, Req, Resp>(&mut self, codec: &C, method_name: &str, request: &Req) -> Result<Resp>
+    where
+        C: Codec<Req> + Codec<Resp>,
+    {
+        let body = codec.encode(request).map_err(|e| Error::Protocol(format!("encoding request: {}", e)))?;
+        let response = self.do_request(method_name, &body)?;
+        Codec::<Resp>::decode(codec, &response).map_err(|e| Error::Protocol(format!("decoding response: {}", e)))
+    }
+
+    /// Writes a request without waiting for its response, so several can be
+    /// in flight on the connection at once. Collect each response with
+    /// [`PendingRequest::wait`], in any order.
+    pub fn send(&mut self, method_name: &str, request_body: &[u8]) -> Result<PendingRequest> {
+        let sent = Instant::now();
+        let request = self.prepare(Message::request(method_name, request_body))?;
+        let result = self.ensure_connected(false).and_then(|_| self.write_request(&request, &[]));
+        if let Err(e) = self.track(result) {
+            self.observe(&request, request_body.len(), sent, Err(e))?;
+        }
+        Ok(PendingRequest { request_id: request.request_id, method_name: request.method_name, request_len: request_body.len(), sent })
+    }
+
+    /// Writes all `requests` in a single burst, then collects their responses.
+    /// Results are in the order of `requests`. A failure that breaks the
+    /// connection is reported for every request not answered before it.
+    pub fn do_batch(&mut self, requests: &[(&str, &[u8])]) -> Vec<Result<Bytes>> {
+        let sent = Instant::now();
+        let messages: Result<Vec<_>> = requests.iter().map(|(method_name, body)| self.prepare(Message::request(method_name, body))).collect();
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(e) => return requests.iter().map(|_| Err(e.duplicate())).collect(),
+        };
+        let written = self.ensure_connected(false).and_then(|_| {
+            let compressed = messages.iter().map(|message| self.compress(message)).collect::<Result<Vec<_>>>()?;
+            // Written together, without copying the bodies into one buffer first.
+            let frames: Vec<Frame> = compressed.iter().map(|message| self.encode(message)).collect();
+            let parts: Vec<IoSlice<'_>> = frames.iter().flat_map(Frame::parts).collect();
+            Ok(self.write_raw(&parts)?)
+        });
+        if let Err(e) = self.track(written) {
+            return messages.iter().zip(requests).map(|(message, (_, body))| self.observe(message, body.len(), sent, Err(e.duplicate()))).collect();
+        }
+        for message in &messages {
+            self.outstanding.insert(message.request_id.clone(), None);
+        }
+        self.stats.requests_sent += messages.len() as u64;
+
+        let mut results: Vec<Result<Bytes>> = Vec::with_capacity(messages.len());
+        for (message, (_, body)) in messages.iter().zip(requests) {
+            let result = match results.last() {
+                Some(Err(e)) if e.breaks_connection() => Err(e.duplicate()),
+                _ => {
+                    let result = self.read_response(&message.request_id, self.timeout, None);
+                    self.track(result)
+                }
+            };
+            results.push(self.observe(message, body.len(), sent, result));
+        }
+        results
+    }
+
+    /// Calls a method the server answers with a stream of body frames (see
+    /// [`crate::Server::register_stream`]). The client timeout applies to
+    /// each frame rather than to the whole stream.
+    pub fn do_request_stream(&mut self, method_name: &str, request_body: &[u8]) -> Result<ResponseStream<'_>> {
+        let request = self.prepare(Message::request(method_name, request_body))?;
+        let result = self.ensure_connected(false).and_then(|_| self.write_message(&request));
+        self.track(result)?;
+        Ok(ResponseStream::new(self, request.request_id))
+    }
+
+    /// Sends the request body read from `body` in chunks, so it never has to
+    /// be held in memory whole, then waits for the single response. The
+    /// server must handle `method_name` with [`crate::Server::register_upload`].
+    pub fn do_request_streamed<R: Read>(&mut self, method_name: &str, body: R) -> Result<Bytes> {
+        let sent = Instant::now();
+        let request = self.prepare(Message::request(method_name, b""))?;
+        let mut counted = Counted { inner: body, len: 0 };
+        let result = self
+            .ensure_connected(false)
+            .and_then(|_| self.compress(&request))
+            .and_then(|request| self.upload(&request, &mut counted))
+            .and_then(|_| self.read_response(&request.request_id, self.timeout, None));
+        let result = self.track(result);
+        self.observe(&request, counted.len, sent, result)
+    }
+
+    /// Opens a bidirectional call to `method_name`, registered on the server
+    /// with [`crate::Server::register_duplex`]. The client is borrowed until
+    /// the call is dropped.
+    pub fn open_duplex(&mut self, method_name: &str) -> Result<DuplexCall<'_>> {
+        let request = self.prepare(Message::request(method_name, b""))?;
+        let result = self.ensure_connected(false).and_then(|_| self.write_message(&request));
+        self.track(result)?;
+        Ok(DuplexCall::new(self, request))
+    }
+
+    /// Subscribes to messages the server pushes for `method_name` (see
+    /// [`crate::Server::register_subscription`]) until either side leaves.
+    pub fn subscribe(&mut self, method_name: &str, request_body: &[u8]) -> Result<Subscription<'_>> {
+        let request = self.prepare(Message::request(method_name, request_body))?;
+        let result = self.ensure_connected(false).and_then(|_| self.write_message(&request));
+        self.track(result)?;
+        Ok(Subscription::new(self, request.request_id))
+    }
+
+    /// Publishes `payload` to a topic served with
+    /// [`crate::Server::register_topics`], returning how many subscribers it
+    /// reached.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<usize> {
+        let body = publish_body(topic, payload)?;
+        let reached = self.do_request(PUBLISH, &body)?;
+        String::from_utf8(reached.to_vec())?
+            .parse()
+            .map_err(|_| Error::Protocol("publish response is not a subscriber count".to_string()))
+    }
+
+    /// Subscribes to payloads published to `topic`.
+    pub fn subscribe_topic(&mut self, topic: &str) -> Result<Subscription<'_>> {
+        self.subscribe(SUBSCRIBE, topic.as_bytes())
+    }
+
+    fn upload<R: Read>(&mut self, request: &Message, mut body: R) -> Result<()> {
+        let mut chunk = vec![0u8; UPLOAD_CHUNK];
+        let mut first = true;
+        loop {
+            let n = match body.read(&mut chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // Frames already sent cannot be taken back.
+                    self.broken = true;
+                    return Err(e.into());
+                }
+            };
+            // The first frame names the method, so it is sent even when empty.
+            if n == 0 && !first {
+                break;
+            }
+            let frame = request.with_body(Bytes::copy_from_slice(&chunk[..n]));
+            self.write_raw(&self.encode(&frame).parts())?;
+            if n == 0 {
+                break;
+            }
+            first = false;
+        }
+
+        let end = request.reply(STREAM_END, Ok(Bytes::new()));
+        self.write_raw(&self.encode(&end).parts())?;
+        self.outstanding.insert(request.request_id.clone(), None);
+        self.stats.requests_sent += 1;
+        Ok(())
+    }
+
+    /// Sends a message the server will not answer and returns as soon as it
+    /// is written. Delivery is not confirmed.
+    pub fn notify(&mut self, method_name: &str, request_body: &[u8]) -> Result<()> {
+        let notification = self.prepare(Message::notification(method_name, request_body))?;
+        let result = self.ensure_connected(false).and_then(|_| self.write_message(&notification));
+        self.track(result)
+    }
+
+    fn request(
+        &mut self,
+        method_name: &str,
+        request_body: &[u8],
+        headers: &[(&str, &str)],
+        fds: &[BorrowedFd<'_>],
+        timeout: Option<Duration>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(Bytes, Vec<OwnedFd>)> {
+        let mut attempt = 1;
+        loop {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                return Err(Error::Cancelled);
+            }
+
+            let sent = Instant::now();
+            let mut request = Message::request(method_name, request_body);
+            request.headers = headers.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            let mut request = self.prepare(request)?;
+            if let Some(timeout) = timeout.filter(|_| self.propagate_deadline) {
+                request.headers.push((DEADLINE_HEADER.to_string(), timeout.as_millis().to_string()));
+            }
+            fds::mark(&mut request, fds.len());
+            let result = self
+                .ensure_connected(attempt > 1)
+                .and_then(|_| self.write_request(&request, fds))
+                .and_then(|_| self.read_response(&request.request_id, timeout, cancel));
+
+            let result = self.track(result);
+            let error = match self.observe(&request, request_body.len(), sent, result) {
+                Ok(body) => return Ok((body, self.passed_fds.remove(&request.request_id).unwrap_or_default())),
+                Err(e) => e,
+            };
+
+            match self.retry_policy.as_ref().and_then(|policy| policy.backoff(attempt, &error)) {
+                Some(delay) => thread::sleep(delay),
+                None => return Err(error),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Adds the bearer token and trace headers, if any, to a request or
+    /// notification, then hands it to the interceptors.
+    fn prepare(&self, mut message: Message) -> Result<Message> {
+        if let Some(token) = &self.bearer_token {
+            let value = format!("Bearer {}", token);
+            check_header(AUTHORIZATION_HEADER, &value)?;
+            message.headers.push((AUTHORIZATION_HEADER.to_string(), value));
+        }
+        if let Some(trace_id) = &self.trace_id {
+            message.headers.push((TRACE_HEADER.to_string(), trace_id.clone()));
+        }
+        if let Some(context) = &self.trace_context {
+            message.headers.push((TRACEPARENT_HEADER.to_string(), context.child().to_string()));
+        }
+        self.interceptors.before(&mut message)?;
+        Ok(message)
+    }
+
+    /// `message` as sent on the current connection: with its body
+    /// compressed by the codec the connection settled on, if any.
+    fn compress(&self, message: &Message) -> Result<Message> {
+        let mut message = message.clone();
+        if let Some(compression) = &self.compression {
+            compression.encode_request(&mut message)?;
+        }
+        Ok(message)
+    }
+
+    /// Reports the outcome of a call to the interceptors, and how long the
+    /// server took to answer it to the stats.
+    fn observe(&mut self, request: &Message, request_len: usize, sent: Instant, result: Result<Bytes>) -> Result<Bytes> {
+        let elapsed = sent.elapsed();
+        if let Some(circuit) = &mut self.circuit {
+            circuit.record(&result);
+        }
+        if matches!(result, Ok(_) | Err(Error::RemoteError { .. })) {
+            self.stats.round_trip(elapsed);
+        }
+        if let Err(error) = &result {
+            self.observers.emit(ConnectionEvent::RequestFailed { method_name: &request.method_name, error });
+        }
+        self.interceptors.after(&CallOutcome {
+            method_name: &request.method_name,
+            request_id: &request.request_id,
+            request_len,
+            elapsed,
+            result: &result,
+        });
+        result
+    }
+
+    fn track<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            if !self.broken && self.conn.is_some() && e.breaks_connection() {
+                self.observers.emit(ConnectionEvent::Disconnected { error: e });
+            }
+            self.broken |= e.breaks_connection();
+            self.stats.saw_error(e);
+        }
+        result
+    }
+
+    /// Dials if there is no connection yet, or replaces a broken one when
+    /// reconnecting is enabled or a retry is about to be made.
+    fn ensure_connected(&mut self, retrying: bool) -> Result<()> {
+        if let Some(circuit) = &self.circuit {
+            circuit.check()?;
+        }
+        let idle = self.idle_timeout.is_some_and(|timeout| self.last_active.elapsed() >= timeout);
+        if idle && self.outstanding.is_empty() && !self.broken {
+            if let Some(conn) = self.conn.take() {
+                let _ = conn.stream.shutdown();
+            }
+        }
+        if let Err(e) = self.check_alive() {
+            if !(self.auto_reconnect || retrying) {
+                return Err(e);
+            }
+        }
+        if self.conn.is_none() || (self.broken && (self.auto_reconnect || retrying)) {
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+
+    /// Pings a connection that has been idle longer than the keepalive
+    /// interval. A missed answer leaves it broken.
+    fn check_alive(&mut self) -> Result<()> {
+        let timeout = match self.keepalive {
+            Some(keepalive) if self.conn.is_some() && !self.broken && self.last_active.elapsed() >= keepalive.interval => keepalive.timeout,
+            _ => return Ok(()),
+        };
+        let ping = Message::request(PING, b"");
+        let result = self
+            .write_raw(&self.encode(&ping).parts())
+            .map_err(Error::from)
+            .and_then(|_| self.read_frame_for(&ping.request_id, Some(timeout), None))
+            .and_then(|message| message.into_response(&ping.request_id));
+        match self.track(result) {
+            Ok(_) | Err(Error::RemoteError { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes a prepared request, compressed and framed for the connection.
+    /// One resent after reconnecting is compressed and framed again, as the
+    /// new connection may have settled on a different codec or framing.
+    fn write_request(&mut self, request: &Message, fds: &[BorrowedFd<'_>]) -> Result<()> {
+        let frame = self.encode(&self.compress(request)?);
+        match self.write_raw_with_fds(&frame.parts(), fds) {
+            Err(e) if self.auto_reconnect && is_disconnect(&e) => {
+                self.reconnect()?;
+                let frame = self.encode(&self.compress(request)?);
+                self.write_raw_with_fds(&frame.parts(), fds)?;
+            }
+            result => result?,
+        }
+        self.outstanding.insert(request.request_id.clone(), None);
+        self.stats.requests_sent += 1;
+        Ok(())
+    }
+
+    /// Like [`Client::write_request`], for messages not awaiting a single
+    /// response.
+    fn write_message(&mut self, message: &Message) -> Result<()> {
+        let frame = self.encode(&self.compress(message)?);
+        match self.write_raw(&frame.parts()) {
+            Err(e) if self.auto_reconnect && is_disconnect(&e) => {
+                self.reconnect()?;
+                let frame = self.encode(&self.compress(message)?);
+                self.write_raw(&frame.parts())?;
+            }
+            result => result?,
+        }
+        self.stats.requests_sent += 1;
+        Ok(())
+    }
+
+    /// Reads responses until the one for `request_id` arrives, keeping those
+    /// that belong to other outstanding requests for their own callers.
+    fn read_response(&mut self, request_id: &str, timeout: Option<Duration>, cancel: Option<&CancelToken>) -> Result<Bytes> {
+        match self.outstanding.get_mut(request_id) {
+            Some(slot) => {
+                if let Some(mut message) = slot.take() {
+                    self.outstanding.remove(request_id);
+                    compression::decode(&mut message, self.compression.as_slice(), self.max_message_size)?;
+                    return message.into_response(request_id);
+                }
+            }
+            None => {
+                let lost = io::Error::new(io::ErrorKind::ConnectionAborted, "connection was replaced before the response arrived");
+                return Err(lost.into());
+            }
+        }
+
+        let mut message = match self.read_frame_for(request_id, timeout, cancel) {
+            Err(Error::Cancelled) => {
+                self.forget(request_id);
+                return Err(Error::Cancelled);
+            }
+            result => result?,
+        };
+        self.outstanding.remove(request_id);
+        compression::decode(&mut message, self.compression.as_slice(), self.max_message_size)?;
+        message.into_response(request_id)
+    }
+
+    /// Reads the next frame of the stream answering `request_id`, parking
+    /// responses to pipelined requests that arrive in between.
+    pub(crate) fn read_stream_frame(&mut self, request_id: &str) -> Result<Message> {
+        let result = self.read_frame_for(request_id, self.timeout, None);
+        self.track(result)
+    }
+
+    pub(crate) fn read_pushed_frame(&mut self, request_id: &str) -> Result<Message> {
+        let result = self.read_frame_for(request_id, None, None);
+        self.track(result)
+    }
+
+    fn read_frame_for(&mut self, request_id: &str, timeout: Option<Duration>, cancel: Option<&CancelToken>) -> Result<Message> {
+        loop {
+            if let Some(cancel) = cancel {
+                self.wait_readable(timeout, cancel)?;
+            }
+            let conn = self.conn.as_mut().expect("client connects before reading");
+            conn.stream.set_read_timeout(timeout)?;
+            let message = self.framing.read_into(&mut conn.reader, self.max_message_size, &mut conn.buffer)?;
+            self.integrity.verify(&message)?;
+            self.stats.frames_received += 1;
+            self.last_active = Instant::now();
+            // Taken as each frame is read, as descriptors queue up in the
+            // order their frames arrive.
+            let passed = conn.reader.get_mut().take_fds(fds::count(&message)?)?;
+            if !passed.is_empty() {
+                self.passed_fds.entry(message.request_id.clone()).or_default().extend(passed);
+            }
+
+            if message.request_id == request_id {
+                return Ok(message);
+            }
+            if self.discarded.remove(&message.request_id) {
+                continue;
+            }
+            match self.outstanding.get_mut(&message.request_id) {
+                Some(slot) => *slot = Some(message),
+                None => {
+                    return Err(Error::RequestIdMismatch {
+                        expected: request_id.to_string(),
+                        received: message.request_id,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Waits for the next frame to start arriving without consuming any of
+    /// it, so giving up in between leaves the connection in a clean state.
+    fn wait_readable(&mut self, timeout: Option<Duration>, cancel: &CancelToken) -> Result<()> {
+        let conn = self.conn.as_mut().expect("client connects before reading");
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let poll = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => left.min(CANCEL_POLL),
+                    _ => return Err(Error::Timeout),
+                },
+                None => CANCEL_POLL,
+            };
+            conn.stream.set_read_timeout(Some(poll))?;
+            match conn.reader.fill_buf() {
+                Ok(_) => return Ok(()),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Gives up on a request: its response will be skipped, and the server
+    /// told if cancel frames are enabled.
+    fn forget(&mut self, request_id: &str) {
+        self.outstanding.remove(request_id);
+        self.discarded.insert(request_id.to_string());
+        if self.cancel_frames {
+            let cancel = Message { request_id: request_id.to_string(), ..Message::notification(CANCEL, b"") };
+            let _ = self.write_frame(&cancel);
+        }
+    }
+
+    pub(crate) fn write_frame(&mut self, frame: &Message) -> Result<()> {
+        let result = self.write_raw(&self.encode(frame).parts()).map_err(Error::from);
+        self.track(result)
+    }
+
+    /// Marks the connection unusable after frames were left unread on it.
+    pub(crate) fn abandon_connection(&mut self) {
+        self.broken = true;
+    }
+
+    fn encode(&self, message: &Message) -> Frame {
+        self.framing.encode_frame(&self.integrity.seal(message))
+    }
+
+    fn write_raw(&mut self, parts: &[IoSlice<'_>]) -> io::Result<()> {
+        self.write_raw_with_fds(parts, &[])
+    }
+
+    fn write_raw_with_fds(&mut self, parts: &[IoSlice<'_>], fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        let conn = self.conn.as_mut().expect("client connects before writing");
+        conn.stream.set_write_timeout(self.write_timeout)?;
+        match fds {
+            [] => conn.stream.write_parts(parts)?,
+            // Descriptors go with the first bytes, so the frame is sent whole.
+            fds => conn.stream.write_with_fds(&parts.iter().flat_map(|part| part.iter().copied()).collect::<Vec<u8>>(), fds)?,
+        }
+        self.last_active = Instant::now();
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        if self.conn.is_some() {
+            self.observers.emit(ConnectionEvent::ReconnectAttempt);
+        }
+        self.dial()?;
+        if self.handshake {
+            self.negotiate()?;
+        }
+        Ok(())
+    }
+
+    fn dial(&mut self) -> Result<()> {
+        let stream: Box<dyn Transport> = match &self.dialer {
+            Some(dialer) => dialer.dial()?,
+            None => Box::new(FdStream::new(connect_unix(&self.address)?)),
+        };
+        let reader = BufReader::new(stream.try_clone()?);
+        self.conn = Some(Connection { stream, reader, buffer: BytesMut::new() });
+        self.framing = self.base_framing;
+        self.negotiated = None;
+        self.compression = self.compressors.first().cloned();
+        self.outstanding.clear();
+        self.discarded.clear();
+        self.passed_fds.clear();
+        self.broken = false;
+        self.last_active = Instant::now();
+        self.stats.connects += 1;
+        self.observers.emit(ConnectionEvent::Connected);
+        Ok(())
+    }
+
+    /// Runs the handshake on a fresh connection. A server that answers with
+    /// an error does not know it; one that stays silent or hangs up may not
+    /// even expect unknown methods, so it gets a fresh connection without.
+    fn negotiate(&mut self) -> Result<()> {
+        let codecs: Vec<_> = self.compressors.iter().map(Compression::name).collect();
+        let request = Message::request(HANDSHAKE, &offer(&codecs));
+        let timeout = Some(self.timeout.unwrap_or(HANDSHAKE_TIMEOUT));
+        let answer = self
+            .write_raw(&self.encode(&request).parts())
+            .map_err(Error::from)
+            .and_then(|_| self.read_frame_for(&request.request_id, timeout, None))
+            .and_then(|message| message.into_response(&request.request_id))
+            .and_then(|body| Negotiated::parse(&body));
+
+        match answer {
+            Ok(negotiated) => {
+                self.framing = negotiated.framing();
+                self.compression = negotiated.compression().and_then(|name| compression::find(&self.compressors, name)).cloned();
+                self.negotiated = Some(negotiated);
+                Ok(())
+            }
+            Err(Error::RemoteError { .. }) => Ok(()),
+            Err(_) => self.dial(),
+        }
+    }
+}
+
+/// A request written by [`Client::send`] whose response has not been read.
+#[must_use = "the response stays buffered in the client until waited for"]
+#[derive(Debug)]
+pub struct PendingRequest {
+    request_id: String,
+    method_name: String,
+    request_len: usize,
+    sent: Instant,
+}
+
+impl PendingRequest {
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// Blocks until the response to this request arrives on `client`, which
+    /// must be the client that sent it.
+    pub fn wait(self, client: &mut Client) -> Result<Bytes> {
+        let result = client.read_response(&self.request_id, client.timeout, None);
+        let result = client.track(result);
+        let request = Message { request_id: self.request_id, ..Message::request(&self.method_name, b"") };
+        client.observe(&request, self.request_len, self.sent, result)
+    }
+}
+
+/// Counts the bytes read through it, for reporting the size of an upload.
+struct Counted<R> {
+    inner: R,
+    len: usize,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.len += n;
+        Ok(n)
+    }
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::NotConnected
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Code, Error as ClientError, Server, Status};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::error::Error;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    fn run_client() -> Result<(), Box<dyn Error>> {
+        let mut client = Client::new("/tmp/salt-ssd.sock", 10)?;
+        let method_name = "getnssusers";
+        let request_body = b"";
+
+        let response = client.do_request(method_name, request_body)?;
+        match std::str::from_utf8(&response) {
+            Ok(s) => println!("Received response: {}", s),
+            Err(e) => eprintln!("Response was not valid UTF-8: {}", e),
+        }
+        client.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs a unixconn server listening on /tmp/salt-ssd.sock"]
+    #[allow(clippy::to_string_in_format_args)]
+    fn it_works() {
+        if let Err(e) = run_client() {
+            eprintln!("Error: {}", e.to_string());
+            std::process::exit(1);
+        }
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let path = socket_path();
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let mut client = Client::new(&path, 1).unwrap();
+        assert!(matches!(client.do_request("echo", b""), Err(ClientError::Timeout)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sub_second_timeouts() {
+        let path = socket_path();
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let mut client = Client::with_timeout(&path, Some(Duration::from_millis(250))).unwrap();
+        let started = std::time::Instant::now();
+
+        assert!(matches!(client.do_request("echo", b""), Err(ClientError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stalled_reader_hits_the_write_timeout() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut client = Client::new(&path, 60).unwrap();
+        let _conn = listener.accept().unwrap();
+        client.set_write_timeout(Some(Duration::from_millis(100)));
+
+        let body = vec![b'x'; 64 << 20];
+        assert!(matches!(client.do_request("upload", &body), Err(ClientError::Timeout)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn per_request_timeout_overrides_the_default() {
+        let path = socket_path();
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let mut client = Client::new(&path, 60).unwrap();
+        let started = std::time::Instant::now();
+        let result = client.do_request_with_timeout("export", b"", Duration::from_millis(100));
+
+        assert!(matches!(result, Err(ClientError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reconnects_after_server_restart() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let first = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let request = Framing::Delimited.read(&mut BufReader::new(&conn)).unwrap();
+            conn.write_all(&Framing::Delimited.encode(&request)).unwrap();
+        });
+
+        let mut client = Client::builder(&path).auto_reconnect(true).connect().unwrap();
+        assert_eq!(&client.do_request("echo", b"one").unwrap()[..], b"one");
+        first.join().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        assert_eq!(&client.do_request("echo", b"two").unwrap()[..], b"two");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_resent_after_reconnecting_use_the_new_framing() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        // Predates the handshake, so the first connection stays delimited.
+        let first = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let handshake = Framing::Delimited.read(&mut reader).unwrap();
+            let refused = handshake.reply(HANDSHAKE, Err(Status::new(Code::Unimplemented, "unknown method")));
+            conn.write_all(&Framing::Delimited.encode(&refused)).unwrap();
+            let request = Framing::Delimited.read(&mut reader).unwrap();
+            conn.write_all(&Framing::Delimited.encode(&request)).unwrap();
+        });
+
+        let mut client = Client::builder(&path).timeout(Some(Duration::from_secs(5))).handshake(true).auto_reconnect(true).connect().unwrap();
+        assert_eq!(&client.do_request("echo", b"one").unwrap()[..], b"one");
+        assert!(client.negotiated().is_none());
+        first.join().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        assert_eq!(&client.do_request("echo", b"two").unwrap()[..], b"two");
+        assert_eq!(client.negotiated().map(Negotiated::framing), Some(Framing::LengthPrefixed));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn retries_until_the_server_recovers() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        let calls = AtomicUsize::new(0);
+        server.register("flaky", move |body| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => Err(Status::new(Code::Unavailable, "warming up")),
+            _ => Ok(body.to_vec()),
+        });
+        thread::spawn(move || server.run());
+
+        let mut client = Client::new(&path, 5).unwrap();
+        let unavailable = |e: &ClientError| matches!(e, ClientError::RemoteError { code: Code::Unavailable, .. });
+        client.set_retry_policy(Some(RetryPolicy::new(3).base_delay(Duration::ZERO).retry_if(unavailable)));
+        assert_eq!(&client.do_request("flaky", b"ok").unwrap()[..], b"ok");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cancelled_requests_leave_the_client_usable() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        let counted = Arc::new(AtomicUsize::new(0));
+        let calls = counted.clone();
+        server.register("slow", |body| {
+            thread::sleep(Duration::from_millis(300));
+            Ok::<_, Status>(body.to_vec())
+        });
+        server.register("count", move |_| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, Status>(Vec::new())
+        });
+        thread::spawn(move || server.run());
+        let mut client = Client::builder(&path).timeout(Some(Duration::from_secs(5))).cancel_frames(true).connect().unwrap();
+        let cancel_soon = || {
+            let cancel = CancelToken::new();
+            let canceller = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                canceller.cancel();
+            });
+            cancel
+        };
+
+        // Already running on the server: its response is skipped on arrival.
+        let result = client.do_request_cancellable("slow", b"late", &cancel_soon());
+        assert!(matches!(result, Err(ClientError::Cancelled)));
+        assert!(!client.is_broken());
+        assert_eq!(&client.do_request("slow", b"next").unwrap()[..], b"next");
+
+        // Still queued behind another request: the server never runs it.
+        let pending = client.send("slow", b"first").unwrap();
+        let result = client.do_request_cancellable("count", b"", &cancel_soon());
+        assert!(matches!(result, Err(ClientError::Cancelled)));
+        assert_eq!(&pending.wait(&mut client).unwrap()[..], b"first");
+        assert_eq!(&client.do_request("slow", b"done").unwrap()[..], b"done");
+        assert_eq!(counted.load(Ordering::SeqCst), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lazy_client_connects_on_first_request() {
+        let path = socket_path();
+        let mut client = Client::builder(&path).connect_lazy();
+        assert!(client.do_request("echo", b"early").is_err());
+
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        assert_eq!(&client.do_request("echo", b"late").unwrap()[..], b"late");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pipelined_responses_can_be_collected_in_any_order() {
+        let path = crate::test_support::echo_server(1);
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let pending: Vec<_> = (0..5).map(|i| client.send("echo", format!("{}", i).as_bytes()).unwrap()).collect();
+        let interleaved = client.do_request("echo", b"inline").unwrap();
+
+        let mut responses: Vec<_> = pending.into_iter().rev().map(|p| p.wait(&mut client).unwrap()).collect();
+        responses.reverse();
+
+        assert_eq!(&interleaved[..], b"inline");
+        assert_eq!(responses, ["0", "1", "2", "3", "4"].map(|s| Bytes::from(s.as_bytes())));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batch_results_follow_request_order() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("fail", |_| Err(Status::new(Code::InvalidArgument, "bad")));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::new(&path, 5).unwrap();
+        let results = client.do_batch(&[("echo", b"one"), ("fail", b""), ("echo", b"three")]);
+
+        assert_eq!(&results[0].as_ref().unwrap()[..], b"one");
+        assert!(matches!(results[1], Err(ClientError::RemoteError { code: Code::InvalidArgument, .. })));
+        assert_eq!(&results[2].as_ref().unwrap()[..], b"three");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn idle_connections_that_miss_a_heartbeat_are_replaced() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            // The first connection is accepted but never answered, like one
+            // to a hung server.
+            let (_hung, _) = listener.accept().unwrap();
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            while let Ok(request) = Framing::Delimited.read(&mut reader) {
+                conn.write_all(&Framing::Delimited.encode(&request)).unwrap();
+            }
+        });
+
+        let builder = Client::builder(&path).timeout(Some(Duration::from_secs(5))).keepalive(Duration::ZERO, Duration::from_millis(100));
+        let mut client = builder.auto_reconnect(true).connect().unwrap();
+        assert_eq!(&client.do_request("echo", b"ping").unwrap()[..], b"ping");
+        assert_eq!(client.stats().reconnects(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+//! Optional compression of request and response bodies.
+//!
+//! The crate ships no codec itself, to stay free of native dependencies:
+//! implement [`Compressor`] over zstd, lz4 or whatever both peers have, and
+//! configure it on client and server. A compressed body is marked by a
+//! header naming the codec, and a client announces the codec it reads so the
+//! server knows it may compress the response. The body a call opens with is
+//! compressed, as is a single response; the frames of streams, uploads,
+//! duplex calls and subscriptions are sent as is.
+//!
+//! Either side may be given several codecs. A client that runs the
+//! handshake settles on the first of its own the server also has, so peers
+//! built against different codec libraries still find one in common, or
+//! fall back to sending bodies uncompressed.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::protocol::Message;
+
+/// Header naming the codec a body was compressed with.
+const ENCODING_HEADER: &str = "__encoding";
+
+/// Header naming the codec the sender can decompress responses with.
+const ACCEPT_ENCODING_HEADER: &str = "__accept-encoding";
+
+/// Bodies shorter than this are sent uncompressed, as the saving would not
+/// be worth the work.
+const MIN_COMPRESSED_LEN: usize = 1024;
+
+/// A compression codec, e.g. zstd at some level.
+pub trait Compressor: Send + Sync {
+    /// Identifies the codec on the wire, e.g. `"zstd"`.
+    fn name(&self) -> &str;
+
+    fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Restores `body`, stopping once the output is longer than `limit`
+    /// bytes: whatever it returns beyond that is refused, so a small body
+    /// cannot be made to expand without bound.
+    fn decompress(&self, body: &[u8], limit: usize) -> io::Result<Vec<u8>>;
+}
+
+/// A shared [`Compressor`], cheap to clone into every client built from one
+/// builder.
+#[derive(Clone)]
+pub(crate) struct Compression(pub(crate) Arc<dyn Compressor>);
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Compression").field(&self.0.name()).finish()
+    }
+}
+
+impl Compression {
+    /// Compresses the body if that is worthwhile and marks that `message`'s
+    /// sender accepts compressed responses.
+    pub(crate) fn encode_request(&self, message: &mut Message) -> io::Result<()> {
+        message.headers.push((ACCEPT_ENCODING_HEADER.to_string(), self.0.name().to_string()));
+        self.encode(message)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn encode(&self, message: &mut Message) -> io::Result<()> {
+        if message.body.len() < MIN_COMPRESSED_LEN {
+            return Ok(());
+        }
+        message.body = Bytes::from(self.0.compress(&message.body)?);
+        message.headers.push((ENCODING_HEADER.to_string(), self.0.name().to_string()));
+        Ok(())
+    }
+}
+
+/// Compresses the body of a response to `request` with the codec its sender
+/// accepts, if that is one of `codecs` and compressing is worthwhile.
+pub(crate) fn encode_response(codecs: &[Compression], request: &Message, response: &mut Message) -> io::Result<()> {
+    match request.header(ACCEPT_ENCODING_HEADER).and_then(|accepted| find(codecs, accepted)) {
+        Some(compression) => compression.encode(response),
+        None => Ok(()),
+    }
+}
+
+/// Restores the body of `message` if it arrived compressed. Fails if it was
+/// compressed with a codec not among `codecs`, or with
+/// [`Error::MessageTooLarge`] if it restores to more than `max_len` bytes.
+pub(crate) fn decode(message: &mut Message, codecs: &[Compression], max_len: Option<usize>) -> Result<()> {
+    let encoding = match message.header(ENCODING_HEADER) {
+        Some(encoding) => encoding,
+        None => return Ok(()),
+    };
+    let compression = find(codecs, encoding)
+        .ok_or_else(|| Error::Protocol(format!("body compressed with unsupported codec {:?}", encoding)))?;
+
+    let limit = max_len.unwrap_or(usize::MAX);
+    let body = compression.0.decompress(&message.body, limit)?;
+    if body.len() > limit {
+        return Err(Error::MessageTooLarge { limit });
+    }
+    message.body = Bytes::from(body);
+    message.headers.retain(|(key, _)| key != ENCODING_HEADER);
+    Ok(())
+}
+
+pub(crate) fn find<'a>(codecs: &'a [Compression], name: &str) -> Option<&'a Compression> {
+    codecs.iter().find(|compression| compression.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Server, Status};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Run-length encoding as (count, byte) pairs, counting its calls. The
+    /// name lets tests stand in for distinct codecs.
+    struct RunLength {
+        name: &'static str,
+        calls: AtomicUsize,
+    }
+
+    impl RunLength {
+        fn new(name: &'static str) -> Arc<Self> {
+            Arc::new(RunLength { name, calls: AtomicUsize::new(0) })
+        }
+    }
+
+    impl Compressor for RunLength {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut out = Vec::new();
+            for run in body.chunk_by(|a, b| a == b) {
+                for part in run.chunks(u8::MAX as usize) {
+                    out.extend_from_slice(&[part.len() as u8, part[0]]);
+                }
+            }
+            Ok(out)
+        }
+
+        fn decompress(&self, body: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if !body.len().is_multiple_of(2) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "odd run-length body"));
+            }
+            let mut out = Vec::new();
+            for pair in body.chunks(2) {
+                if out.len() > limit {
+                    break;
+                }
+                out.extend(std::iter::repeat_n(pair[1], pair[0] as usize));
+            }
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn large_bodies_are_compressed_both_ways() {
+        let path = socket_path();
+        let server_codec = RunLength::new("rle");
+        let mut server = Server::bind(&path).unwrap();
+        server.add_compression(server_codec.clone());
+        server.register("grow", |body| Ok::<_, Status>(body.repeat(4)));
+        thread::spawn(move || server.run());
+
+        let client_codec = RunLength::new("rle");
+        let mut client = Client::builder(&path)
+            .timeout(Some(std::time::Duration::from_secs(5)))
+            .compression(client_codec.clone())
+            .connect()
+            .unwrap();
+
+        assert_eq!(&client.do_request("grow", b"ab").unwrap()[..], b"abababab");
+        assert_eq!(client_codec.calls.load(Ordering::SeqCst), 0);
+
+        let body = vec![b'x'; 4096];
+        assert_eq!(client.do_request("grow", &body).unwrap(), vec![b'x'; 4 * 4096]);
+        // Each side compressed one body and restored the other's.
+        assert_eq!(client_codec.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(server_codec.calls.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn every_kind_of_call_compresses_its_body() {
+        let path = socket_path();
+        let server_codec = RunLength::new("rle");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let mut server = Server::bind(&path).unwrap();
+        server.add_compression(server_codec.clone());
+        server.register("grow", |body| Ok::<_, Status>(body.repeat(4)));
+        server.register("event", move |body| {
+            tx.lock().unwrap().send(body.len()).unwrap();
+            Ok::<_, Status>(Vec::new())
+        });
+        server.register_upload("upload", |upload| {
+            let mut body = Vec::new();
+            upload.read_to_end(&mut body).unwrap();
+            Ok(vec![b'y'; body.len()])
+        });
+        thread::spawn(move || server.run());
+
+        let client_codec = RunLength::new("rle");
+        let mut client = Client::builder(&path).compression(client_codec.clone()).connect().unwrap();
+        let body = vec![b'x'; 4096];
+
+        let pending = client.send("grow", &body).unwrap();
+        assert_eq!(pending.wait(&mut client).unwrap().len(), 4 * 4096);
+        assert_eq!(client_codec.calls.load(Ordering::SeqCst), 2);
+
+        let batch = client.do_batch(&[("grow", &body[..]), ("grow", &body[..])]);
+        assert!(batch.iter().all(|result| result.as_ref().unwrap().len() == 4 * 4096));
+        assert_eq!(client_codec.calls.load(Ordering::SeqCst), 6);
+
+        client.notify("event", &body).unwrap();
+        assert_eq!(rx.recv().unwrap(), 4096);
+        assert_eq!(client_codec.calls.load(Ordering::SeqCst), 7);
+
+        assert_eq!(client.do_request_streamed("upload", &body[..]).unwrap(), vec![b'y'; 4096]);
+        assert_eq!(client_codec.calls.load(Ordering::SeqCst), 8);
+        assert_eq!(server_codec.calls.load(Ordering::SeqCst), 8);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bodies_restoring_past_the_size_limit_are_refused() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.set_max_message_size(Some(2048));
+        server.add_compression(RunLength::new("rle"));
+        server.register("grow", |body| Ok::<_, Status>(body.repeat(4)));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::builder(&path).compression(RunLength::new("rle")).max_message_size(4096).connect().unwrap();
+        // Both compress to a few dozen bytes, well within either frame limit.
+        let refused = client.do_request("grow", &[b'x'; 4096]);
+        assert!(matches!(refused, Err(Error::RemoteError { code: crate::Code::ResourceExhausted, .. })), "{:?}", refused);
+        assert!(matches!(client.do_request("grow", &[b'x'; 2048]), Err(Error::MessageTooLarge { limit: 4096 })));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_handshake_settles_on_a_codec_both_sides_have() {
+        let path = socket_path();
+        let (rle, lz) = (RunLength::new("rle"), RunLength::new("lz"));
+        let mut server = Server::bind(&path).unwrap();
+        server.set_workers(2);
+        server.add_compression(rle.clone());
+        server.add_compression(lz.clone());
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+        let body = vec![b'x'; 4096];
+
+        let newer = RunLength::new("zz");
+        let mut mixed = Client::builder(&path).handshake(true).compression(newer.clone()).compression(lz.clone()).connect().unwrap();
+        assert_eq!(mixed.negotiated().unwrap().compression(), Some("lz"));
+        assert_eq!(mixed.do_request("echo", &body).unwrap(), body);
+        assert_eq!(newer.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(rle.calls.load(Ordering::SeqCst), 0);
+
+        let mut unmatched = Client::builder(&path).handshake(true).compression(newer.clone()).connect().unwrap();
+        assert_eq!(unmatched.negotiated().unwrap().compression(), None);
+        assert_eq!(unmatched.do_request("echo", &body).unwrap(), body);
+        assert_eq!(newer.calls.load(Ordering::SeqCst), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use std::cell::RefCell;
+use std::os::fd::OwnedFd;
+use std::time::{Duration, Instant};
+
+use crate::credentials::PeerCredentials;
+use crate::protocol::{Message, DEADLINE_HEADER, TRACE_HEADER};
+use crate::stream::Incoming;
+use crate::trace::{TraceContext, TRACEPARENT_HEADER};
+
+/// What a handler registered with [`crate::Server::register_with_context`]
+/// can learn about the request beyond its body.
+pub struct RequestContext<'a> {
+    request: &'a Message,
+    /// Where the request was read, unless the event loop read it.
+    incoming: Option<&'a Incoming>,
+    peer: Option<PeerCredentials>,
+    deadline: Option<Instant>,
+    reply_fds: RefCell<Vec<OwnedFd>>,
+}
+
+impl<'a> RequestContext<'a> {
+    /// `received` is when the request was read, so time spent queued behind
+    /// other requests counts against its deadline.
+    pub(crate) fn new(request: &'a Message, incoming: &'a Incoming, received: Instant) -> Self {
+        RequestContext { incoming: Some(incoming), ..Self::polled(request, incoming.peer(), received) }
+    }
+
+    /// A context for a request read by the event loop, which never sees it
+    /// cancelled and passes no descriptors.
+    pub(crate) fn polled(request: &'a Message, peer: Option<PeerCredentials>, received: Instant) -> Self {
+        let deadline = request
+            .header(DEADLINE_HEADER)
+            .and_then(|millis| millis.parse().ok())
+            .map(|millis| received + Duration::from_millis(millis));
+        RequestContext { request, incoming: None, peer, deadline, reply_fds: RefCell::new(Vec::new()) }
+    }
+
+    pub fn method_name(&self) -> &str {
+        &self.request.method_name
+    }
+
+    /// The ID the client gave the request, which its response carries too.
+    pub fn request_id(&self) -> &str {
+        &self.request.request_id
+    }
+
+    /// The request body, as passed to the handler.
+    pub(crate) fn body(&self) -> &[u8] {
+        &self.request.body
+    }
+
+    /// The value of the header `name` sent with
+    /// [`crate::Client::do_request_with_headers`], if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.request.header(name)
+    }
+
+    /// The trace ID the caller set with [`crate::Client::set_trace_id`].
+    pub fn trace_id(&self) -> Option<&str> {
+        self.request.header(TRACE_HEADER)
+    }
+
+    /// The span the caller sent the request as, from a W3C `traceparent`
+    /// header such as [`crate::Client::set_trace_context`] sends; `None` if
+    /// there is none or it is malformed. Handing it to a client of the
+    /// handler's own continues the trace in requests made on its behalf.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.request.header(TRACEPARENT_HEADER).and_then(TraceContext::parse)
+    }
+
+    /// All headers the caller sent, in order, leaving out those the protocol
+    /// uses itself.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.request
+            .headers
+            .iter()
+            .filter(|(key, _)| !key.starts_with("__"))
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// When the caller stops waiting for the response, if it said so (see
+    /// [`crate::Client::set_propagate_deadline`]). Work finishing after that
+    /// is wasted.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the deadline has already passed.
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether the client has cancelled the request since it was read.
+    pub fn is_cancelled(&self) -> bool {
+        self.incoming.is_some_and(|incoming| incoming.is_cancelled(&self.request.request_id))
+    }
+
+    /// The process that opened the connection, read from the socket itself
+    /// so it can be trusted for authorization; `None` where the platform
+    /// does not tell.
+    pub fn peer(&self) -> Option<PeerCredentials> {
+        self.peer
+    }
+
+    /// The descriptors passed with the request by
+    /// [`crate::Client::do_request_with_fds`], in order. Later calls return
+    /// none; those never taken are closed once the request is answered.
+    pub fn take_fds(&self) -> Vec<OwnedFd> {
+        self.incoming.map(|incoming| incoming.take_fds(&self.request.request_id)).unwrap_or_default()
+    }
+
+    /// Passes `fd` back to the caller with the response. It is closed here
+    /// once sent.
+    pub fn attach_fd(&self, fd: OwnedFd) {
+        self.reply_fds.borrow_mut().push(fd);
+    }
+
+    pub(crate) fn take_reply_fds(&self) -> Vec<OwnedFd> {
+        self.reply_fds.take()
+    }
+}
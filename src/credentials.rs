@@ -0,0 +1,81 @@
+use std::io;
+use std::os::unix::net::UnixStream;
+
+/// Who is on the other end of a unix socket connection, as recorded by the
+/// kernel when it connected; the process cannot claim to be anyone else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pid: Option<i32>,
+    uid: u32,
+    gid: u32,
+}
+
+impl PeerCredentials {
+    pub fn new(pid: Option<i32>, uid: u32, gid: u32) -> Self {
+        PeerCredentials { pid, uid, gid }
+    }
+
+    /// The process that connected, where the platform reports it. It may
+    /// have exited, and its ID been reused, since.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+}
+
+/// Reads the credentials of the peer of `conn` (SO_PEERCRED).
+#[cfg(target_os = "linux")]
+pub(crate) fn peer_credentials(conn: &UnixStream) -> io::Result<PeerCredentials> {
+    use std::os::fd::AsRawFd;
+
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` is a live ucred and `len` its size, as SO_PEERCRED expects.
+    let result = unsafe {
+        libc::getsockopt(
+            conn.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // A peer in a PID namespace this process cannot see reports 0.
+    let pid = Some(cred.pid).filter(|&pid| pid > 0);
+    Ok(PeerCredentials::new(pid, cred.uid, cred.gid))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn peer_credentials(_conn: &UnixStream) -> io::Result<PeerCredentials> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "peer credentials are only read on Linux"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::{testing, Server};
+
+    #[test]
+    fn handlers_see_who_connected() {
+        let mut server = Server::new();
+        server.register_with_context("whoami", |context, _| {
+            let peer = context.peer().ok_or("no credentials")?;
+            Ok(format!("{} {} {:?}", peer.uid(), peer.gid(), peer.pid()).into_bytes())
+        });
+        let mut client = testing::connect(server).unwrap();
+
+        // SAFETY: these calls cannot fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let expected = format!("{} {} Some({})", uid, gid, std::process::id());
+        assert_eq!(client.do_request("whoami", b"").unwrap(), expected.as_bytes());
+    }
+}
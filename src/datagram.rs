@@ -0,0 +1,58 @@
+use std::os::unix::net::UnixDatagram;
+
+use crate::error::Result;
+use crate::protocol::{Framing, Message};
+
+/// Sends notifications as datagrams to a `SOCK_DGRAM` unix socket, one frame
+/// per datagram, without setting up a connection.
+///
+/// Suited to short-lived processes submitting logs or metrics. There are no
+/// responses, and as with [`crate::Client::notify`] delivery is not
+/// confirmed; a datagram larger than the socket's buffer is refused by the
+/// kernel rather than split.
+pub struct DatagramClient {
+    socket: UnixDatagram,
+    framing: Framing,
+}
+
+impl DatagramClient {
+    pub fn connect(address: &str) -> Result<Self> {
+        Self::with_framing(address, Framing::default())
+    }
+
+    pub fn with_framing(address: &str, framing: Framing) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(address)?;
+        Ok(DatagramClient { socket, framing })
+    }
+
+    pub fn notify(&self, method_name: &str, body: &[u8]) -> Result<()> {
+        self.socket.send(&self.framing.encode(&Message::notification(method_name, body)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+
+    #[test]
+    fn each_notification_is_one_datagram() {
+        let path = socket_path();
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        let client = DatagramClient::connect(&path).unwrap();
+        client.notify("metric", b"requests=1").unwrap();
+        client.notify("metric", b"requests=2").unwrap();
+
+        let mut datagram = [0u8; 256];
+        for expected in [&b"requests=1"[..], b"requests=2"] {
+            let n = receiver.recv(&mut datagram).unwrap();
+            let message = Framing::default().read(&mut &datagram[..n]).unwrap();
+            assert!(message.is_notification());
+            assert_eq!((message.method_name.as_str(), &message.body[..]), ("metric", expected));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::protocol::{Message, STREAM_END};
+use crate::status::{Code, Status};
+use crate::stream::{ConnWriter, Incoming, ResponseSink, UploadReader};
+use crate::Client;
+
+/// Client side of a bidirectional call opened with [`Client::open_duplex`].
+///
+/// Frames flow independently in both directions under one request_id until
+/// each side sends its end marker: [`DuplexCall::close_send`] for the client,
+/// the handler returning for the server. Dropping the call closes the send
+/// side; if the server had not finished, the client is marked broken.
+pub struct DuplexCall<'a> {
+    client: &'a mut Client,
+    request: Message,
+    sending: bool,
+    receiving: bool,
+}
+
+impl<'a> DuplexCall<'a> {
+    pub(crate) fn new(client: &'a mut Client, request: Message) -> Self {
+        DuplexCall { client, request, sending: true, receiving: true }
+    }
+
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<()> {
+        if !self.sending {
+            return Err(Error::Protocol("send side of the call is already closed".to_string()));
+        }
+        self.client.write_frame(&self.request.with_body(Bytes::copy_from_slice(frame)))
+    }
+
+    /// Tells the server no more frames will be sent.
+    pub fn close_send(&mut self) -> Result<()> {
+        if !self.sending {
+            return Ok(());
+        }
+        self.sending = false;
+        self.client.write_frame(&self.request.reply(STREAM_END, Ok(Bytes::new())))
+    }
+
+    /// Waits for the next frame from the server; `None` once the server has
+    /// ended the call.
+    pub fn recv_frame(&mut self) -> Result<Option<Bytes>> {
+        if !self.receiving {
+            return Ok(None);
+        }
+
+        let frame = match self.client.read_stream_frame(&self.request.request_id) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.receiving = false;
+                return Err(e);
+            }
+        };
+        if frame.method_name == STREAM_END {
+            self.receiving = false;
+            return frame.into_response(&self.request.request_id).map(|_| None);
+        }
+        frame.into_response(&self.request.request_id).map(Some)
+    }
+}
+
+impl Drop for DuplexCall<'_> {
+    fn drop(&mut self) {
+        if self.close_send().is_err() || self.receiving {
+            self.client.abandon_connection();
+        }
+    }
+}
+
+/// Server side of a bidirectional call, handed to handlers registered with
+/// [`crate::Server::register_duplex`].
+pub struct DuplexSession<'a> {
+    incoming: UploadReader<'a>,
+    outgoing: ResponseSink<'a>,
+}
+
+impl<'a> DuplexSession<'a> {
+    pub(crate) fn new(incoming: &'a Incoming, writer: &'a ConnWriter, request: &'a Message) -> Self {
+        DuplexSession {
+            incoming: UploadReader::without_body(incoming, request),
+            outgoing: ResponseSink::new(writer, incoming, request),
+        }
+    }
+
+    /// Waits for the next frame from the client; `None` once it has closed
+    /// its send side.
+    pub fn recv_frame(&mut self) -> Result<Option<Bytes>, Status> {
+        self.incoming
+            .recv_frame()
+            .map_err(|e| Status::new(Code::Aborted, format!("call closed by the client: {}", e)))
+    }
+
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<(), Status> {
+        self.outgoing.send(frame)
+    }
+
+    pub(crate) fn into_parts(self) -> (UploadReader<'a>, ResponseSink<'a>) {
+        (self.incoming, self.outgoing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::Server;
+    use std::thread;
+
+    #[test]
+    fn frames_flow_both_ways_until_each_side_closes() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register_duplex("shell", |session| {
+            session.send_frame(b"$ ")?;
+            while let Some(line) = session.recv_frame()? {
+                if line.as_ref() == b"exit" {
+                    return Err(Status::new(Code::Cancelled, "bye"));
+                }
+                session.send_frame(&line.to_ascii_uppercase())?;
+            }
+            Ok(())
+        });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let mut call = client.open_duplex("shell").unwrap();
+        assert_eq!(call.recv_frame().unwrap().unwrap(), &b"$ "[..]);
+        call.send_frame(b"ls").unwrap();
+        assert_eq!(call.recv_frame().unwrap().unwrap(), &b"LS"[..]);
+        call.close_send().unwrap();
+        assert_eq!(call.recv_frame().unwrap(), None);
+        drop(call);
+
+        let mut call = client.open_duplex("shell").unwrap();
+        call.recv_frame().unwrap();
+        call.send_frame(b"exit").unwrap();
+        assert!(matches!(call.recv_frame(), Err(Error::RemoteError { code: Code::Cancelled, .. })));
+        drop(call);
+
+        assert!(!client.is_broken());
+        assert_eq!(&client.do_request("echo", b"after").unwrap()[..], b"after");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+use std::time::Duration;
+
+use crate::status::Code;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong talking to a unixconn peer.
+#[derive(Debug)]
+pub enum Error {
+    /// The connection failed.
+    Io(io::Error),
+    /// The peer sent something that is not a valid frame.
+    Protocol(String),
+    /// The peer answered with a non-empty error field.
+    RemoteError { code: Code, message: String },
+    /// The response belongs to a different request.
+    RequestIdMismatch { expected: String, received: String },
+    /// No response arrived within the configured timeout.
+    Timeout,
+    /// The request was cancelled through its [`crate::CancelToken`].
+    Cancelled,
+    /// The peer sent a frame longer than the configured maximum message
+    /// size; the rest of it was left unread.
+    MessageTooLarge { limit: usize },
+    /// The client's [`crate::CircuitBreaker`] is open, so the call was not
+    /// sent; the next one is let through after `retry_in`.
+    CircuitOpen { retry_in: Duration },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "client io error: {}", e),
+            Error::Protocol(message) => write!(f, "error protocol {}", message),
+            Error::RemoteError { code: Code::Unknown, message } => write!(f, "client response error: {}", message),
+            Error::RemoteError { code, message } => write!(f, "client response error: {}: {}", code, message),
+            Error::RequestIdMismatch { expected, received } => {
+                write!(f, "client wrong requestID error: expected {}, received {}", expected, received)
+            }
+            Error::Timeout => write!(f, "client timeout error"),
+            Error::Cancelled => write!(f, "client request cancelled"),
+            Error::MessageTooLarge { limit } => write!(f, "error protocol message exceeds {} bytes", limit),
+            Error::CircuitOpen { retry_in } => write!(f, "client circuit open, retry in {}ms", retry_in.as_millis()),
+        }
+    }
+}
+
+impl Error {
+    /// Anything but an error reported by the peer means the stream may be
+    /// desynchronised and must not carry further requests. A cancelled
+    /// request's response is skipped when it turns up, so it is no exception,
+    /// and a call held back by an open circuit never reached the stream.
+    pub(crate) fn breaks_connection(&self) -> bool {
+        !matches!(self, Error::RemoteError { .. } | Error::Cancelled | Error::CircuitOpen { .. })
+    }
+
+    /// Copies the error for reporting one failure against several requests;
+    /// an I/O error keeps its kind and message but loses its source.
+    pub(crate) fn duplicate(&self) -> Error {
+        match self {
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Protocol(message) => Error::Protocol(message.clone()),
+            Error::RemoteError { code, message } => Error::RemoteError { code: *code, message: message.clone() },
+            Error::RequestIdMismatch { expected, received } => Error::RequestIdMismatch {
+                expected: expected.clone(),
+                received: received.clone(),
+            },
+            Error::Timeout => Error::Timeout,
+            Error::Cancelled => Error::Cancelled,
+            Error::MessageTooLarge { limit } => Error::MessageTooLarge { limit: *limit },
+            Error::CircuitOpen { retry_in } => Error::CircuitOpen { retry_in: *retry_in },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // Unix sockets report an expired read/write timeout as WouldBlock.
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::Protocol(format!("field is not valid UTF-8: {}", e))
+    }
+}
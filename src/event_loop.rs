@@ -0,0 +1,538 @@
+//! [`Server::run_event_loop`]: every connection served from one thread,
+//! waiting on all of them with epoll.
+//!
+//! Each connection keeps what it has read but not yet parsed and what is
+//! waiting to be sent. Requests are answered as soon as they are whole,
+//! in order, and the answers written as far as the socket takes them; a
+//! client that stops reading has its further requests left unread until
+//! it catches up.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::{Duration, Instant};
+
+use crate::credentials::{peer_credentials, PeerCredentials};
+use crate::error::{Error, Result};
+use crate::handshake::HANDSHAKE;
+use crate::limits::{Overload, Permit};
+use crate::protocol::{Decoder, Encoder, Message, CANCEL, STREAM_END, UNSUBSCRIBE};
+use crate::server::{self, Server, ACCEPT_BACKOFF};
+use crate::shutdown::Tracked;
+use crate::status::{Code, Status};
+
+/// The key the listener is registered under; connections count up from 1.
+const LISTENER: u64 = 0;
+
+const READ_CHUNK: usize = 16 * 1024;
+
+/// How much unsent output stops a connection's requests being read.
+const WRITE_BACKLOG: usize = 1024 * 1024;
+
+const MAX_EVENTS: usize = 64;
+
+pub(crate) fn run(server: &Server, listener: &UnixListener) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let result = EventLoop::new(server, listener).and_then(|mut event_loop| event_loop.run());
+    // The listener stays the server's, for a later call to run.
+    listener.set_nonblocking(false)?;
+    result
+}
+
+struct EventLoop<'a> {
+    server: &'a Server,
+    listener: &'a UnixListener,
+    epoll: Epoll,
+    listening: bool,
+    /// When to take the listener back after running out of descriptors.
+    paused_until: Option<Instant>,
+    conns: HashMap<u64, Conn<'a>>,
+    /// Connections waiting for a slot under the connection limit, oldest
+    /// first.
+    queued: Vec<u64>,
+    next_key: u64,
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(server: &'a Server, listener: &'a UnixListener) -> io::Result<Self> {
+        let epoll = Epoll::new()?;
+        epoll.add(listener.as_raw_fd(), LISTENER, libc::EPOLLIN as u32)?;
+        Ok(EventLoop { server, listener, epoll, listening: true, paused_until: None, conns: HashMap::new(), queued: Vec::new(), next_key: LISTENER + 1 })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Vec::with_capacity(MAX_EVENTS);
+        loop {
+            if self.listening && self.server.shutdown.is_stopping() {
+                if self.paused_until.take().is_none() {
+                    self.epoll.delete(self.listener.as_raw_fd())?;
+                }
+                self.listening = false;
+                for key in std::mem::take(&mut self.queued) {
+                    self.close(key);
+                }
+            }
+            if !self.listening && self.conns.is_empty() {
+                return Ok(());
+            }
+
+            if self.paused_until.is_some_and(|until| until <= Instant::now()) {
+                self.epoll.add(self.listener.as_raw_fd(), LISTENER, libc::EPOLLIN as u32)?;
+                self.paused_until = None;
+            }
+
+            let deadline = [self.next_idle_deadline(), self.paused_until].into_iter().flatten().min();
+            self.epoll.wait(&mut events, deadline)?;
+            for event in &events {
+                let (key, ready) = (event.u64, event.events);
+                if key == LISTENER {
+                    self.accept()?;
+                } else {
+                    self.drive(key, ready);
+                }
+            }
+            self.close_idle();
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Dropped by the client before it was accepted.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                // The listener stays readable, so it is left out of the
+                // wait until descriptors may have been freed.
+                Err(e) if server::is_out_of_descriptors(&e) => {
+                    self.server.report_error(&e.into());
+                    self.epoll.delete(self.listener.as_raw_fd())?;
+                    self.paused_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            // Shutting down connects once to wake the loop up.
+            if self.server.shutdown.is_stopping() {
+                continue;
+            }
+            if let Err(e) = self.admit(stream) {
+                self.server.report_error(&e.into());
+            }
+        }
+    }
+
+    fn admit(&mut self, stream: UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let tracked = self.server.shutdown.track(&stream)?;
+        let permit = self.server.connections.acquire(Overload::Reject);
+        let turning_away = permit.is_none() && self.server.overload == Overload::Reject;
+        let key = self.next_key;
+        self.next_key += 1;
+        let conn = Conn::new(stream, self.server, permit, turning_away, tracked);
+        self.epoll.add(conn.stream.as_raw_fd(), key, conn.interest())?;
+        if conn.is_queued() {
+            self.queued.push(key);
+        }
+        self.conns.insert(key, conn);
+        Ok(())
+    }
+
+    /// Reads, answers and writes what `ready` allows on one connection,
+    /// closing it once it is done.
+    fn drive(&mut self, key: u64, ready: u32) {
+        let Some(conn) = self.conns.get_mut(&key) else { return };
+        let hung_up = ready & (libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0;
+        if conn.is_queued() {
+            if hung_up {
+                self.queued.retain(|&queued| queued != key);
+                self.close(key);
+            }
+            return;
+        }
+
+        let driven = conn.drive(self.server, ready & (libc::EPOLLIN | libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0);
+        match driven {
+            Ok(()) if !conn.is_done() => {
+                let interest = conn.interest();
+                if interest != conn.registered && self.epoll.modify(conn.stream.as_raw_fd(), key, interest).is_ok() {
+                    conn.registered = interest;
+                }
+            }
+            Ok(()) => self.close(key),
+            Err(e) => {
+                if !matches!(&e, Error::Io(e) if matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset)) {
+                    self.server.report_error(&e);
+                }
+                self.close(key);
+            }
+        }
+    }
+
+    /// Closes a connection, handing its slot to the connection queued
+    /// longest.
+    fn close(&mut self, key: u64) {
+        let Some(conn) = self.conns.remove(&key) else { return };
+        let _ = self.epoll.delete(conn.stream.as_raw_fd());
+        let freed = conn.permit.is_some();
+        drop(conn);
+        if !freed || self.queued.is_empty() {
+            return;
+        }
+        let Some(permit) = self.server.connections.acquire(Overload::Reject) else { return };
+        let next = self.queued.remove(0);
+        if let Some(conn) = self.conns.get_mut(&next) {
+            conn.permit = Some(permit);
+            conn.last_active = Instant::now();
+            if self.epoll.modify(conn.stream.as_raw_fd(), next, conn.interest()).is_ok() {
+                conn.registered = conn.interest();
+            }
+        }
+    }
+
+    /// When the connection idle the longest times out, if any can.
+    fn next_idle_deadline(&self) -> Option<Instant> {
+        let timeout = self.server.idle_timeout?;
+        self.conns.values().filter(|conn| conn.can_idle()).map(|conn| conn.last_active + timeout).min()
+    }
+
+    fn close_idle(&mut self) {
+        let Some(timeout) = self.server.idle_timeout else { return };
+        let idle: Vec<u64> = self
+            .conns
+            .iter()
+            .filter(|(_, conn)| conn.can_idle() && conn.last_active.elapsed() >= timeout)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in idle {
+            self.close(key);
+        }
+    }
+}
+
+struct Conn<'a> {
+    stream: UnixStream,
+    first: bool,
+    peer: Option<PeerCredentials>,
+    /// The connection's slot under the connection limit. Without one it
+    /// is left unread until a slot frees up, unless turned away.
+    permit: Option<Permit<'a>>,
+    /// Over the connection limit with [`Overload::Reject`]: its first
+    /// request is answered with [`Code::Unavailable`], then it is closed.
+    turning_away: bool,
+    decoder: Decoder,
+    encoder: Encoder,
+    /// Requests whose further frames are dropped unread, as the loop
+    /// refused the upload or duplex call they belong to.
+    discarding: HashSet<String>,
+    read_closed: bool,
+    last_active: Instant,
+    /// The events the connection is registered for.
+    registered: u32,
+    _tracked: Tracked<'a>,
+}
+
+impl<'a> Conn<'a> {
+    fn new(stream: UnixStream, server: &Server, permit: Option<Permit<'a>>, turning_away: bool, tracked: Tracked<'a>) -> Self {
+        let mut conn = Conn {
+            peer: peer_credentials(&stream).ok(),
+            stream,
+            first: true,
+            permit,
+            turning_away,
+            decoder: Decoder::new(server.framing, server.max_message_size),
+            encoder: Encoder::new(server.framing),
+            discarding: HashSet::new(),
+            read_closed: false,
+            last_active: Instant::now(),
+            registered: 0,
+            _tracked: tracked,
+        };
+        conn.registered = conn.interest();
+        conn
+    }
+
+    /// The events to wait for: input while there is room to answer it, and
+    /// room to write while answers are waiting.
+    fn interest(&self) -> u32 {
+        if self.is_queued() {
+            return 0;
+        }
+        let mut interest = 0;
+        if !self.read_closed && self.encoder.pending().len() < WRITE_BACKLOG {
+            interest |= libc::EPOLLIN as u32;
+        }
+        if !self.encoder.pending().is_empty() {
+            interest |= libc::EPOLLOUT as u32;
+        }
+        interest
+    }
+
+    fn is_queued(&self) -> bool {
+        self.permit.is_none() && !self.turning_away
+    }
+
+    fn is_done(&self) -> bool {
+        self.read_closed && self.encoder.pending().is_empty()
+    }
+
+    /// Whether the idle timeout applies: not while the connection waits
+    /// for a slot or for its client to read its answers.
+    fn can_idle(&self) -> bool {
+        self.permit.is_some() && self.encoder.pending().is_empty()
+    }
+
+    fn drive(&mut self, server: &Server, readable: bool) -> Result<()> {
+        if readable && !self.read_closed {
+            self.read()?;
+        }
+        loop {
+            let blocked = self.answer(server)?;
+            self.flush()?;
+            // Answering stopped at the backlog, which writing then cleared.
+            if !blocked || self.encoder.pending().len() >= WRITE_BACKLOG {
+                break;
+            }
+        }
+        if self.read_closed {
+            // A frame cut off by the client hanging up is never answered.
+            self.decoder.clear();
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<()> {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.read_closed = true,
+                Ok(n) => {
+                    self.decoder.feed(&chunk[..n]);
+                    self.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+            return Ok(());
+        }
+    }
+
+    /// Answers the requests read in full, returning whether it stopped at
+    /// the write backlog with more possibly left.
+    fn answer(&mut self, server: &Server) -> Result<bool> {
+        while !self.read_closed || self.decoder.has_partial_frame() {
+            if self.encoder.pending().len() >= WRITE_BACKLOG {
+                return Ok(true);
+            }
+            let Some(request) = self.decoder.decode()? else { break };
+            server.integrity.verify(&request)?;
+            self.handle(server, request)?;
+            if self.turning_away && !self.first {
+                self.read_closed = true;
+                self.decoder.clear();
+            }
+        }
+        Ok(false)
+    }
+
+    fn handle(&mut self, server: &Server, request: Message) -> Result<()> {
+        let first = std::mem::replace(&mut self.first, false);
+        if self.turning_away {
+            let busy = Status::new(Code::Unavailable, "server is at its connection limit");
+            self.send(server, server.refusal(&request, busy));
+            return Ok(());
+        }
+        if request.method_name == HANDSHAKE {
+            let (reply, framing) = server.answer_handshake(&request, first);
+            self.send(server, Some(reply));
+            // The client waits for the answer, so whatever follows is in
+            // the agreed framing.
+            if let Some(framing) = framing {
+                self.decoder.set_framing(framing);
+                self.encoder.set_framing(framing);
+            }
+            return Ok(());
+        }
+
+        if request.method_name == STREAM_END {
+            self.discarding.remove(&request.request_id);
+            return Ok(());
+        }
+        if request.method_name == CANCEL || request.method_name == UNSUBSCRIBE || self.discarding.contains(&request.request_id) {
+            return Ok(());
+        }
+
+        let request_id = request.request_id.clone();
+        let (reply, discard) = server.answer_polled(request, self.peer, Instant::now())?;
+        if discard {
+            self.discarding.insert(request_id);
+        }
+        self.send(server, reply);
+        Ok(())
+    }
+
+    fn send(&mut self, server: &Server, reply: Option<Message>) {
+        if let Some(reply) = reply {
+            self.encoder.encode(&server.integrity.seal(&reply));
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.encoder.pending().is_empty() {
+            match self.stream.write(self.encoder.pending()) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.encoder.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Epoll(OwnedFd);
+
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Epoll(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn add(&self, fd: RawFd, key: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, key, events)
+    }
+
+    fn modify(&self, fd: RawFd, key: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, key, events)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, key: u64, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: key };
+        if unsafe { libc::epoll_ctl(self.0.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits for events until `deadline`, or indefinitely without one.
+    /// A wait cut short by a signal returns no events.
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, deadline: Option<Instant>) -> io::Result<()> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_nanos().div_ceil(Duration::from_millis(1).as_nanos()).min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        events.clear();
+        let n = unsafe { libc::epoll_wait(self.0.as_raw_fd(), events.as_mut_ptr(), events.capacity() as libc::c_int, timeout) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            };
+        }
+        // SAFETY: epoll_wait initialised the first n events.
+        unsafe { events.set_len(n as usize) };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::status::{Code, Status};
+    use crate::test_support::socket_path;
+    use crate::{Client, Error, Framing, Overload, Server};
+    use std::thread;
+    use std::time::Duration;
+
+    fn spawn_loop(path: &str, configure: impl FnOnce(&mut Server)) -> thread::JoinHandle<crate::Result<()>> {
+        let mut server = Server::bind(path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("grow", |body| Ok::<_, Status>(body.repeat(1024)));
+        server.register("fail", |_| Err(Status::new(Code::NotFound, "no such thing")));
+        server.register_stream("count", |_, sink| sink.send(b"1"));
+        server.register_upload("upload", |_| Ok(Vec::new()));
+        configure(&mut server);
+        thread::spawn(move || server.run_event_loop())
+    }
+
+    #[test]
+    fn one_thread_serves_many_connections() {
+        let path = socket_path();
+        let (handle_sender, handle) = std::sync::mpsc::channel();
+        let running = spawn_loop(&path, |server| handle_sender.send(server.shutdown_handle()).unwrap());
+
+        let mut clients: Vec<Client> = (0..16).map(|_| Client::new(&path, 5).unwrap()).collect();
+        let mut pending: Vec<_> = clients.iter_mut().enumerate().map(|(i, client)| client.send("echo", i.to_string().as_bytes()).unwrap()).collect();
+        for (i, client) in clients.iter_mut().enumerate().rev() {
+            assert_eq!(pending.pop().unwrap().wait(client).unwrap(), i.to_string().as_bytes());
+        }
+
+        let client = &mut clients[0];
+        client.notify("echo", b"unanswered").unwrap();
+        assert!(matches!(client.do_request("fail", b""), Err(Error::RemoteError { code: Code::NotFound, .. })));
+        assert_eq!(client.do_request("grow", &[b'x'; 4096]).unwrap().len(), 4 << 20);
+        client.ping().unwrap();
+
+        let mut negotiated = Client::builder(&path).handshake(true).connect().unwrap();
+        assert_eq!(negotiated.negotiated().map(|agreed| agreed.framing()), Some(Framing::LengthPrefixed));
+        assert_eq!(&negotiated.do_request("echo", b"a\nb").unwrap()[..], b"a\nb");
+
+        handle.recv().unwrap().shutdown(Duration::from_secs(5));
+        running.join().unwrap().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn methods_needing_a_thread_are_refused() {
+        let path = socket_path();
+        spawn_loop(&path, |_| {});
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let counted: Vec<_> = client.do_request_stream("count", b"").unwrap().collect();
+        assert!(matches!(counted[..], [Err(Error::RemoteError { code: Code::Unimplemented, .. })]));
+        let uploaded = client.do_request_streamed("upload", &[b'x'; 100_000][..]);
+        assert!(matches!(uploaded, Err(Error::RemoteError { code: Code::Unimplemented, .. })));
+        assert_eq!(&client.do_request("echo", b"still served").unwrap()[..], b"still served");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connections_over_the_limit_wait_or_are_turned_away() {
+        let path = socket_path();
+        spawn_loop(&path, |server| server.set_max_connections(Some(1)));
+        let mut first = Client::new(&path, 5).unwrap();
+        assert_eq!(&first.do_request("echo", b"first").unwrap()[..], b"first");
+        let mut second = Client::new(&path, 5).unwrap();
+        let queued = second.send("echo", b"second").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        drop(first);
+        assert_eq!(&queued.wait(&mut second).unwrap()[..], b"second");
+        std::fs::remove_file(&path).unwrap();
+
+        let path = socket_path();
+        spawn_loop(&path, |server| {
+            server.set_max_connections(Some(1));
+            server.set_overload(Overload::Reject);
+        });
+        let mut first = Client::new(&path, 5).unwrap();
+        assert_eq!(&first.do_request("echo", b"first").unwrap()[..], b"first");
+        let mut second = Client::new(&path, 5).unwrap();
+        assert!(matches!(second.do_request("echo", b"second"), Err(Error::RemoteError { code: Code::Unavailable, .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::Error;
+
+/// A change in a client's connection, or a failed call, reported to the
+/// observers added with [`crate::ClientBuilder::on_event`].
+#[derive(Debug)]
+pub enum ConnectionEvent<'a> {
+    /// A connection was made, the first or a replacement.
+    Connected,
+    /// The connection became unusable through `error`. One closed with
+    /// [`crate::Client::close`] is reported when next used.
+    Disconnected { error: &'a Error },
+    /// The client is about to replace its connection with a new one.
+    ReconnectAttempt,
+    /// A call answered with a single response failed, remotely or not.
+    RequestFailed { method_name: &'a str, error: &'a Error },
+}
+
+type Observer = Arc<dyn Fn(&ConnectionEvent<'_>) + Send + Sync>;
+
+/// The observers of a client, shared with the builder it came from.
+#[derive(Clone, Default)]
+pub(crate) struct Observers(Vec<Observer>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observers {
+    pub(crate) fn push(&mut self, observer: Observer) {
+        self.0.push(observer);
+    }
+
+    pub(crate) fn emit(&self, event: ConnectionEvent<'_>) {
+        for observer in &self.0 {
+            observer(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Server, Status};
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn observers_follow_the_connection() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("fail", |_| Err("boom".to_string()));
+        thread::spawn(move || server.run());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let mut client = Client::builder(&path)
+            .on_event(move |event| {
+                let name = match event {
+                    ConnectionEvent::Connected => "connected".to_string(),
+                    ConnectionEvent::Disconnected { .. } => "disconnected".to_string(),
+                    ConnectionEvent::ReconnectAttempt => "reconnecting".to_string(),
+                    ConnectionEvent::RequestFailed { method_name, .. } => format!("{} failed", method_name),
+                };
+                seen.lock().unwrap().push(name);
+            })
+            .connect()
+            .unwrap();
+
+        assert!(client.do_request("fail", b"").is_err());
+        client.close().unwrap();
+        assert!(client.do_request("echo", b"lost").is_err());
+        client.set_auto_reconnect(true);
+        assert_eq!(&client.do_request("echo", b"ping").unwrap()[..], b"ping");
+        let expected = [
+            "connected",
+            "fail failed",
+            "disconnected",
+            "echo failed",
+            "reconnecting",
+            "connected",
+        ];
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::{self, Read, Write};
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::transport::Transport;
+
+/// Which faults a [`FaultyTransport`] injects, and where.
+///
+/// Faults on what the client sends name the frame by its number on the
+/// connection, from 0, as the client writes each frame whole; faults on
+/// what it receives name a byte offset into the incoming stream, however
+/// the peer's frames are split up in reads. Either way the same policy
+/// fails the same way on every run.
+#[derive(Debug, Clone, Default)]
+pub struct FaultPolicy {
+    latency: Option<Duration>,
+    frames: Vec<(u64, FrameFault)>,
+    corrupt_incoming: Vec<u64>,
+    disconnect_incoming: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FrameFault {
+    Truncate(usize),
+    Corrupt(usize),
+    Disconnect(usize),
+}
+
+impl FaultPolicy {
+    pub fn new() -> Self {
+        FaultPolicy::default()
+    }
+
+    /// Waits `latency` before every read and write.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Sends only the first `len` bytes of frame `frame` and drops the
+    /// rest, leaving the peer waiting for it.
+    pub fn truncate_frame(mut self, frame: u64, len: usize) -> Self {
+        self.frames.push((frame, FrameFault::Truncate(len)));
+        self
+    }
+
+    /// Flips every bit of the byte at `offset` in frame `frame`.
+    pub fn corrupt_frame(mut self, frame: u64, offset: usize) -> Self {
+        self.frames.push((frame, FrameFault::Corrupt(offset)));
+        self
+    }
+
+    /// Sends the first `len` bytes of frame `frame`, then closes the
+    /// connection.
+    pub fn disconnect_in_frame(mut self, frame: u64, len: usize) -> Self {
+        self.frames.push((frame, FrameFault::Disconnect(len)));
+        self
+    }
+
+    /// Flips every bit of the received byte at `offset`.
+    pub fn corrupt_incoming(mut self, offset: u64) -> Self {
+        self.corrupt_incoming.push(offset);
+        self
+    }
+
+    /// Ends the incoming stream after `offset` bytes, as if the peer hung
+    /// up there, mid-frame or not.
+    pub fn disconnect_incoming(mut self, offset: u64) -> Self {
+        self.disconnect_incoming = Some(offset);
+        self
+    }
+}
+
+/// A [`Transport`] that passes everything through to another one, except
+/// for the faults its [`FaultPolicy`] injects, for testing how code on top
+/// copes with slow, broken or lying peers:
+///
+/// ```no_run
+/// # use std::os::unix::net::UnixStream;
+/// # use unixconn_rust::Client;
+/// # use unixconn_rust::testing::{FaultPolicy, FaultyTransport};
+/// let policy = FaultPolicy::new().disconnect_incoming(10);
+/// let client = Client::builder("/run/agent.sock")
+///     .transport(move || Ok(FaultyTransport::new(UnixStream::connect("/run/agent.sock")?, policy.clone())))
+///     .connect()?;
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+///
+/// Frames and bytes are counted per connection, across the clones the
+/// client makes of it.
+pub struct FaultyTransport {
+    inner: Box<dyn Transport>,
+    policy: Arc<FaultPolicy>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Default)]
+struct Counts {
+    frames_written: u64,
+    bytes_read: u64,
+}
+
+impl FaultyTransport {
+    pub fn new<T: Transport + 'static>(inner: T, policy: FaultPolicy) -> Self {
+        FaultyTransport { inner: Box::new(inner), policy: Arc::new(policy), counts: Arc::default() }
+    }
+
+    fn counts(&self) -> MutexGuard<'_, Counts> {
+        self.counts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn delay(&self) {
+        if let Some(latency) = self.policy.latency {
+            thread::sleep(latency);
+        }
+    }
+
+    /// Writes one frame through `write`, with the faults scheduled for it.
+    fn write_frame(&mut self, frame: &[u8], write: impl FnOnce(&mut dyn Transport, &[u8]) -> io::Result<()>) -> io::Result<()> {
+        self.delay();
+        let number = {
+            let mut counts = self.counts();
+            counts.frames_written += 1;
+            counts.frames_written - 1
+        };
+        let mut frame = frame.to_vec();
+        let mut disconnect = false;
+        for (_, fault) in self.policy.frames.iter().filter(|(n, _)| *n == number) {
+            match *fault {
+                FrameFault::Truncate(len) => frame.truncate(len),
+                FrameFault::Corrupt(offset) => {
+                    if let Some(byte) = frame.get_mut(offset) {
+                        *byte ^= 0xFF;
+                    }
+                }
+                FrameFault::Disconnect(len) => {
+                    frame.truncate(len);
+                    disconnect = true;
+                }
+            }
+        }
+        write(&mut *self.inner, &frame)?;
+        if disconnect {
+            self.inner.shutdown()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for FaultyTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.delay();
+        let offset = self.counts().bytes_read;
+        let limit = match self.policy.disconnect_incoming {
+            // Whatever was sent after the cut stays unread.
+            Some(end) => buf.len().min(end.saturating_sub(offset) as usize),
+            None => buf.len(),
+        };
+        if limit == 0 && !buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..limit])?;
+        for &corrupt in &self.policy.corrupt_incoming {
+            if let Some(byte) = corrupt.checked_sub(offset).and_then(|at| buf[..n].get_mut(at as usize)) {
+                *byte ^= 0xFF;
+            }
+        }
+        self.counts().bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for FaultyTransport {
+    /// Takes the whole buffer as one frame, so that faults land where the
+    /// policy says whatever the socket accepts at once.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_frame(buf, |inner, frame| inner.write_all(frame))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(FaultyTransport { inner: self.inner.try_clone()?, policy: self.policy.clone(), counts: self.counts.clone() }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn write_with_fds(&mut self, data: &[u8], fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        self.write_frame(data, |inner, frame| inner.write_with_fds(frame, fds))
+    }
+
+    fn take_fds(&mut self, count: usize) -> io::Result<Vec<OwnedFd>> {
+        self.inner.take_fds(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Framing, Message};
+    use crate::test_support::echo_server;
+    use crate::{Client, Error};
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
+
+    fn faulty_client(path: &str, policy: FaultPolicy) -> Client {
+        let path = path.to_string();
+        Client::builder("faulty")
+            .timeout(Some(Duration::from_millis(200)))
+            .transport(move || Ok(FaultyTransport::new(UnixStream::connect(&path)?, policy.clone())))
+            .connect()
+            .unwrap()
+    }
+
+    #[test]
+    fn faults_land_where_the_policy_says() {
+        let path = echo_server(4);
+        let request = Framing::default().encode(&Message::request("echo", b"hello"));
+        let body_at = request.windows(5).position(|window| window == b"hello").unwrap();
+
+        let mut corrupted = faulty_client(&path, FaultPolicy::new().corrupt_frame(1, body_at));
+        assert_eq!(&corrupted.do_request("echo", b"hello").unwrap()[..], b"hello");
+        assert_ne!(corrupted.do_request("echo", b"hello").ok().as_deref(), Some(&b"hello"[..]));
+
+        let mut truncated = faulty_client(&path, FaultPolicy::new().truncate_frame(0, 3));
+        assert!(matches!(truncated.do_request("echo", b"hello"), Err(Error::Timeout)));
+
+        let mut cut = faulty_client(&path, FaultPolicy::new().disconnect_incoming(10));
+        assert!(matches!(cut.do_request("echo", b"hello"), Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+
+        let mut hung_up = faulty_client(&path, FaultPolicy::new().disconnect_in_frame(0, body_at));
+        assert!(hung_up.do_request("echo", b"hello").is_err());
+
+        let mut slow = faulty_client(&path, FaultPolicy::new().latency(Duration::from_millis(20)));
+        let started = Instant::now();
+        slow.do_request("echo", b"hello").unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(40));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+//! Passing open file descriptors alongside messages (SCM_RIGHTS).
+//!
+//! The descriptors travel as ancillary data with the first bytes of the
+//! frame they belong to, and the frame names how many with a header. The
+//! receiving side queues whatever descriptors arrive while it reads and
+//! takes that many off the front once the frame is complete, which keeps
+//! them with the right frame however the bytes were split into reads. A
+//! peer passing more than its frames name is disconnected before they
+//! pile up.
+
+use std::collections::VecDeque;
+use std::io::{self, IoSlice, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::protocol::Message;
+use crate::transport::{write_all_vectored, Transport};
+
+pub(crate) const FDS_HEADER: &str = "__fds";
+
+/// The most descriptors the kernel passes in one message (SCM_MAX_FD).
+pub(crate) const MAX_FDS: usize = 253;
+
+/// Marks `message` as carrying `count` descriptors.
+pub(crate) fn mark(message: &mut Message, count: usize) {
+    if count > 0 {
+        message.headers.push((FDS_HEADER.to_string(), count.to_string()));
+    }
+}
+
+/// How many descriptors `message` says it carries.
+pub(crate) fn count(message: &Message) -> Result<usize> {
+    match message.header(FDS_HEADER) {
+        Some(count) => count.parse().map_err(|_| Error::Protocol(format!("invalid descriptor count {:?}", count))),
+        None => Ok(0),
+    }
+}
+
+/// Takes the first `count` descriptors off `received`.
+pub(crate) fn take(received: &mut VecDeque<OwnedFd>, count: usize) -> io::Result<Vec<OwnedFd>> {
+    if count > received.len() {
+        let e = format!("frame names {} descriptors but {} arrived", count, received.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    Ok(received.drain(..count).collect())
+}
+
+/// Writes all of `data` to `socket`, with `fds` attached to its first bytes.
+pub(crate) fn write_with_fds(socket: &UnixStream, data: &[u8], fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+    if fds.is_empty() {
+        return (&*socket).write_all(data);
+    }
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("at most {} descriptors per message", MAX_FDS)));
+    }
+
+    let payload = mem::size_of_val(fds) as u32;
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(payload) } as usize;
+    // u64 words keep the buffer aligned for cmsghdr.
+    let mut control = vec![0u64; space.div_ceil(mem::size_of::<u64>())];
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    // SAFETY: msghdr is plain data, valid when zeroed.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+
+    // SAFETY: the control buffer holds one header with room for `fds`, as
+    // sized by CMSG_SPACE above, and both buffers outlive the sendmsg call.
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(payload) as _;
+        let data = libc::CMSG_DATA(cmsg).cast::<libc::c_int>();
+        for (i, fd) in fds.iter().enumerate() {
+            data.add(i).write_unaligned(fd.as_raw_fd());
+        }
+        libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    (&*socket).write_all(&data[sent as usize..])
+}
+
+/// The most descriptors a reader holds untaken: those of the frame being
+/// read, and of the next one if its first bytes came in the same read.
+const MAX_QUEUED_FDS: usize = 2 * MAX_FDS;
+
+/// Reads like `read(2)`, queueing descriptors that arrive on the way.
+fn recv_with_fds(socket: BorrowedFd<'_>, buf: &mut [u8], received: &mut VecDeque<OwnedFd>) -> io::Result<usize> {
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<libc::c_int>()) as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(mem::size_of::<u64>())];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    // SAFETY: msghdr is plain data, valid when zeroed.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+
+    // SAFETY: both buffers are live and their lengths are set above.
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: the kernel filled in `msg_controllen` bytes of well-formed
+    // headers, walked with the CMSG macros; SCM_RIGHTS data is descriptors
+    // now owned by this process.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<libc::c_int>();
+                let data = libc::CMSG_DATA(cmsg).cast::<libc::c_int>();
+                for i in 0..count {
+                    received.push_back(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer passed more descriptors than fit one message"));
+    }
+    if received.len() > MAX_QUEUED_FDS {
+        received.clear();
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer passed descriptors no frame names"));
+    }
+    Ok(n as usize)
+}
+
+/// A unix socket that keeps the descriptors passed to it while reading,
+/// to be handed out by frame.
+pub(crate) struct FdStream<S> {
+    socket: S,
+    received: VecDeque<OwnedFd>,
+}
+
+impl<S> FdStream<S> {
+    pub(crate) fn new(socket: S) -> Self {
+        FdStream { socket, received: VecDeque::new() }
+    }
+
+    pub(crate) fn take(&mut self, count: usize) -> io::Result<Vec<OwnedFd>> {
+        take(&mut self.received, count)
+    }
+}
+
+impl<S: AsFd> Read for FdStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        recv_with_fds(self.socket.as_fd(), buf, &mut self.received)
+    }
+}
+
+impl Write for FdStream<UnixStream> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.socket.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Transport for FdStream<UnixStream> {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(FdStream::new(self.socket.try_clone()?)))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    fn write_parts(&mut self, parts: &[IoSlice<'_>]) -> io::Result<()> {
+        write_all_vectored(&mut self.socket, parts)
+    }
+
+    fn write_with_fds(&mut self, data: &[u8], fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        write_with_fds(&self.socket, data, fds)
+    }
+
+    fn take_fds(&mut self, count: usize) -> io::Result<Vec<OwnedFd>> {
+        self.take(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::{Server, Status};
+
+    #[test]
+    fn descriptors_travel_with_requests_and_responses() {
+        let mut server = Server::new();
+        server.register_with_context("greet", |context, body| {
+            let mut passed = context.take_fds();
+            let mut to_caller = UnixStream::from(passed.pop().ok_or("no socket passed")?);
+            to_caller.write_all(body).map_err(|e| e.to_string())?;
+
+            let (ours, theirs) = UnixStream::pair().map_err(|e| e.to_string())?;
+            (&ours).write_all(b"from the server").map_err(|e| e.to_string())?;
+            context.attach_fd(theirs.into());
+            Ok(b"done".to_vec())
+        });
+        let mut client = testing::connect(server).unwrap();
+
+        let (mine, passed) = UnixStream::pair().unwrap();
+        let (body, mut fds) = client.do_request_with_fds("greet", b"hello", &[passed.as_fd()]).unwrap();
+        drop(passed);
+        assert_eq!(&body[..], b"done");
+        let mut greeting = [0u8; 5];
+        (&mine).read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        assert_eq!(fds.len(), 1);
+        let mut reply = [0u8; 15];
+        UnixStream::from(fds.pop().unwrap()).read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"from the server");
+
+        let failed = client.do_request_with_fds("greet", b"again", &[]);
+        assert!(matches!(failed, Err(crate::Error::RemoteError { .. })));
+    }
+
+    #[test]
+    fn peers_passing_unnamed_descriptors_are_disconnected() {
+        let mut server = Server::new();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let serving = std::thread::spawn(move || server.serve(server_end));
+
+        let (passed, _peer) = UnixStream::pair().unwrap();
+        let fds = vec![passed.as_fd(); MAX_FDS];
+        let request = crate::Framing::default().encode(&Message::notification("echo", b"x"));
+        for _ in 0..3 {
+            if write_with_fds(&client_end, &request, &fds).is_err() {
+                break;
+            }
+        }
+        drop(client_end);
+        match serving.join().unwrap() {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            other => panic!("expected the connection to fail, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Entry points for the fuzz targets in `fuzz/`, built only with
+//! `--cfg fuzzing` as `cargo fuzz` does. They feed arbitrary bytes to the
+//! frame parsers, which must fail with an error rather than panic, and
+//! check that the blocking reader and the [`Decoder`] agree on every frame.
+
+use bytes::Bytes;
+
+use crate::protocol::{self, Decoder, Framing};
+
+/// Caps how much a single frame may take, so a fuzzer finding a frame that
+/// makes the reader allocate without bound sees it fail instead.
+const MAX_FRAME: usize = 1 << 16;
+
+/// The framing picked by the first byte of fuzz input, and the rest.
+pub fn split_framing(data: &[u8]) -> (Framing, &[u8]) {
+    match data.split_first() {
+        Some((selector, rest)) => {
+            let framing = match selector % 3 {
+                0 => Framing::Delimited,
+                1 => Framing::Escaped,
+                _ => Framing::LengthPrefixed,
+            };
+            (framing, rest)
+        }
+        None => (Framing::default(), data),
+    }
+}
+
+/// Parses `data` as the fields of one delimited frame, without its
+/// terminator.
+pub fn parse_message(data: &[u8]) {
+    let _ = protocol::parse_message(Bytes::copy_from_slice(data));
+}
+
+/// Reads frames off `data` as a connection would until one fails.
+pub fn read_frames(framing: Framing, data: &[u8]) {
+    let mut reader = data;
+    while framing.read_limited(&mut reader, Some(MAX_FRAME)).is_ok() {}
+}
+
+/// Decodes `data` arriving in chunks of `chunk` bytes, as the
+/// non-blocking transports feed it in, and checks each frame against what
+/// the blocking reader makes of the same bytes.
+pub fn decode_frames(framing: Framing, data: &[u8], chunk: usize) {
+    let mut decoder = Decoder::new(framing, Some(MAX_FRAME));
+    let (mut fed, mut decoded) = (0, 0);
+    for piece in data.chunks(chunk.max(1)) {
+        decoder.feed(piece);
+        fed += piece.len();
+        loop {
+            let mut reader = &data[decoded..fed];
+            match (decoder.decode(), framing.read_limited(&mut reader, Some(MAX_FRAME))) {
+                (Ok(Some(frame)), Ok(read)) => {
+                    assert_eq!(frame, read);
+                    decoded = fed - reader.len();
+                }
+                (Ok(None), Err(_)) => break,
+                (Err(_), Err(_)) => return,
+                (Ok(None), Ok(_)) => panic!("read a frame the decoder is still waiting for"),
+                (Ok(Some(_)), Err(e)) => panic!("decoded a frame the reader rejects: {}", e),
+                (Err(e), Ok(_)) => panic!("read a frame the decoder rejects: {}", e),
+            }
+        }
+    }
+}
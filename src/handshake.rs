@@ -0,0 +1,243 @@
+//! Optional exchange of protocol versions and features right after connect.
+//!
+//! The client sends a `__handshake` request in the framing both sides were
+//! configured with, listing what it supports as `key=value` lines:
+//!
+//! ```text
+//! version=1
+//! framing=length-prefixed,escaped,delimited
+//! features=headers,streaming,cancel,deadline,subscriptions
+//! compression=zstd,lz4
+//! ```
+//!
+//! The server answers in the same form with what it agreed to: the first
+//! framing and the first compression codec in the client's lists it accepts
+//! (`none` if there is no common codec), and the features both support.
+//! Frames after the answer use the agreed framing in both directions. A peer
+//! that does not know the handshake is treated as speaking the legacy
+//! protocol with none of the features.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::protocol::Framing;
+use crate::status::{Code, Status};
+
+pub(crate) const HANDSHAKE: &str = "__handshake";
+
+pub(crate) const VERSION: u32 = 1;
+
+/// Offered in order of preference, binary-safe framings first.
+const FRAMINGS: [Framing; 3] = [Framing::LengthPrefixed, Framing::Escaped, Framing::Delimited];
+
+pub(crate) const FEATURES: [&str; 5] = ["headers", "streaming", "cancel", "deadline", "subscriptions"];
+
+const NO_COMPRESSION: &str = "none";
+
+/// What client and server agreed on during the handshake, from
+/// [`crate::Client::negotiated`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    version: u32,
+    framing: Framing,
+    features: Vec<String>,
+    compression: Option<String>,
+}
+
+impl Negotiated {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// The codec bodies may be compressed with, by its
+    /// [`crate::Compressor::name`].
+    pub fn compression(&self) -> Option<&str> {
+        self.compression.as_deref()
+    }
+
+    fn encode(&self) -> Bytes {
+        Bytes::from(format!(
+            "version={}\nframing={}\nfeatures={}\ncompression={}\n",
+            self.version,
+            framing_name(self.framing),
+            self.features.join(","),
+            self.compression.as_deref().unwrap_or(NO_COMPRESSION)
+        ))
+    }
+
+    /// Reads the server's answer.
+    pub(crate) fn parse(body: &[u8]) -> Result<Self> {
+        let fields = parse_fields(body).ok_or_else(|| Error::Protocol("malformed handshake answer".to_string()))?;
+        let invalid = |field: &str| Error::Protocol(format!("handshake answer has no valid {}", field));
+
+        let version = fields.get("version").and_then(|v| v.parse().ok()).ok_or_else(|| invalid("version"))?;
+        let framing = fields.get("framing").and_then(|f| framing_from_name(f)).ok_or_else(|| invalid("framing"))?;
+        let features = list(fields.get("features").copied()).map(str::to_string).collect();
+        let compression = list(fields.get("compression").copied()).find(|&codec| codec != NO_COMPRESSION).map(str::to_string);
+        Ok(Negotiated { version, framing, features, compression })
+    }
+}
+
+/// The client's side of the handshake, offering everything it supports and
+/// the compression codecs it has, by preference.
+pub(crate) fn offer(codecs: &[&str]) -> Bytes {
+    let framings: Vec<_> = FRAMINGS.into_iter().map(framing_name).collect();
+    Bytes::from(format!(
+        "version={}\nframing={}\nfeatures={}\ncompression={}\n",
+        VERSION,
+        framings.join(","),
+        FEATURES.join(","),
+        codecs.join(",")
+    ))
+}
+
+/// The server's side of the handshake: the answer to send, in the framing
+/// the connection was opened with, and the framing to use afterwards.
+/// `codecs` are the compression codecs the server has.
+pub(crate) fn answer(offer: &[u8], base: Framing, codecs: &[&str]) -> Result<(Bytes, Framing), Status> {
+    let fields = parse_fields(offer).ok_or_else(|| Status::new(Code::InvalidArgument, "malformed handshake"))?;
+    let version = fields
+        .get("version")
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| Status::new(Code::InvalidArgument, "handshake without a version"))?;
+
+    let framing = agreed_framing(&fields, base);
+    let features = list(fields.get("features").copied())
+        .filter(|feature| FEATURES.contains(feature))
+        .map(str::to_string)
+        .collect();
+    let compression = list(fields.get("compression").copied())
+        .find(|codec| codecs.contains(codec))
+        .map(str::to_string);
+
+    let agreed = Negotiated { version: version.min(VERSION), framing, features, compression };
+    Ok((agreed.encode(), framing))
+}
+
+/// The framing [`answer`] settles on, for the reading side of a server
+/// connection, which switches before the answer is written: `base` for an
+/// offer it refuses.
+pub(crate) fn answered_framing(offer: &[u8], base: Framing) -> Framing {
+    answer(offer, base, &[]).map_or(base, |(_, framing)| framing)
+}
+
+fn agreed_framing(fields: &HashMap<&str, &str>, base: Framing) -> Framing {
+    list(fields.get("framing").copied()).find_map(framing_from_name).unwrap_or(base)
+}
+
+pub(crate) fn parse_fields(body: &[u8]) -> Option<HashMap<&str, &str>> {
+    std::str::from_utf8(body)
+        .ok()?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.split_once('='))
+        .collect()
+}
+
+pub(crate) fn list(value: Option<&str>) -> impl Iterator<Item = &str> {
+    value.unwrap_or("").split(',').filter(|item| !item.is_empty())
+}
+
+pub(crate) fn framing_name(framing: Framing) -> &'static str {
+    match framing {
+        Framing::Delimited => "delimited",
+        Framing::Escaped => "escaped",
+        Framing::LengthPrefixed => "length-prefixed",
+    }
+}
+
+pub(crate) fn framing_from_name(name: &str) -> Option<Framing> {
+    FRAMINGS.into_iter().find(|&framing| framing_name(framing) == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Server};
+    use std::io::{BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn our_own_offer_settles_on_length_prefixed_framing() {
+        let (answer, framing) = answer(&offer(&[]), Framing::Delimited, &["zstd"]).unwrap();
+        assert_eq!(framing, Framing::LengthPrefixed);
+        let agreed = Negotiated::parse(&answer).unwrap();
+        assert_eq!(agreed.features().len(), FEATURES.len());
+        assert_eq!(agreed.compression(), None);
+    }
+
+    #[test]
+    fn answer_picks_the_clients_preferred_framing_and_common_features() {
+        let offer = b"version=3\nframing=escaped,delimited\nfeatures=headers,zstd,cancel\ncompression=zstd,gzip\n";
+        let (answer, framing) = answer(offer, Framing::Delimited, &["lz4", "gzip"]).unwrap();
+        assert_eq!(framing, Framing::Escaped);
+
+        let agreed = Negotiated::parse(&answer).unwrap();
+        assert_eq!(agreed.version(), VERSION);
+        assert_eq!(agreed.framing(), Framing::Escaped);
+        assert_eq!(agreed.features(), ["headers", "cancel"]);
+        assert!(!agreed.supports("zstd"));
+        assert_eq!(agreed.compression(), Some("gzip"));
+    }
+
+    #[test]
+    fn refused_offers_keep_the_framing_the_connection_was_opened_with() {
+        let offer = b"version=x\nframing=length-prefixed\n";
+        assert!(answer(offer, Framing::Delimited, &[]).is_err());
+        assert_eq!(answered_framing(offer, Framing::Delimited), Framing::Delimited);
+        assert_eq!(answered_framing(b"version=1\nframing=length-prefixed\n", Framing::Delimited), Framing::LengthPrefixed);
+    }
+
+    #[test]
+    fn clients_upgrade_framing_with_servers_that_take_part() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::builder(&path).handshake(true).connect().unwrap();
+        let negotiated = client.negotiated().unwrap();
+        assert_eq!(negotiated.framing(), Framing::LengthPrefixed);
+        assert!(negotiated.supports("cancel"));
+
+        let body = [0x1E, 0x1F, 0x00];
+        assert_eq!(&client.do_request("echo", &body).unwrap()[..], &body);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn clients_fall_back_when_the_server_hangs_up_on_the_handshake() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let request = Framing::Delimited.read(&mut BufReader::new(&conn)).unwrap();
+                if request.method_name != HANDSHAKE {
+                    conn.write_all(&Framing::Delimited.encode(&request)).unwrap();
+                }
+            }
+        });
+
+        let mut client = Client::builder(&path).handshake(true).connect().unwrap();
+        assert!(client.negotiated().is_none());
+        assert_eq!(&client.do_request("echo", b"legacy").unwrap()[..], b"legacy");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! The reserved `__info` request, answered with what the server is and
+//! supports, in the `key=value` lines of the handshake:
+//!
+//! ```text
+//! version=0.1.2
+//! protocol=1
+//! framing=delimited
+//! features=headers,streaming,cancel,deadline,subscriptions
+//! compression=zstd
+//! ```
+//!
+//! `version` is the server library's own; servers built on other stacks may
+//! put theirs there in any form.
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::handshake::{self, FEATURES, VERSION};
+use crate::protocol::Framing;
+
+pub(crate) const INFO: &str = "__info";
+
+/// What a server reports about itself, from [`crate::Client::server_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    version: String,
+    protocol: u32,
+    framing: Option<Framing>,
+    features: Vec<String>,
+    compression: Vec<String>,
+}
+
+impl ServerInfo {
+    /// The version of the library the server is built on.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The newest handshake version the server speaks.
+    pub fn protocol(&self) -> u32 {
+        self.protocol
+    }
+
+    /// The framing the server was configured with, before any handshake;
+    /// `None` if it named one this client does not know.
+    pub fn framing(&self) -> Option<Framing> {
+        self.framing
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// The compression codecs the server has, by
+    /// [`crate::Compressor::name`].
+    pub fn compression(&self) -> &[String] {
+        &self.compression
+    }
+}
+
+pub(crate) fn describe(framing: Framing, codecs: &[&str]) -> Bytes {
+    Bytes::from(format!(
+        "version={}\nprotocol={}\nframing={}\nfeatures={}\ncompression={}\n",
+        env!("CARGO_PKG_VERSION"),
+        VERSION,
+        handshake::framing_name(framing),
+        FEATURES.join(","),
+        codecs.join(",")
+    ))
+}
+
+/// Reads an `__info` answer. Only `version` and `protocol` are required;
+/// fields this client does not know are ignored.
+pub(crate) fn parse(body: &[u8]) -> Result<ServerInfo> {
+    let fields = handshake::parse_fields(body).ok_or_else(|| Error::Protocol("malformed server info".to_string()))?;
+    let invalid = |field: &str| Error::Protocol(format!("server info has no valid {}", field));
+    Ok(ServerInfo {
+        version: fields.get("version").ok_or_else(|| invalid("version"))?.to_string(),
+        protocol: fields.get("protocol").and_then(|p| p.parse().ok()).ok_or_else(|| invalid("protocol"))?,
+        framing: fields.get("framing").and_then(|f| handshake::framing_from_name(f)),
+        features: handshake::list(fields.get("features").copied()).map(str::to_string).collect(),
+        compression: handshake::list(fields.get("compression").copied()).map(str::to_string).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Server, Status};
+
+    #[test]
+    fn servers_describe_themselves() {
+        let mut server = Server::new();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        let mut client = testing::connect(server).unwrap();
+
+        let info = client.server_info().unwrap();
+        assert_eq!((info.version(), info.protocol()), (env!("CARGO_PKG_VERSION"), VERSION));
+        assert_eq!(info.framing(), Some(Framing::Delimited));
+        assert!(info.supports("streaming"));
+        assert!(info.compression().is_empty());
+    }
+
+    #[test]
+    fn other_servers_may_leave_fields_out() {
+        let info = parse(b"version=go-1.4\nprotocol=1\nframing=carrier-pigeon\nuptime=3d\n").unwrap();
+        assert_eq!((info.version(), info.framing()), ("go-1.4", None));
+        assert!(info.features().is_empty());
+        assert!(matches!(parse(b"version=1.0\n"), Err(Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,188 @@
+//! Hooks a [`crate::Client`] runs around the requests it sends.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::error::Result;
+use crate::protocol::{check_header, Message};
+
+/// Sees every request a client sends before it is written, and the outcome
+/// of every call answered with a single response; see
+/// [`crate::ClientBuilder::interceptor`]. Interceptors run in the order they
+/// were added.
+///
+/// A closure taking an [`OutgoingRequest`] is an interceptor with only the
+/// first hook.
+pub trait Interceptor: Send + Sync {
+    /// Runs on each request, notification or stream opened, and on each
+    /// retry of it. An error fails the call without sending anything.
+    fn before(&self, request: &mut OutgoingRequest<'_>) -> Result<()> {
+        let _ = request;
+        Ok(())
+    }
+
+    /// Runs once the response to a request is read, or the attempt to get
+    /// it failed. Streams, duplex calls and subscriptions are not reported.
+    fn after(&self, outcome: &CallOutcome<'_>) {
+        let _ = outcome;
+    }
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(&mut OutgoingRequest<'_>) -> Result<()> + Send + Sync,
+{
+    fn before(&self, request: &mut OutgoingRequest<'_>) -> Result<()> {
+        self(request)
+    }
+}
+
+/// A request about to be sent, which [`Interceptor::before`] may change.
+pub struct OutgoingRequest<'a> {
+    message: &'a mut Message,
+}
+
+impl OutgoingRequest<'_> {
+    pub fn method_name(&self) -> &str {
+        &self.message.method_name
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.message.request_id
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.message.body
+    }
+
+    pub fn set_body(&mut self, body: &[u8]) {
+        self.message.body = Bytes::copy_from_slice(body);
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.message.header(name)
+    }
+
+    /// Sets a header, replacing any of the same name. Names and values are
+    /// restricted as for [`crate::Client::do_request_with_headers`].
+    pub fn set_header(&mut self, name: &str, value: &str) -> Result<()> {
+        check_header(name, value)?;
+        self.message.headers.retain(|(key, _)| key != name);
+        self.message.headers.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// How a call went, as reported to [`Interceptor::after`].
+pub struct CallOutcome<'a> {
+    pub(crate) method_name: &'a str,
+    pub(crate) request_id: &'a str,
+    pub(crate) request_len: usize,
+    pub(crate) elapsed: Duration,
+    pub(crate) result: &'a Result<Bytes>,
+}
+
+impl CallOutcome<'_> {
+    pub fn method_name(&self) -> &str {
+        self.method_name
+    }
+
+    pub fn request_id(&self) -> &str {
+        self.request_id
+    }
+
+    /// The size of the request body as the caller passed it, before any
+    /// compression.
+    pub fn request_len(&self) -> usize {
+        self.request_len
+    }
+
+    /// From the request being prepared to the response being read.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The response body or the error the caller gets.
+    pub fn result(&self) -> &Result<Bytes> {
+        self.result
+    }
+}
+
+/// The interceptors of a client, shared with the builder it came from.
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors(Vec<Arc<dyn Interceptor>>);
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptors({})", self.0.len())
+    }
+}
+
+impl Interceptors {
+    pub(crate) fn push(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.0.push(interceptor);
+    }
+
+    pub(crate) fn before(&self, message: &mut Message) -> Result<()> {
+        let mut request = OutgoingRequest { message };
+        self.0.iter().try_for_each(|interceptor| interceptor.before(&mut request))
+    }
+
+    pub(crate) fn after(&self, outcome: &CallOutcome<'_>) {
+        for interceptor in &self.0 {
+            interceptor.after(outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Client, Error, Server};
+    use std::sync::Mutex;
+
+    /// Records every call it sees, and tags requests with a header.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Interceptor for Arc<Recorder> {
+        fn before(&self, request: &mut OutgoingRequest<'_>) -> Result<()> {
+            request.set_header("trace-id", "4bf92f")
+        }
+
+        fn after(&self, outcome: &CallOutcome<'_>) {
+            let result = match outcome.result() {
+                Ok(body) => format!("ok {}", body.len()),
+                Err(e) => format!("err {}", e),
+            };
+            self.calls.lock().unwrap().push(format!("{} {} {}", outcome.method_name(), outcome.request_len(), result));
+        }
+    }
+
+    #[test]
+    fn interceptors_see_requests_and_outcomes() {
+        let mut server = Server::new();
+        server.register_with_context("trace", |context, _| Ok(context.header("trace-id").unwrap_or("none").as_bytes().to_vec()));
+        server.register("fail", |_| Err("boom".to_string()));
+        let recorder = Arc::new(Recorder::default());
+        let builder = Client::builder("intercepted")
+            .interceptor(recorder.clone())
+            .interceptor(|request: &mut OutgoingRequest<'_>| match request.method_name() {
+                "forbidden" => Err(Error::Protocol("not from here".to_string())),
+                _ => Ok(()),
+            });
+        let (mut client, server_end) = testing::pair_with(builder).unwrap();
+        std::thread::spawn(move || server.serve(server_end));
+
+        assert_eq!(&client.do_request("trace", b"abc").unwrap()[..], b"4bf92f");
+        let pending = client.send("fail", b"").unwrap();
+        assert!(pending.wait(&mut client).is_err());
+        assert!(matches!(client.do_request("forbidden", b""), Err(Error::Protocol(_))));
+        assert_eq!(*recorder.calls.lock().unwrap(), ["trace 3 ok 6", "fail 0 err client response error: boom"]);
+    }
+}
@@ -1,142 +1,101 @@
-
-use std::os::unix::net::UnixStream;
-use std::io::{self, Read, Write, BufReader};
-use std::error::Error;
-use bytes::{Bytes, BytesMut, BufMut};
-use uuid::Uuid;
-use std::time::Duration;
-
-const PROTOCOL_FIELDS: usize = 4;
-
-fn metadata_delim() -> &'static [u8] {
-    &[0x1E]
-}
-
-fn message_delim() -> u8 {
-    0x1F
-}
-
-struct Message {
-    request_id: String,
-    method_name: String,
-    body: Bytes,
-    error: String,
-}
-
-pub struct Client {
-    conn: UnixStream,
-    timeout: u64
-}
-
-impl Client {
-    pub fn new(address: &str, timeout: u64) -> Result<Self, Box<dyn Error>> {
-        let conn = UnixStream::connect(address)?;
-        Ok(Client { conn, timeout })
-    }
-
-    pub fn close(&self) -> io::Result<()> {
-        self.conn.shutdown(std::net::Shutdown::Both)
-    }
-
-    pub fn do_request(&mut self, method_name: &str, request_body: &[u8]) -> Result<Bytes, Box<dyn Error>> {
-        let request_id = Uuid::new_v4().to_string();
-        let request = Message {
-            request_id: request_id.clone(),
-            method_name: method_name.to_string(),
-            body: Bytes::from(request_body.to_vec()),
-            error: String::new(),
-        };
-
-        let raw_request = message_to_bytes(&request);
-        self.conn.write_all(&raw_request)?;
-        self.conn.set_read_timeout(Some(Duration::from_secs(self.timeout)))?;
-
-        let mut reader = BufReader::new(&self.conn);
-        let message = read_message(&mut reader)?;
-
-        if !message.error.is_empty() {
-            return Err(format!("client response error: {}", message.error).into());
-        }
-
-        if message.request_id != request_id {
-            return Err(format!("client wrong requestID error: {}", message.error).into());
-        }
-
-        Ok(message.body)
-    }
-}
-
-fn parse_message(body: &[u8]) -> Result<Message, Box<dyn Error>> {
-    let parts: Vec<&[u8]> = body.split(|&b| b == metadata_delim()[0]).collect();
-    if parts.len() != PROTOCOL_FIELDS {
-        return Err(format!("error protocol received message with {} parts, expected {}", parts.len(), PROTOCOL_FIELDS).into());
-    }
-
-    Ok(Message {
-        request_id: String::from_utf8(parts[0].to_vec())?,
-        method_name: String::from_utf8(parts[1].to_vec())?,
-        error: String::from_utf8(parts[2].to_vec())?,
-        body: Bytes::from(parts[3].to_vec()),
-    })
-}
-
-fn message_to_bytes(r: &Message) -> Bytes {
-    let mut buffer = BytesMut::new();
-
-    buffer.put(r.request_id.as_bytes());
-    buffer.put(metadata_delim());
-
-    buffer.put(r.method_name.as_bytes());
-    buffer.put(metadata_delim());
-
-    buffer.put(r.error.as_bytes());
-    buffer.put(metadata_delim());
-
-    buffer.put(&r.body[..]);
-    buffer.put_u8(message_delim());
-
-    buffer.freeze()
-}
-
-fn read_message<R: Read>(reader: &mut R) -> Result<Message, Box<dyn Error>> {
-    let mut message_body = Vec::new();
-    let mut byte = [0u8; 1];
-
-    loop {
-        reader.read_exact(&mut byte)?;
-        if byte[0] == message_delim() {
-            break;
-        }
-        message_body.push(byte[0]);
-    }
-
-    parse_message(&message_body)
-}
-
+mod activation;
+pub mod aio;
+mod breaker;
+mod builder;
+mod cancel;
+mod checksum;
+mod client;
+mod codec;
+mod compression;
+mod context;
+mod credentials;
+mod datagram;
+mod duplex;
+mod error;
+mod events;
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod faults;
+mod fds;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing;
+mod handshake;
+mod info;
+mod interceptor;
+mod logging;
+mod metrics;
+mod limits;
+mod middleware;
+mod multi;
+mod mux;
+mod nonblocking;
+mod pool;
+mod protocol;
+mod pubsub;
+mod reflection;
+mod replay;
+mod retry;
+#[cfg(target_os = "linux")]
+mod seqpacket;
+mod server;
+mod service;
+mod shared;
+mod shutdown;
+#[cfg(feature = "signals")]
+mod signals;
+mod signing;
+mod stats;
+mod status;
+mod stream;
+mod subscription;
+mod trace;
+mod transport;
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+mod vsock;
+pub mod testing;
 #[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn run_client() -> Result<(), Box<dyn Error>> {
-        let mut client = Client::new("/tmp/salt-ssd.sock", 10)?;
-        let method_name = "getnssusers";
-        let request_body = b"";
-
-        let response = client.do_request(method_name, request_body)?;
-        match std::str::from_utf8(&response) {
-            Ok(s) => println!("Received response: {}", s),
-            Err(e) => eprintln!("Response was not valid UTF-8: {}", e),
-        }
-        client.close()?;
-
-        Ok(())
-    }
-
-    #[test]
-    fn it_works() {
-        if let Err(e) = run_client() {
-            eprintln!("Error: {}", e.to_string());
-            std::process::exit(1);
-        }
-    }
-}
+mod test_support;
+
+pub use breaker::CircuitBreaker;
+pub use builder::ClientBuilder;
+pub use cancel::CancelToken;
+pub use client::{Client, PendingRequest};
+pub use codec::Codec;
+pub use compression::Compressor;
+pub use context::RequestContext;
+pub use credentials::PeerCredentials;
+pub use datagram::DatagramClient;
+pub use duplex::{DuplexCall, DuplexSession};
+pub use error::{Error, Result};
+pub use events::ConnectionEvent;
+pub use handshake::Negotiated;
+pub use info::ServerInfo;
+pub use interceptor::{CallOutcome, Interceptor, OutgoingRequest};
+pub use logging::CallLogger;
+pub use metrics::Metrics;
+pub use limits::Overload;
+pub use middleware::{BearerAuth, Middleware, Outcome, PeerAllowlist, RateLimit};
+pub use multi::{Balance, MultiClient};
+pub use mux::MuxClient;
+pub use nonblocking::NonBlockingClient;
+pub use pool::{ClientPool, PooledClient};
+pub use protocol::Framing;
+pub use pubsub::Topics;
+pub use reflection::{MethodInfo, MethodKind};
+pub use replay::{RecordingTransport, ReplayTransport};
+pub use retry::{is_transient, RetryPolicy};
+#[cfg(target_os = "linux")]
+pub use seqpacket::SeqpacketStream;
+pub use server::Server;
+pub use service::Service;
+pub use shared::SharedClient;
+pub use shutdown::ShutdownHandle;
+pub use stats::ClientStats;
+pub use status::{Code, Status};
+pub use stream::{ResponseSink, ResponseStream, UploadReader};
+pub use subscription::{Subscriber, Subscription};
+pub use trace::TraceContext;
+pub use transport::{SocketPermissions, Transport};
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+pub use vsock::{VsockStream, VMADDR_CID_HOST};
@@ -0,0 +1,127 @@
+//! Caps on how many connections and handlers a server takes on at once.
+
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// What a server does with a connection or request beyond its limit; see
+/// [`crate::Server::set_overload`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overload {
+    /// Holds it until another finishes. A connection waits accepted but
+    /// unread, so its client only notices the delay.
+    #[default]
+    Wait,
+    /// Answers it with [`crate::Code::Unavailable`] at once. A connection
+    /// has its first request answered so and is then closed.
+    Reject,
+}
+
+/// A counting semaphore with an optional maximum.
+#[derive(Default)]
+pub(crate) struct Limit {
+    max: Option<usize>,
+    in_use: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Limit {
+    pub(crate) fn set_max(&mut self, max: Option<usize>) {
+        self.max = max.map(|max| max.max(1));
+    }
+
+    /// Takes a slot, waiting for one if `overload` says to; `None` if the
+    /// limit is reached and it says to reject.
+    pub(crate) fn acquire(&self, overload: Overload) -> Option<Permit<'_>> {
+        let mut in_use = lock(&self.in_use);
+        while self.max.is_some_and(|max| *in_use >= max) {
+            if overload == Overload::Reject {
+                return None;
+            }
+            in_use = self.freed.wait(in_use).unwrap_or_else(PoisonError::into_inner);
+        }
+        *in_use += 1;
+        Some(Permit { limit: self })
+    }
+}
+
+/// A slot of a [`Limit`], given back when dropped.
+pub(crate) struct Permit<'a> {
+    limit: &'a Limit,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *lock(&self.limit.in_use) -= 1;
+        self.limit.freed.notify_one();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Code, Error, Server, Status};
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// A server whose `hold` handler reports that it started and then waits
+    /// for a go-ahead.
+    fn spawn_holding(path: &str, configure: impl FnOnce(&mut Server)) -> (mpsc::Receiver<()>, mpsc::SyncSender<()>) {
+        let (started, holding) = mpsc::channel();
+        let (release, released) = mpsc::sync_channel(0);
+        let (started, released) = (Mutex::new(started), Mutex::new(released));
+        let mut server = Server::bind(path).unwrap();
+        server.set_workers(3);
+        configure(&mut server);
+        server.register("hold", move |_| {
+            let _ = lock(&started).send(());
+            let _ = lock(&released).recv();
+            Ok::<_, Status>(b"done".to_vec())
+        });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+        (holding, release)
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_rejected() {
+        let path = socket_path();
+        let (holding, release) = spawn_holding(&path, |server| {
+            server.set_max_concurrent_requests(Some(1));
+            server.set_overload(Overload::Reject);
+        });
+        let mut busy = Client::new(&path, 5).unwrap();
+        let pending = busy.send("hold", b"").unwrap();
+        holding.recv().unwrap();
+
+        let mut client = Client::new(&path, 5).unwrap();
+        assert!(matches!(client.do_request("echo", b"ping"), Err(Error::RemoteError { code: Code::Unavailable, .. })));
+        release.send(()).unwrap();
+        assert_eq!(&pending.wait(&mut busy).unwrap()[..], b"done");
+    }
+
+    #[test]
+    fn connections_over_the_limit_wait_or_are_turned_away() {
+        let path = socket_path();
+        let (_holding, _release) = spawn_holding(&path, |server| {
+            server.set_max_connections(Some(1));
+            server.set_overload(Overload::Reject);
+        });
+        let mut first = Client::new(&path, 5).unwrap();
+        assert_eq!(&first.do_request("echo", b"ping").unwrap()[..], b"ping");
+        let mut second = Client::new(&path, 5).unwrap();
+        assert!(matches!(second.do_request("echo", b"ping"), Err(Error::RemoteError { code: Code::Unavailable, .. })));
+
+        let path = socket_path();
+        let (_holding, _release) = spawn_holding(&path, |server| server.set_max_connections(Some(1)));
+        let mut first = Client::new(&path, 5).unwrap();
+        assert_eq!(&first.do_request("echo", b"ping").unwrap()[..], b"ping");
+        let mut second = Client::new(&path, 5).unwrap();
+        let pending = second.send("echo", b"queued").unwrap();
+        drop(first);
+        assert_eq!(&pending.wait(&mut second).unwrap()[..], b"queued");
+    }
+}
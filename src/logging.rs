@@ -0,0 +1,148 @@
+//! One log line per call, on the client as an [`Interceptor`] and on the
+//! server as [`Middleware`].
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::context::RequestContext;
+use crate::interceptor::{CallOutcome, Interceptor};
+use crate::middleware::{Middleware, Outcome};
+use crate::status::Status;
+
+/// Logs the method, request ID, body sizes, duration and outcome of every
+/// call, once it is answered. Lines go to stderr unless
+/// [`CallLogger::with_sink`] takes them elsewhere, e.g. into a `log` or
+/// `tracing` macro.
+///
+/// ```no_run
+/// # use unixconn_rust::{CallLogger, Client, Server};
+/// let logger = CallLogger::new().log_bodies(64);
+/// let client = Client::builder("/run/agent.sock").interceptor(logger.clone()).connect()?;
+/// let mut server = Server::bind("/run/agent.sock")?;
+/// server.add_middleware(logger);
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+///
+/// Bodies are left out unless [`CallLogger::log_bodies`] is set, as they
+/// may hold secrets. Each side logs the body it received: the client the
+/// response, the server the request.
+#[derive(Clone)]
+pub struct CallLogger {
+    sink: Arc<dyn Fn(&str) + Send + Sync>,
+    max_body: Option<usize>,
+}
+
+impl CallLogger {
+    pub fn new() -> Self {
+        Self::with_sink(|line| eprintln!("{}", line))
+    }
+
+    /// Hands each line to `sink` instead of printing it.
+    pub fn with_sink<F>(sink: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        CallLogger { sink: Arc::new(sink), max_body: None }
+    }
+
+    /// Includes up to `max_len` bytes of each body received, noting how
+    /// much was cut off.
+    pub fn log_bodies(mut self, max_len: usize) -> Self {
+        self.max_body = Some(max_len);
+        self
+    }
+
+    fn log(&self, mut line: String, body: &[u8]) {
+        if let Some(max_len) = self.max_body {
+            let shown = &body[..body.len().min(max_len)];
+            let _ = write!(line, " body={:?}", String::from_utf8_lossy(shown));
+            if shown.len() < body.len() {
+                let _ = write!(line, "+{}", body.len() - shown.len());
+            }
+        }
+        (self.sink)(&line);
+    }
+}
+
+impl Default for CallLogger {
+    fn default() -> Self {
+        CallLogger::new()
+    }
+}
+
+fn line(side: &str, method_name: &str, request_id: &str, sizes: (usize, usize), elapsed: Duration, outcome: &str) -> String {
+    format!(
+        "unixconn {} method={} request_id={} request_len={} response_len={} duration={:?} outcome={}",
+        side, method_name, request_id, sizes.0, sizes.1, elapsed, outcome
+    )
+}
+
+fn describe(status: Option<&Status>) -> String {
+    match status {
+        Some(status) => format!("{:?}", status.to_string()),
+        None => "OK".to_string(),
+    }
+}
+
+impl Interceptor for CallLogger {
+    fn after(&self, outcome: &CallOutcome<'_>) {
+        let (response, result) = match outcome.result() {
+            Ok(body) => (&body[..], "OK".to_string()),
+            Err(e) => (&[][..], format!("{:?}", e.to_string())),
+        };
+        let sizes = (outcome.request_len(), response.len());
+        self.log(line("call", outcome.method_name(), outcome.request_id(), sizes, outcome.elapsed(), &result), response);
+    }
+}
+
+impl Middleware for CallLogger {
+    fn check(&self, _: &RequestContext<'_>) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn after(&self, context: &RequestContext<'_>, outcome: &Outcome<'_>) {
+        let sizes = (context.body().len(), outcome.response_len());
+        let result = describe(outcome.status());
+        self.log(line("served", context.method_name(), context.request_id(), sizes, outcome.elapsed(), &result), context.body());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Client, Code, Server};
+    use std::sync::Mutex;
+
+    #[test]
+    fn both_sides_log_each_call() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let collected = lines.clone();
+        let logger = CallLogger::with_sink(move |line| collected.lock().unwrap().push(line.to_string())).log_bodies(4);
+        let mut server = Server::new();
+        server.add_middleware(logger.clone());
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("lookup", |_| Err(Status::new(Code::NotFound, "no such user")));
+        let (mut client, server_end) = testing::pair_with(Client::builder("logged").interceptor(logger)).unwrap();
+        let serving = std::thread::spawn(move || server.serve(server_end));
+
+        let pending = client.send("echo", b"ping pong").unwrap();
+        let id = pending.request_id().to_string();
+        pending.wait(&mut client).unwrap();
+        assert!(client.do_request("lookup", b"").is_err());
+
+        // The server logs after answering, so either side may log first,
+        // and its last line only lands once it is done.
+        drop(client);
+        serving.join().unwrap().unwrap();
+        let mut lines = lines.lock().unwrap().clone();
+        lines.sort();
+        assert_eq!(lines.len(), 4);
+        let fields = format!("method=echo request_id={} request_len=9 response_len=9", id);
+        assert!(lines[0].starts_with(&format!("unixconn call {} ", fields)));
+        assert!(lines[0].ends_with(r#"outcome=OK body="ping"+5"#));
+        assert!(lines[1].contains(r#"outcome="client response error: NOT_FOUND: no such user" body="""#));
+        assert!(lines[2].starts_with(&format!("unixconn served {} ", fields)));
+        assert!(lines[3].contains(r#"outcome="NOT_FOUND: no such user" body="""#));
+    }
+}
@@ -0,0 +1,183 @@
+//! Per-method call metrics, rendered in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::context::RequestContext;
+use crate::interceptor::{CallOutcome, Interceptor};
+use crate::middleware::{Middleware, Outcome};
+use crate::status::Status;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counts calls by method: how many, how many failed, how long they took
+/// and how many body bytes went each way. Add it to a client with
+/// [`crate::ClientBuilder::interceptor`] or to a server with
+/// [`crate::Server::add_middleware`], and serve
+/// [`Metrics::render_prometheus`] to a scraper.
+///
+/// ```no_run
+/// # use unixconn_rust::{Metrics, Server};
+/// let metrics = Metrics::new("agent_rpc");
+/// let mut server = Server::bind("/run/agent.sock")?;
+/// server.add_middleware(metrics.clone());
+/// // Later, from the HTTP handler behind /metrics:
+/// let exposition = metrics.render_prometheus();
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+///
+/// The in-flight gauge is kept on servers only, as clients do not report
+/// every kind of call back. Clones share their counts.
+#[derive(Clone)]
+pub struct Metrics {
+    namespace: Arc<str>,
+    methods: Arc<Mutex<BTreeMap<String, Series>>>,
+    in_flight: Arc<AtomicI64>,
+}
+
+#[derive(Default)]
+struct Series {
+    requests: u64,
+    errors: u64,
+    sent: u64,
+    received: u64,
+    /// Calls per bucket of [`BUCKETS`], not yet cumulative; the last counts
+    /// those slower than all bounds.
+    buckets: [u64; BUCKETS.len() + 1],
+    seconds: f64,
+}
+
+impl Metrics {
+    /// Metrics named `<namespace>_requests_total` and so on.
+    pub fn new(namespace: &str) -> Self {
+        Metrics { namespace: namespace.into(), methods: Arc::default(), in_flight: Arc::default() }
+    }
+
+    fn record(&self, method_name: &str, failed: bool, elapsed: Duration, sent: usize, received: usize) {
+        let mut methods = self.methods.lock().unwrap_or_else(PoisonError::into_inner);
+        let series = match methods.get_mut(method_name) {
+            Some(series) => series,
+            None => methods.entry(method_name.to_string()).or_default(),
+        };
+        series.requests += 1;
+        series.errors += u64::from(failed);
+        series.sent += sent as u64;
+        series.received += received as u64;
+        let seconds = elapsed.as_secs_f64();
+        series.buckets[BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(BUCKETS.len())] += 1;
+        series.seconds += seconds;
+    }
+
+    /// All metrics in the Prometheus text exposition format, version 0.0.4.
+    pub fn render_prometheus(&self) -> String {
+        let ns = &self.namespace;
+        let methods = self.methods.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: fn(&Series) -> u64| {
+            let _ = writeln!(out, "# HELP {}_{} {}\n# TYPE {}_{} counter", ns, name, help, ns, name);
+            for (method, series) in methods.iter() {
+                let _ = writeln!(out, "{}_{}{{method=\"{}\"}} {}", ns, name, escape(method), value(series));
+            }
+        };
+        counter("requests_total", "Calls answered.", |series| series.requests);
+        counter("errors_total", "Calls answered with an error.", |series| series.errors);
+        counter("sent_bytes_total", "Body bytes sent.", |series| series.sent);
+        counter("received_bytes_total", "Body bytes received.", |series| series.received);
+
+        let _ = writeln!(out, "# HELP {}_request_duration_seconds How long calls took.", ns);
+        let _ = writeln!(out, "# TYPE {}_request_duration_seconds histogram", ns);
+        for (method, series) in methods.iter() {
+            let method = escape(method);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().map(f64::to_string).chain(["+Inf".to_string()]).zip(series.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "{}_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}", ns, method, bound, cumulative);
+            }
+            let _ = writeln!(out, "{}_request_duration_seconds_sum{{method=\"{}\"}} {}", ns, method, series.seconds);
+            let _ = writeln!(out, "{}_request_duration_seconds_count{{method=\"{}\"}} {}", ns, method, series.requests);
+        }
+
+        let _ = writeln!(out, "# HELP {}_in_flight_requests Requests being handled.", ns);
+        let _ = writeln!(out, "# TYPE {}_in_flight_requests gauge", ns);
+        let _ = writeln!(out, "{}_in_flight_requests {}", ns, self.in_flight.load(Ordering::Relaxed));
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n")
+}
+
+impl Interceptor for Metrics {
+    fn after(&self, outcome: &CallOutcome<'_>) {
+        let received = outcome.result().as_ref().map_or(0, |body| body.len());
+        self.record(outcome.method_name(), outcome.result().is_err(), outcome.elapsed(), outcome.request_len(), received);
+    }
+}
+
+impl Middleware for Metrics {
+    fn check(&self, _: &RequestContext<'_>) -> Result<(), Status> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn after(&self, context: &RequestContext<'_>, outcome: &Outcome<'_>) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.record(context.method_name(), outcome.status().is_some(), outcome.elapsed(), outcome.response_len(), context.body().len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Client, Server};
+
+    #[test]
+    fn calls_are_counted_on_both_sides() {
+        let (client_metrics, server_metrics) = (Metrics::new("client"), Metrics::new("server"));
+        let mut server = Server::new();
+        server.add_middleware(server_metrics.clone());
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("fail", |_| Err("boom".to_string()));
+        server.register_with_context("gauge", {
+            let metrics = server_metrics.clone();
+            move |_, _| Ok(metrics.render_prometheus().contains("server_in_flight_requests 1").to_string().into_bytes())
+        });
+        let (mut client, server_end) = testing::pair_with(Client::builder("measured").interceptor(client_metrics.clone())).unwrap();
+        std::thread::spawn(move || server.serve(server_end));
+
+        client.do_request("echo", b"ping").unwrap();
+        client.do_request("echo", b"pong").unwrap();
+        assert!(client.do_request("fail", b"").is_err());
+        assert_eq!(&client.do_request("gauge", b"").unwrap()[..], b"true");
+
+        let rendered = client_metrics.render_prometheus();
+        for line in [
+            "# TYPE client_requests_total counter",
+            "client_requests_total{method=\"echo\"} 2",
+            "client_errors_total{method=\"echo\"} 0",
+            "client_errors_total{method=\"fail\"} 1",
+            "client_sent_bytes_total{method=\"echo\"} 8",
+            "client_received_bytes_total{method=\"echo\"} 8",
+            "client_request_duration_seconds_bucket{method=\"echo\",le=\"+Inf\"} 2",
+            "client_request_duration_seconds_count{method=\"fail\"} 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{} missing from\n{}", line, rendered);
+        }
+        assert!(server_metrics.render_prometheus().contains("server_received_bytes_total{method=\"echo\"} 8\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::new("rpc");
+        metrics.record("odd\"name\\", false, Duration::from_millis(3), 0, 0);
+        let rendered = metrics.render_prometheus();
+        assert!(rendered.contains(r#"rpc_requests_total{method="odd\"name\\"} 1"#));
+        assert!(rendered.contains(r#"rpc_request_duration_seconds_bucket{method="odd\"name\\",le="0.0025"} 0"#));
+        assert!(rendered.contains(r#"rpc_request_duration_seconds_bucket{method="odd\"name\\",le="0.005"} 1"#));
+    }
+}
@@ -0,0 +1,398 @@
+//! Layers the server runs around the handler of each request.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::context::RequestContext;
+use crate::status::{Code, Status};
+
+pub(crate) const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// Runs around the handler of every request, whatever its kind; see
+/// [`crate::Server::add_middleware`].
+///
+/// Middleware forms a stack: checks run in the order it was added, and the
+/// first to refuse a request answers it with the error, as if its handler
+/// had returned it, without running the checks after it. Once the request
+/// is answered, [`Middleware::after`] runs in the reverse order on each
+/// middleware whose check let it through, so each one wraps all below it.
+pub trait Middleware: Send + Sync {
+    fn check(&self, context: &RequestContext<'_>) -> Result<(), Status>;
+
+    /// Runs once the request has been answered, by its handler or by a
+    /// refusal further down the stack.
+    fn after(&self, context: &RequestContext<'_>, outcome: &Outcome<'_>) {
+        let _ = (context, outcome);
+    }
+}
+
+/// How a request was answered, as reported to [`Middleware::after`].
+pub struct Outcome<'a> {
+    pub(crate) status: Option<&'a Status>,
+    pub(crate) response_len: usize,
+    pub(crate) elapsed: Duration,
+}
+
+impl Outcome<'_> {
+    /// The error the request was answered with, if it failed.
+    pub fn status(&self) -> Option<&Status> {
+        self.status
+    }
+
+    /// The size of the response body of a unary or upload call, before any
+    /// compression; zero for the other kinds.
+    pub fn response_len(&self) -> usize {
+        self.response_len
+    }
+
+    /// From the first check to the answer being sent.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&RequestContext<'_>) -> Result<(), Status> + Send + Sync,
+{
+    fn check(&self, context: &RequestContext<'_>) -> Result<(), Status> {
+        self(context)
+    }
+}
+
+/// Lets a request through only if the process that opened its connection
+/// runs as one of the allowed users or in one of the allowed groups, going
+/// by [`RequestContext::peer`]. Others get [`Code::PermissionDenied`], as do
+/// all requests where the platform does not report peer credentials.
+///
+/// Every method is guarded unless [`PeerAllowlist::only_methods`] narrows
+/// it down.
+#[derive(Debug, Clone, Default)]
+pub struct PeerAllowlist {
+    uids: HashSet<u32>,
+    gids: HashSet<u32>,
+    methods: Option<HashSet<String>>,
+}
+
+impl PeerAllowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.uids.insert(uid);
+        self
+    }
+
+    /// Allows peers whose primary group is `gid`; supplementary groups are
+    /// not reported by the kernel.
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.gids.insert(gid);
+        self
+    }
+
+    /// Guards only `methods`, letting requests for any other through.
+    pub fn only_methods<I, S>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.methods.get_or_insert_with(HashSet::new).extend(methods.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl Middleware for PeerAllowlist {
+    fn check(&self, context: &RequestContext<'_>) -> Result<(), Status> {
+        if self.methods.as_ref().is_some_and(|methods| !methods.contains(context.method_name())) {
+            return Ok(());
+        }
+        let peer = context
+            .peer()
+            .ok_or_else(|| Status::new(Code::PermissionDenied, "peer credentials are unavailable"))?;
+        if self.uids.contains(&peer.uid()) || self.gids.contains(&peer.gid()) {
+            return Ok(());
+        }
+        let method = context.method_name();
+        Err(Status::new(Code::PermissionDenied, format!("uid {} gid {} may not call {}", peer.uid(), peer.gid(), method)))
+    }
+}
+
+/// Lets a request through only if it carries a bearer token, as sent by
+/// [`crate::Client::set_bearer_token`], that `validate` accepts. Requests
+/// without one get [`Code::Unauthenticated`]; `validate` chooses the error
+/// for the others.
+///
+/// ```no_run
+/// # use unixconn_rust::{BearerAuth, Code, Server, Status};
+/// let mut server = Server::bind("/run/shared/agent.sock")?;
+/// server.add_middleware(BearerAuth::new(|token: &str| match token {
+///     "s3cret" => Ok(()),
+///     _ => Err(Status::new(Code::Unauthenticated, "unknown token")),
+/// }));
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+pub struct BearerAuth<F> {
+    validate: F,
+}
+
+impl<F> BearerAuth<F>
+where
+    F: Fn(&str) -> Result<(), Status> + Send + Sync,
+{
+    pub fn new(validate: F) -> Self {
+        BearerAuth { validate }
+    }
+}
+
+impl<F> Middleware for BearerAuth<F>
+where
+    F: Fn(&str) -> Result<(), Status> + Send + Sync,
+{
+    fn check(&self, context: &RequestContext<'_>) -> Result<(), Status> {
+        match context.header(AUTHORIZATION_HEADER).and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(token) => (self.validate)(token),
+            None => Err(Status::new(Code::Unauthenticated, "request carries no bearer token")),
+        }
+    }
+}
+
+/// Refuses requests arriving faster than allowed with
+/// [`Code::ResourceExhausted`], whose message says how long to wait.
+///
+/// Each limit is a token bucket: up to `burst` requests pass at once, after
+/// which they pass at `per_second` on average. A request must pass both the
+/// limit of its method, if it has one, and the global limit, if set; the
+/// limits are shared by all connections.
+///
+/// ```no_run
+/// # use unixconn_rust::{RateLimit, Server};
+/// let mut server = Server::bind("/run/nss.sock")?;
+/// server.add_middleware(RateLimit::new().global(1000.0, 100).method("getnssusers", 2.0, 5));
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    global: Option<Mutex<Bucket>>,
+    methods: HashMap<String, Mutex<Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits all requests together.
+    pub fn global(mut self, per_second: f64, burst: u32) -> Self {
+        self.global = Some(Mutex::new(Bucket::new(per_second, burst)));
+        self
+    }
+
+    /// Limits requests for `method`, replacing any limit set for it before.
+    pub fn method(mut self, method: &str, per_second: f64, burst: u32) -> Self {
+        self.methods.insert(method.to_string(), Mutex::new(Bucket::new(per_second, burst)));
+        self
+    }
+}
+
+impl Middleware for RateLimit {
+    fn check(&self, context: &RequestContext<'_>) -> Result<(), Status> {
+        let now = Instant::now();
+        // Locked in this order only, and a token taken from neither unless
+        // both have one, so a refused request costs the other limit nothing.
+        let mut method = self.methods.get(context.method_name()).map(|bucket| bucket.lock().unwrap_or_else(PoisonError::into_inner));
+        let mut global = self.global.as_ref().map(|bucket| bucket.lock().unwrap_or_else(PoisonError::into_inner));
+        let wait = [method.as_deref_mut(), global.as_deref_mut()].into_iter().flatten().map(|bucket| bucket.wait(now)).max();
+        match wait {
+            Some(wait) if !wait.is_zero() => {
+                let message = format!("rate limit for {} exceeded, retry in {}ms", context.method_name(), wait.as_millis().max(1));
+                Err(Status::new(Code::ResourceExhausted, message))
+            }
+            _ => {
+                for bucket in [method.as_deref_mut(), global.as_deref_mut()].into_iter().flatten() {
+                    bucket.tokens -= 1.0;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    burst: f64,
+    per_second: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Bucket { tokens: burst, burst, per_second, refilled: Instant::now() }
+    }
+
+    /// Refills the bucket up to `now`, then reports how long until it holds
+    /// a whole token; zero if it does already.
+    fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled = now;
+        match self.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false if self.per_second > 0.0 => Duration::from_secs_f64((1.0 - self.tokens) / self.per_second),
+            false => Duration::MAX,
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{testing, Client, Error, Server};
+
+    #[test]
+    fn peers_outside_the_allowlist_are_refused() {
+        // SAFETY: these calls cannot fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut server = Server::new();
+        server.add_middleware(PeerAllowlist::new().allow_uid(uid.wrapping_add(1)).only_methods(["shutdown"]));
+        server.add_middleware(PeerAllowlist::new().allow_gid(gid));
+        server.register("shutdown", |_| Ok::<_, Status>(b"bye".to_vec()));
+        server.register("status", |_| Ok::<_, Status>(b"up".to_vec()));
+        server.register_stream("logs", |_, sink| sink.send(b"line"));
+        let mut client = testing::connect(server).unwrap();
+
+        assert_eq!(&client.do_request("status", b"").unwrap()[..], b"up");
+        assert!(matches!(
+            client.do_request("shutdown", b""),
+            Err(Error::RemoteError { code: Code::PermissionDenied, .. })
+        ));
+        assert_eq!(client.do_request_stream("logs", b"").unwrap().count(), 1);
+    }
+
+    #[test]
+    fn closures_refuse_requests_of_every_kind() {
+        let mut server = Server::new();
+        server.add_middleware(|context: &RequestContext<'_>| match context.header("token") {
+            Some("open sesame") => Ok(()),
+            _ => Err(Status::new(Code::Unauthenticated, "no token")),
+        });
+        server.register_stream("logs", |_, sink| sink.send(b"line"));
+        server.register_upload("store", |_| Ok(Vec::new()));
+        server.register("status", |_| Ok::<_, Status>(b"up".to_vec()));
+        let mut client = testing::connect(server).unwrap();
+
+        let frames: Vec<_> = client.do_request_stream("logs", b"").unwrap().collect();
+        assert!(matches!(frames[..], [Err(Error::RemoteError { code: Code::Unauthenticated, .. })]));
+        assert!(client.do_request_streamed("store", &[1u8; 200_000][..]).is_err());
+        let answer = client.do_request_with_headers("status", b"", &[("token", "open sesame")]);
+        assert_eq!(&answer.unwrap()[..], b"up");
+    }
+
+    #[test]
+    fn bearer_tokens_are_checked_before_dispatch() {
+        let mut server = Server::new();
+        server.add_middleware(BearerAuth::new(|token: &str| match token {
+            "s3cret" => Ok(()),
+            _ => Err(Status::new(Code::PermissionDenied, "unknown token")),
+        }));
+        server.register("status", |_| Ok::<_, Status>(b"up".to_vec()));
+        let (mut client, server_end) = testing::pair_with(Client::builder("agent").bearer_token("s3cret")).unwrap();
+        std::thread::spawn(move || server.serve(server_end));
+
+        assert_eq!(&client.do_request("status", b"").unwrap()[..], b"up");
+        client.set_bearer_token(Some("guess"));
+        assert!(matches!(client.do_request("status", b""), Err(Error::RemoteError { code: Code::PermissionDenied, .. })));
+        client.set_bearer_token(None);
+        assert!(matches!(client.do_request("status", b""), Err(Error::RemoteError { code: Code::Unauthenticated, .. })));
+    }
+
+    /// Logs its check and its post hook, refusing the methods in `refuse`.
+    struct Layer {
+        name: &'static str,
+        refuse: &'static [&'static str],
+        log: std::sync::Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Layer {
+        fn check(&self, context: &RequestContext<'_>) -> Result<(), Status> {
+            self.log.lock().unwrap().push(format!("{} check", self.name));
+            match self.refuse.contains(&context.method_name()) {
+                true => Err(Status::new(Code::PermissionDenied, self.name)),
+                false => Ok(()),
+            }
+        }
+
+        fn after(&self, _: &RequestContext<'_>, outcome: &Outcome<'_>) {
+            let status = outcome.status().map(|status| status.code().as_str()).unwrap_or("OK");
+            self.log.lock().unwrap().push(format!("{} after {} {}", self.name, status, outcome.response_len()));
+        }
+    }
+
+    #[test]
+    fn middleware_wraps_handlers_in_order() {
+        let log = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mut server = Server::new();
+        server.add_middleware(Layer { name: "outer", refuse: &[], log: log.clone() });
+        server.add_middleware(Layer { name: "inner", refuse: &["admin"], log: log.clone() });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("admin", |_| Ok::<_, Status>(Vec::new()));
+        server.register_stream("logs", |_, _| Err(Status::new(Code::Internal, "disk gone")));
+        let (mut client, server_end) = testing::pair().unwrap();
+        let serving = std::thread::spawn(move || server.serve(server_end));
+
+        assert_eq!(&client.do_request("echo", b"ping").unwrap()[..], b"ping");
+        assert!(client.do_request("admin", b"").is_err());
+        assert_eq!(client.do_request_stream("logs", b"").unwrap().count(), 1);
+        // Post hooks run after the answer is sent, so wait for the server
+        // to finish before looking.
+        drop(client);
+        serving.join().unwrap().unwrap();
+        let expected = [
+            "outer check",
+            "inner check",
+            "inner after OK 4",
+            "outer after OK 4",
+            "outer check",
+            "inner check",
+            "outer after PERMISSION_DENIED 0",
+            "outer check",
+            "inner check",
+            "inner after INTERNAL 0",
+            "outer after INTERNAL 0",
+        ];
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn requests_over_the_rate_are_refused() {
+        let mut server = Server::new();
+        server.add_middleware(RateLimit::new().global(1000.0, 100).method("getnssusers", 0.01, 2));
+        server.register("getnssusers", |_| Ok::<_, Status>(b"root".to_vec()));
+        server.register("status", |_| Ok::<_, Status>(b"up".to_vec()));
+        let mut client = testing::connect(server).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(&client.do_request("getnssusers", b"").unwrap()[..], b"root");
+        }
+        match client.do_request("getnssusers", b"") {
+            Err(Error::RemoteError { code: Code::ResourceExhausted, message }) => assert!(message.contains("retry in")),
+            other => panic!("expected the rate limit, got {:?}", other),
+        }
+        assert_eq!(&client.do_request("status", b"").unwrap()[..], b"up");
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let mut bucket = Bucket::new(10.0, 1);
+        let start = bucket.refilled;
+        assert_eq!(bucket.wait(start), Duration::ZERO);
+        bucket.tokens -= 1.0;
+        assert_eq!(bucket.wait(start + Duration::from_millis(50)), Duration::from_millis(50));
+        assert_eq!(bucket.wait(start + Duration::from_millis(100)), Duration::ZERO);
+        assert_eq!(bucket.wait(start + Duration::from_secs(10)), Duration::ZERO);
+        assert_eq!(bucket.tokens, 1.0);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::builder::ClientBuilder;
+use crate::error::{Error, Result};
+use crate::Client;
+
+/// How a [`MultiClient`] picks the backend for each request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Each backend in turn.
+    #[default]
+    RoundRobin,
+    /// A backend chosen at random.
+    Random,
+    /// The first backend that is up, in the order given, for a primary
+    /// daemon with standbys. Requests go back to an earlier backend once
+    /// its failure has been skipped over for
+    /// [`MultiClient::skip_failing_for`].
+    Failover,
+}
+
+/// A client for several servers offering the same methods, such as shards
+/// of one daemon, that spreads requests across them.
+///
+/// Each request goes to one backend, picked as [`MultiClient::balance`]
+/// says. After a call to a backend fails without an answer, that backend is
+/// passed over for [`MultiClient::skip_failing_for`] while others are up. A
+/// request is tried on the next backend only when its socket could not be
+/// reached, so it is never sent twice. Backends connect when first used
+/// and reconnect after errors.
+///
+/// Made with [`MultiClient::discover`], the backends are the sockets in a
+/// directory, rescanned as daemons come and go.
+pub struct MultiClient {
+    builder: ClientBuilder,
+    backends: RwLock<Vec<Arc<Backend>>>,
+    balance: Balance,
+    skip_failing_for: Duration,
+    next: AtomicUsize,
+    directory: Option<Directory>,
+}
+
+struct Backend {
+    address: String,
+    client: Mutex<Client>,
+    failing_until: Mutex<Option<Instant>>,
+}
+
+/// Where [`MultiClient::discover`] looks for backends.
+struct Directory {
+    path: PathBuf,
+    refresh_every: Duration,
+    scanned: Mutex<Instant>,
+}
+
+impl MultiClient {
+    pub fn new(addresses: &[&str], timeout: u64) -> Self {
+        Self::from_builder(Client::builder("").timeout(Some(Duration::from_secs(timeout))), addresses)
+    }
+
+    /// Connects to each of `addresses` with the other settings of
+    /// `builder`; its own address is unused.
+    pub fn from_builder(builder: ClientBuilder, addresses: &[&str]) -> Self {
+        let backends = addresses.iter().map(|address| Arc::new(Backend::new(&builder, address))).collect();
+        MultiClient {
+            builder,
+            backends: RwLock::new(backends),
+            balance: Balance::default(),
+            skip_failing_for: Duration::from_secs(1),
+            next: AtomicUsize::new(0),
+            directory: None,
+        }
+    }
+
+    /// Uses every socket in `directory` whose name ends in `.sock` as a
+    /// backend, e.g. `/run/myapp/sockets/`. The directory is scanned again
+    /// before a request once [`MultiClient::refresh_every`] has passed;
+    /// sockets that appeared are added and those removed dropped. A scan
+    /// that fails keeps the backends found before.
+    pub fn discover(builder: ClientBuilder, directory: impl AsRef<Path>) -> Result<Self> {
+        let mut client = Self::from_builder(builder, &[]);
+        let path = directory.as_ref().to_path_buf();
+        *client.backends.get_mut().unwrap() = client.scan(&path)?;
+        client.directory = Some(Directory { path, refresh_every: Duration::from_secs(1), scanned: Mutex::new(Instant::now()) });
+        Ok(client)
+    }
+
+    /// How often a discovered directory is scanned; once a second by
+    /// default.
+    pub fn refresh_every(mut self, interval: Duration) -> Self {
+        if let Some(directory) = &mut self.directory {
+            directory.refresh_every = interval;
+        }
+        self
+    }
+
+    /// The socket paths currently used.
+    pub fn addresses(&self) -> Vec<String> {
+        self.refresh();
+        self.backends().iter().map(|backend| backend.address.clone()).collect()
+    }
+
+    pub fn balance(mut self, balance: Balance) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// How long a failing backend is left out; one second by default.
+    pub fn skip_failing_for(mut self, duration: Duration) -> Self {
+        self.skip_failing_for = duration;
+        self
+    }
+
+    pub fn do_request(&self, method_name: &str, request_body: &[u8]) -> Result<Bytes> {
+        self.call(|client| client.do_request(method_name, request_body))
+    }
+
+    pub fn do_request_with_timeout(&self, method_name: &str, request_body: &[u8], timeout: Duration) -> Result<Bytes> {
+        self.call(|client| client.do_request_with_timeout(method_name, request_body, timeout))
+    }
+
+    pub fn notify(&self, method_name: &str, request_body: &[u8]) -> Result<()> {
+        self.call(|client| client.notify(method_name, request_body))
+    }
+
+    /// Runs `call` on the chosen backend, moving on to the next one while
+    /// backends cannot be reached.
+    fn call<T>(&self, mut call: impl FnMut(&mut Client) -> Result<T>) -> Result<T> {
+        self.refresh();
+        let mut unreachable = None;
+        for backend in self.candidates() {
+            let result = call(&mut backend.lock());
+            backend.record(&result, self.skip_failing_for);
+            match result {
+                Err(e) if !was_sent(&e) => unreachable = Some(e),
+                result => return result,
+            }
+        }
+        Err(unreachable.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "multi client has no backends").into()))
+    }
+
+    /// Every backend, starting from the one the balance picks, with those
+    /// failing at the end.
+    fn candidates(&self) -> impl Iterator<Item = Arc<Backend>> {
+        let backends = self.backends();
+        let start = match self.balance {
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Balance::Random => RandomState::new().build_hasher().finish() as usize,
+            Balance::Failover => 0,
+        };
+        let ordered = (0..backends.len()).map(|i| backends[(start + i) % backends.len()].clone());
+        let (up, failing): (Vec<_>, Vec<_>) = ordered.partition(|backend| !backend.is_failing());
+        up.into_iter().chain(failing)
+    }
+
+    fn backends(&self) -> Vec<Arc<Backend>> {
+        self.backends.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Rescans the discovered directory if it is due.
+    fn refresh(&self) {
+        let Some(directory) = &self.directory else { return };
+        {
+            let mut scanned = directory.scanned.lock().unwrap_or_else(PoisonError::into_inner);
+            if scanned.elapsed() < directory.refresh_every {
+                return;
+            }
+            *scanned = Instant::now();
+        }
+        if let Ok(backends) = self.scan(&directory.path) {
+            *self.backends.write().unwrap_or_else(PoisonError::into_inner) = backends;
+        }
+    }
+
+    /// The sockets in `directory` by name, keeping the backends already
+    /// connected to them.
+    fn scan(&self, directory: &Path) -> Result<Vec<Arc<Backend>>> {
+        let mut addresses = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "sock") && entry.file_type()?.is_socket() {
+                addresses.push(path.to_string_lossy().into_owned());
+            }
+        }
+        addresses.sort_unstable();
+
+        let known = self.backends();
+        Ok(addresses
+            .iter()
+            .map(|address| match known.iter().find(|backend| &backend.address == address) {
+                Some(backend) => backend.clone(),
+                None => Arc::new(Backend::new(&self.builder, address)),
+            })
+            .collect())
+    }
+}
+
+impl Backend {
+    fn new(builder: &ClientBuilder, address: &str) -> Self {
+        let builder = ClientBuilder { address: address.to_string(), ..builder.clone() }.auto_reconnect(true);
+        Backend { address: address.to_string(), client: Mutex::new(builder.connect_lazy()), failing_until: Mutex::new(None) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Client> {
+        self.client.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_failing(&self) -> bool {
+        let failing_until = self.failing_until.lock().unwrap_or_else(PoisonError::into_inner);
+        failing_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record<T>(&self, result: &Result<T>, skip_for: Duration) {
+        let mut failing_until = self.failing_until.lock().unwrap_or_else(PoisonError::into_inner);
+        *failing_until = match result {
+            Err(e) if e.breaks_connection() => Some(Instant::now() + skip_for),
+            _ => None,
+        };
+    }
+}
+
+/// Whether a failed call may have reached the server, which it cannot
+/// have if dialling its socket failed.
+fn was_sent(error: &Error) -> bool {
+    !matches!(error, Error::Io(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Server, Status};
+    use std::thread;
+
+    fn named_server(name: &'static str) -> String {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("whoami", move |_| Ok::<_, Status>(name.as_bytes().to_vec()));
+        thread::spawn(move || server.run());
+        path
+    }
+
+    #[test]
+    fn requests_take_turns_and_skip_unreachable_backends() {
+        let (a, b) = (named_server("a"), named_server("b"));
+        let missing = socket_path();
+        let client = MultiClient::new(&[&a, &missing, &b], 5);
+
+        let served: Vec<_> = (0..4).map(|_| client.do_request("whoami", b"").unwrap()).collect();
+        assert_eq!(served, ["a", "b", "b", "a"].map(|name| Bytes::from(name.as_bytes())));
+        assert!(client.backends()[1].is_failing());
+
+        std::fs::remove_file(&a).unwrap();
+        std::fs::remove_file(&b).unwrap();
+    }
+
+    #[test]
+    fn failover_prefers_the_first_backend_that_is_up() {
+        let primary = socket_path();
+        let standby = named_server("standby");
+        let client = MultiClient::new(&[&primary, &standby], 5).balance(Balance::Failover).skip_failing_for(Duration::ZERO);
+        assert_eq!(&client.do_request("whoami", b"").unwrap()[..], b"standby");
+
+        let mut server = Server::bind(&primary).unwrap();
+        server.register("whoami", |_| Ok::<_, Status>(b"primary".to_vec()));
+        thread::spawn(move || server.run());
+        for _ in 0..2 {
+            assert_eq!(&client.do_request("whoami", b"").unwrap()[..], b"primary");
+        }
+
+        std::fs::remove_file(&primary).unwrap();
+        std::fs::remove_file(&standby).unwrap();
+    }
+
+    #[test]
+    fn discovered_backends_follow_the_directory() {
+        let directory = std::env::temp_dir().join(format!("unixconn-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&directory).unwrap();
+        fs::write(directory.join("notes.txt"), b"not a socket").unwrap();
+        let serve = |name: &'static str| {
+            let mut server = Server::bind(directory.join(format!("{}.sock", name)).to_str().unwrap()).unwrap();
+            server.register("whoami", move |_| Ok::<_, Status>(name.as_bytes().to_vec()));
+            thread::spawn(move || server.run());
+        };
+        serve("a");
+
+        let builder = Client::builder("").timeout(Some(Duration::from_secs(5)));
+        let client = MultiClient::discover(builder, &directory).unwrap().refresh_every(Duration::ZERO);
+        assert_eq!(&client.do_request("whoami", b"").unwrap()[..], b"a");
+
+        serve("b");
+        fs::remove_file(directory.join("a.sock")).unwrap();
+        let b = directory.join("b.sock").to_string_lossy().into_owned();
+        assert_eq!(client.addresses(), [b]);
+        assert_eq!(&client.do_request("whoami", b"").unwrap()[..], b"b");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn all_backends_down_reports_the_last_error() {
+        let client = MultiClient::new(&[&socket_path(), &socket_path()], 5).balance(Balance::Random);
+        assert!(matches!(client.do_request("whoami", b""), Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound));
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::builder::{ClientBuilder, Keepalive};
+use crate::compression::{self, Compression};
+use crate::error::{Error, Result};
+use crate::interceptor::{CallOutcome, Interceptors};
+use crate::middleware::AUTHORIZATION_HEADER;
+use crate::protocol::{check_header, Framing, Message, PING};
+use crate::signing::Integrity;
+use crate::transport::{connect_unix, write_all_vectored};
+
+/// A client that keeps many requests in flight on one connection.
+///
+/// A background thread reads responses and hands each to the caller waiting
+/// for its request_id, so responses may arrive in any order and a slow call
+/// does not hold up the others. Clones share the connection, which is shut
+/// down once the last clone is dropped.
+///
+/// Frames are checksummed, signed, authorized and compressed as the builder
+/// says, and requests pass through its interceptors; see
+/// [`ClientBuilder::connect_multiplexed`] for the options it leaves out.
+#[derive(Clone)]
+pub struct MuxClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    writer: Mutex<UnixStream>,
+    framing: Framing,
+    timeout: Option<Duration>,
+    integrity: Integrity,
+    bearer_token: Option<String>,
+    /// The builder's first codec, there being no handshake to pick another.
+    compression: Option<Compression>,
+    max_message_size: Option<usize>,
+    interceptors: Interceptors,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    waiting: Mutex<Waiting>,
+}
+
+#[derive(Default)]
+struct Waiting {
+    responses: HashMap<String, mpsc::Sender<Message>>,
+    /// Why the reader stopped; set once the connection is unusable.
+    closed: Option<String>,
+    /// When the last frame arrived, or the connection was made.
+    last_read: Option<Instant>,
+}
+
+impl MuxClient {
+    pub(crate) fn from_builder(builder: &ClientBuilder) -> Result<Self> {
+        let writer = connect_unix(&builder.address)?;
+        writer.set_write_timeout(builder.write_timeout)?;
+        let reader = BufReader::new(writer.try_clone()?);
+
+        let waiting = Waiting { last_read: Some(Instant::now()), ..Waiting::default() };
+        let shared = Arc::new(Shared { waiting: Mutex::new(waiting) });
+        let (framing, max_len) = (builder.framing, builder.max_message_size);
+        let integrity = Integrity { checksums: builder.checksums, key: builder.signing_key.clone() };
+        let reader_shared = Arc::clone(&shared);
+        let reader_integrity = integrity.clone();
+        thread::Builder::new()
+            .name("unixconn-mux-reader".to_string())
+            .spawn(move || reader_shared.read_responses(reader, framing, max_len, &reader_integrity))?;
+
+        let client = MuxClient {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(writer),
+                framing,
+                timeout: builder.timeout,
+                integrity,
+                bearer_token: builder.bearer_token.clone(),
+                compression: builder.compression.first().cloned(),
+                max_message_size: max_len,
+                interceptors: builder.interceptors.clone(),
+                shared,
+            }),
+        };
+        if let Some(keepalive) = builder.keepalive {
+            let inner = Arc::downgrade(&client.inner);
+            thread::Builder::new()
+                .name("unixconn-mux-heartbeat".to_string())
+                .spawn(move || MuxClient::heartbeat(inner, keepalive))?;
+        }
+        Ok(client)
+    }
+
+    pub fn do_request(&self, method_name: &str, request_body: &[u8]) -> Result<Bytes> {
+        self.call(method_name, request_body, self.inner.timeout)
+    }
+
+    pub fn do_request_with_timeout(&self, method_name: &str, request_body: &[u8], timeout: Duration) -> Result<Bytes> {
+        self.call(method_name, request_body, Some(timeout))
+    }
+
+    /// Sends a message the server will not answer; see [`crate::Client::notify`].
+    pub fn notify(&self, method_name: &str, request_body: &[u8]) -> Result<()> {
+        let notification = self.prepare(Message::notification(method_name, request_body))?;
+        self.write(&notification)
+    }
+
+    /// Sends a prepared request and reports its outcome to the interceptors.
+    fn call(&self, method_name: &str, request_body: &[u8], timeout: Option<Duration>) -> Result<Bytes> {
+        let sent = Instant::now();
+        let request = self.prepare(Message::request(method_name, request_body))?;
+        let result = self.do_request_inner(request.clone(), timeout);
+        self.inner.interceptors.after(&CallOutcome {
+            method_name: &request.method_name,
+            request_id: &request.request_id,
+            request_len: request_body.len(),
+            elapsed: sent.elapsed(),
+            result: &result,
+        });
+        result
+    }
+
+    /// Adds the bearer token, if any, hands the message to the
+    /// interceptors, then compresses what they leave of its body; as
+    /// [`crate::Client`] does.
+    fn prepare(&self, mut message: Message) -> Result<Message> {
+        if let Some(token) = &self.inner.bearer_token {
+            let value = format!("Bearer {}", token);
+            check_header(AUTHORIZATION_HEADER, &value)?;
+            message.headers.push((AUTHORIZATION_HEADER.to_string(), value));
+        }
+        self.inner.interceptors.before(&mut message)?;
+        if let Some(compression) = &self.inner.compression {
+            compression.encode_request(&mut message)?;
+        }
+        Ok(message)
+    }
+
+    fn write(&self, message: &Message) -> Result<()> {
+        let frame = self.inner.framing.encode_frame(&self.inner.integrity.seal(message));
+        Ok(write_all_vectored(&mut *self.inner.writer.lock().unwrap(), &frame.parts())?)
+    }
+
+    fn do_request_inner(&self, request: Message, timeout: Option<Duration>) -> Result<Bytes> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut waiting = self.inner.shared.waiting.lock().unwrap();
+            if let Some(reason) = &waiting.closed {
+                return Err(closed_error(reason));
+            }
+            waiting.responses.insert(request.request_id.clone(), tx);
+        }
+
+        if let Err(e) = self.write(&request) {
+            self.forget(&request.request_id);
+            return Err(e);
+        }
+
+        let received = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(mut message) => {
+                compression::decode(&mut message, self.inner.compression.as_slice(), self.inner.max_message_size)?;
+                message.into_response(&request.request_id)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                self.forget(&request.request_id);
+                Err(Error::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                let waiting = self.inner.shared.waiting.lock().unwrap();
+                Err(closed_error(waiting.closed.as_deref().unwrap_or("reader stopped")))
+            }
+        }
+    }
+
+    fn forget(&self, request_id: &str) {
+        self.inner.shared.waiting.lock().unwrap().responses.remove(request_id);
+    }
+
+    /// Pings whenever nothing arrived for a whole interval, and shuts the
+    /// connection down when a ping goes unanswered. Stops with the
+    /// connection or once the last clone is dropped.
+    fn heartbeat(inner: Weak<Inner>, keepalive: Keepalive) {
+        loop {
+            thread::sleep(keepalive.interval);
+            let Some(inner) = inner.upgrade() else { return };
+            let client = MuxClient { inner };
+            let idle = {
+                let waiting = client.inner.shared.waiting.lock().unwrap();
+                if waiting.closed.is_some() {
+                    return;
+                }
+                waiting.last_read.is_none_or(|read| read.elapsed() >= keepalive.interval)
+            };
+            if !idle {
+                continue;
+            }
+            match client.do_request_inner(Message::request(PING, b""), Some(keepalive.timeout)) {
+                Ok(_) | Err(Error::RemoteError { .. }) => {}
+                Err(e) => {
+                    client.inner.shared.close(format!("no answer to heartbeat: {}", e));
+                    let _ = client.inner.writer.lock().unwrap().shutdown(std::net::Shutdown::Both);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Hands each response to its caller until the connection fails, or a
+    /// frame longer than `max_len` or failing `integrity` makes it
+    /// unusable.
+    fn read_responses(&self, mut reader: BufReader<UnixStream>, framing: Framing, max_len: Option<usize>, integrity: &Integrity) {
+        let mut buffer = BytesMut::new();
+        let reason = loop {
+            let frame = framing.read_into(&mut reader, max_len, &mut buffer).and_then(|frame| integrity.verify(&frame).map(|_| frame));
+            match frame {
+                Ok(message) => {
+                    let mut waiting = self.waiting.lock().unwrap();
+                    waiting.last_read = Some(Instant::now());
+                    // A response nobody waits for any more belongs to a
+                    // request that timed out; drop it.
+                    if let Some(tx) = waiting.responses.remove(&message.request_id) {
+                        let _ = tx.send(message);
+                    }
+                }
+                Err(e) => break e.to_string(),
+            }
+        };
+        self.close(reason);
+    }
+
+    /// Fails every waiting call; the first reason given is the one kept.
+    fn close(&self, reason: String) {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.closed.get_or_insert(reason);
+        waiting.responses.clear();
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = self.writer.get_mut().unwrap().shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn closed_error(reason: &str) -> Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, format!("multiplexed connection closed: {}", reason)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Status};
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn responses_are_matched_by_request_id() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let first = Framing::Delimited.read(&mut reader).unwrap();
+            let second = Framing::Delimited.read(&mut reader).unwrap();
+            conn.write_all(&Framing::Delimited.encode(&second)).unwrap();
+            conn.write_all(&Framing::Delimited.encode(&first)).unwrap();
+        });
+
+        let client = Client::builder(&path).timeout(Some(Duration::from_secs(5))).connect_multiplexed().unwrap();
+        let callers: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|body| {
+                let client = client.clone();
+                thread::spawn(move || assert_eq!(client.do_request("echo", body.as_bytes()).unwrap(), body.as_bytes()))
+            })
+            .collect();
+        for caller in callers {
+            caller.join().unwrap();
+        }
+
+        assert!(client.do_request("echo", b"after close").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn oversized_responses_close_the_connection() {
+        let path = socket_path();
+        let mut server = crate::Server::bind(&path).unwrap();
+        server.register("grow", |body| Ok::<_, Status>(body.repeat(64)));
+        thread::spawn(move || server.run());
+
+        let builder = Client::builder(&path).timeout(Some(Duration::from_secs(5))).max_message_size(256);
+        let client = builder.connect_multiplexed().unwrap();
+        assert_eq!(client.do_request("grow", b"x").unwrap().len(), 64);
+        let error = client.do_request("grow", &[b'x'; 16]).unwrap_err();
+        assert!(error.to_string().contains("exceeds 256 bytes"), "{}", error);
+        assert!(client.do_request("grow", b"x").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_are_signed_authorized_and_compressed_as_configured() {
+        let path = socket_path();
+        let mut server = crate::Server::bind(&path).unwrap();
+        server.set_signing_key(b"shared");
+        server.set_checksums(true);
+        server.register_with_context("whoami", |context, body| {
+            Ok(format!("{}:{}:{}", context.header("authorization").unwrap_or("anonymous"), context.header("trace-id").unwrap_or("-"), body.len()).into_bytes())
+        });
+        thread::spawn(move || server.run());
+
+        let builder = Client::builder(&path)
+            .timeout(Some(Duration::from_secs(5)))
+            .signing_key(b"shared")
+            .checksums(true)
+            .bearer_token("s3cret")
+            .interceptor(|request: &mut crate::OutgoingRequest<'_>| request.set_header("trace-id", "4bf92f"));
+        let client = builder.connect_multiplexed().unwrap();
+        assert_eq!(&client.do_request("whoami", b"abc").unwrap()[..], b"Bearer s3cret:4bf92f:3");
+        client.notify("whoami", b"").unwrap();
+        assert_eq!(&client.do_request("whoami", b"").unwrap()[..], b"Bearer s3cret:4bf92f:0");
+
+        let unsigned = Client::builder(&path).timeout(Some(Duration::from_secs(5))).connect_multiplexed().unwrap();
+        assert!(unsigned.do_request("whoami", b"").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missed_heartbeats_close_the_connection() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (_hung, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let builder = Client::builder(&path).keepalive(Duration::from_millis(20), Duration::from_millis(50));
+        let client = builder.connect_multiplexed().unwrap();
+        let error = client.do_request("echo", b"never answered").unwrap_err();
+        assert!(error.to_string().contains("no answer to heartbeat"), "{}", error);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+//! A client for event loops that are not an async runtime.
+//!
+//! [`NonBlockingClient`] never blocks: it queues requests, and writes and
+//! reads only as far as the socket allows whenever the loop says it is
+//! ready. It exposes its descriptor so that the socket can be registered
+//! with whatever the loop polls, be it epoll directly or mio through
+//! `SourceFd`.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::task::Poll;
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::protocol::{Decoder, Encoder, Framing, Message};
+
+const READ_CHUNK: usize = 4096;
+
+/// A client on a non-blocking socket, driven by the caller's event loop:
+///
+/// ```no_run
+/// # use std::task::Poll;
+/// # use unixconn_rust::NonBlockingClient;
+/// let mut client = NonBlockingClient::connect("/run/agent.sock")?;
+/// let id = client.send("echo", b"hello");
+/// // Register client.as_raw_fd() for readability, and for writability
+/// // while client.wants_write(), then on each wake-up:
+/// if let Poll::Ready(result) = client.poll_send() {
+///     result?;
+/// }
+/// while let Poll::Ready(answer) = client.poll_recv() {
+///     let (request_id, response) = answer?;
+///     assert_eq!(request_id, id);
+///     println!("{:?}", response?);
+/// }
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+///
+/// Requests may be pipelined; answers come back in whatever order the
+/// server sends them, under the ID [`NonBlockingClient::send`] returned.
+/// Only plain unary calls are spoken: there is no handshake, and no
+/// timeouts, which are left to the loop's timers and
+/// [`NonBlockingClient::forget`].
+pub struct NonBlockingClient {
+    conn: UnixStream,
+    encoder: Encoder,
+    decoder: Decoder,
+    /// Requests sent and not yet answered or forgotten.
+    pending: HashSet<String>,
+}
+
+impl NonBlockingClient {
+    /// Connects to `address`, which blocks only as long as connecting to a
+    /// unix socket does, and switches the socket to non-blocking mode.
+    pub fn connect(address: &str) -> Result<Self> {
+        Self::from_stream(UnixStream::connect(address)?, Framing::default())
+    }
+
+    pub fn from_stream(conn: UnixStream, framing: Framing) -> Result<Self> {
+        conn.set_nonblocking(true)?;
+        Ok(NonBlockingClient { conn, encoder: Encoder::new(framing), decoder: Decoder::new(framing, None), pending: HashSet::new() })
+    }
+
+    /// Bounds the size of an answer frame, as
+    /// [`crate::Client::set_max_message_size`] does; a longer one fails
+    /// [`NonBlockingClient::poll_recv`] with [`Error::MessageTooLarge`].
+    /// `None`, the default, accepts any size.
+    pub fn set_max_message_size(&mut self, max_len: Option<usize>) {
+        self.decoder.set_max_len(max_len);
+    }
+
+    /// Queues a request, to go out on the next [`NonBlockingClient::poll_send`],
+    /// and returns the ID its answer will come back under.
+    pub fn send(&mut self, method_name: &str, request_body: &[u8]) -> String {
+        let request = Message::request(method_name, request_body);
+        self.encoder.encode(&request);
+        self.pending.insert(request.request_id.clone());
+        request.request_id
+    }
+
+    /// Queues a notification, which gets no answer.
+    pub fn notify(&mut self, method_name: &str, request_body: &[u8]) {
+        self.encoder.encode(&Message::notification(method_name, request_body));
+    }
+
+    /// Gives up on a request, e.g. once it has timed out; its answer is
+    /// dropped if it comes after all.
+    pub fn forget(&mut self, request_id: &str) {
+        self.pending.remove(request_id);
+    }
+
+    /// Whether there is something left to send, and so whether to wait for
+    /// the socket to be writable.
+    pub fn wants_write(&self) -> bool {
+        !self.encoder.pending().is_empty()
+    }
+
+    /// How many requests are waiting for an answer.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Writes what is queued until the socket would block. Ready once
+    /// everything has been sent, pending while some is left for the next
+    /// time the socket is writable.
+    pub fn poll_send(&mut self) -> Poll<Result<()>> {
+        while !self.encoder.pending().is_empty() {
+            match self.conn.write(self.encoder.pending()) {
+                Ok(0) => return Poll::Ready(Err(Error::Io(io::ErrorKind::WriteZero.into()))),
+                Ok(n) => self.encoder.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e.into())),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// The next answer, with the ID of the request it answers, reading
+    /// until it is whole or the socket would block. An error answer from
+    /// the server is the inner result; the outer one fails only when the
+    /// connection does, after which the client is of no further use.
+    ///
+    /// Call it until it is pending each time the socket is readable, as
+    /// one read may bring in several answers.
+    pub fn poll_recv(&mut self) -> Poll<Result<(String, Result<Bytes>)>> {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            while let Some(message) = match self.decoder.decode() {
+                Ok(message) => message,
+                Err(e) => return Poll::Ready(Err(e)),
+            } {
+                if self.pending.remove(&message.request_id) {
+                    let request_id = message.request_id.clone();
+                    return Poll::Ready(Ok((request_id.clone(), message.into_response(&request_id))));
+                }
+            }
+
+            match self.conn.read(&mut chunk) {
+                Ok(0) => return Poll::Ready(Err(Error::Io(io::ErrorKind::UnexpectedEof.into()))),
+                Ok(n) => self.decoder.feed(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e.into())),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> UnixStream {
+        self.conn
+    }
+}
+
+impl AsRawFd for NonBlockingClient {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl AsFd for NonBlockingClient {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.conn.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::{Code, Status};
+    use crate::Server;
+    use std::thread;
+
+    /// Waits for `events` on the client's socket, as an event loop would.
+    fn wait(client: &NonBlockingClient, events: libc::c_short) {
+        let mut fd = libc::pollfd { fd: client.as_raw_fd(), events, revents: 0 };
+        assert_eq!(unsafe { libc::poll(&mut fd, 1, 5000) }, 1);
+    }
+
+    fn served_client() -> NonBlockingClient {
+        let mut server = Server::new();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("fail", |_| Err(Status::new(Code::NotFound, "nothing here")));
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        thread::spawn(move || server.serve(server_end));
+        NonBlockingClient::from_stream(client_end, Framing::default()).unwrap()
+    }
+
+    fn run_until_answered(client: &mut NonBlockingClient) -> Vec<(String, Result<Bytes>)> {
+        let mut answers = Vec::new();
+        while client.pending() > 0 {
+            let events = if client.wants_write() { libc::POLLIN | libc::POLLOUT } else { libc::POLLIN };
+            wait(client, events);
+            if let Poll::Ready(result) = client.poll_send() {
+                result.unwrap();
+            }
+            while let Poll::Ready(answer) = client.poll_recv() {
+                answers.push(answer.unwrap());
+            }
+        }
+        answers
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_from_a_readiness_loop() {
+        let mut client = served_client();
+        assert!(matches!(client.poll_recv(), Poll::Pending));
+
+        let first = client.send("echo", b"first");
+        let failing = client.send("fail", b"");
+        let big = client.send("echo", &vec![b'x'; 1 << 20]);
+        let answers = run_until_answered(&mut client);
+
+        assert_eq!(answers.len(), 3);
+        for (request_id, response) in answers {
+            match response {
+                Ok(body) if request_id == first => assert_eq!(&body[..], b"first"),
+                Ok(body) if request_id == big => assert_eq!(body.len(), 1 << 20),
+                Err(Error::RemoteError { code: Code::NotFound, .. }) => assert_eq!(request_id, failing),
+                other => panic!("unexpected answer to {}: {:?}", request_id, other),
+            }
+        }
+    }
+
+    #[test]
+    fn answers_to_forgotten_requests_are_dropped() {
+        let mut client = served_client();
+        let forgotten = client.send("echo", b"too late");
+        client.forget(&forgotten);
+        client.notify("echo", b"no answer");
+        let wanted = client.send("echo", b"wanted");
+
+        let answers = run_until_answered(&mut client);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].0, wanted);
+        assert_eq!(&answers[0].1.as_ref().unwrap()[..], b"wanted");
+    }
+
+    #[test]
+    fn answers_over_the_limit_fail_the_connection() {
+        let mut client = served_client();
+        client.set_max_message_size(Some(64));
+        client.send("echo", &[b'x'; 100]);
+
+        loop {
+            let events = if client.wants_write() { libc::POLLIN | libc::POLLOUT } else { libc::POLLIN };
+            wait(&client, events);
+            if let Poll::Ready(result) = client.poll_send() {
+                result.unwrap();
+            }
+            if let Poll::Ready(answer) = client.poll_recv() {
+                assert!(matches!(answer, Err(Error::MessageTooLarge { limit: 64 })), "{:?}", answer.map(|(id, _)| id));
+                break;
+            }
+        }
+    }
+}
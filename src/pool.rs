@@ -0,0 +1,160 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::builder::ClientBuilder;
+use crate::error::Result;
+use crate::protocol::Framing;
+use crate::Client;
+
+/// A fixed-size set of connections to one socket, shared between threads.
+///
+/// Connections are opened on demand up to `size` and handed out one request
+/// at a time; callers beyond that wait for one to be returned. A connection
+/// that breaks is dropped instead of going back into the pool.
+pub struct ClientPool {
+    builder: ClientBuilder,
+    size: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Client>,
+    open: usize,
+}
+
+impl ClientPool {
+    pub fn new(address: &str, timeout: u64, size: usize) -> Self {
+        Self::with_framing(address, timeout, size, Framing::default())
+    }
+
+    pub fn with_framing(address: &str, timeout: u64, size: usize, framing: Framing) -> Self {
+        let builder = Client::builder(address)
+            .timeout(Some(Duration::from_secs(timeout)))
+            .framing(framing);
+        Self::from_builder(builder, size)
+    }
+
+    /// Opens every pooled connection with the settings of `builder`.
+    pub fn from_builder(builder: ClientBuilder, size: usize) -> Self {
+        ClientPool {
+            builder,
+            size: size.max(1),
+            state: Mutex::new(PoolState { idle: Vec::new(), open: 0 }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Checks out a connection, blocking while all of them are in use.
+    pub fn get(&self) -> Result<PooledClient<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok(PooledClient { pool: self, client: Some(client) });
+            }
+            if state.open < self.size {
+                break;
+            }
+            state = self.returned.wait(state).unwrap();
+        }
+
+        state.open += 1;
+        drop(state);
+
+        match self.builder.connect() {
+            Ok(client) => Ok(PooledClient { pool: self, client: Some(client) }),
+            Err(e) => {
+                self.release(None);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn do_request(&self, method_name: &str, request_body: &[u8]) -> Result<Bytes> {
+        self.get()?.do_request(method_name, request_body)
+    }
+
+    fn release(&self, client: Option<Client>) {
+        let mut state = self.state.lock().unwrap();
+        match client {
+            Some(client) if !client.is_broken() => state.idle.push(client),
+            _ => state.open -= 1,
+        }
+        self.returned.notify_one();
+    }
+}
+
+/// A connection checked out of a [`ClientPool`], returned to it on drop.
+pub struct PooledClient<'a> {
+    pool: &'a ClientPool,
+    client: Option<Client>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.client.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::echo_server;
+    use std::thread;
+
+    #[test]
+    fn shares_bounded_connections_between_threads() {
+        let path = echo_server(4);
+        let pool = ClientPool::new(&path, 5, 2);
+
+        thread::scope(|scope| {
+            for i in 0..8 {
+                let pool = &pool;
+                scope.spawn(move || {
+                    let body = format!("request {}", i);
+                    assert_eq!(pool.do_request("echo", body.as_bytes()).unwrap(), body.as_bytes());
+                });
+            }
+        });
+
+        let state = pool.state.lock().unwrap();
+        assert!(state.open <= 2);
+        assert_eq!(state.idle.len(), state.open);
+        drop(state);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_broken_connections() {
+        let path = echo_server(1);
+        let pool = ClientPool::new(&path, 5, 1);
+
+        let mut client = pool.get().unwrap();
+        client.close().unwrap();
+        assert!(client.do_request("echo", b"").is_err());
+        drop(client);
+
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+        assert_eq!(pool.do_request("echo", b"again").unwrap(), &b"again"[..]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,868 @@
+use std::io::{self, BufRead, IoSlice, Read};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::status::Status;
+
+const PROTOCOL_FIELDS: usize = 4;
+
+/// Fields in a frame that carries headers, sent between error and body.
+const PROTOCOL_FIELDS_WITH_HEADERS: usize = 5;
+
+const LENGTH_PREFIX: usize = 4;
+
+/// Method name of the frame that ends a streamed exchange.
+pub(crate) const STREAM_END: &str = "__eos";
+
+/// Method name of the frame a client sends to leave a subscription.
+pub(crate) const UNSUBSCRIBE: &str = "__unsubscribe";
+
+/// Method name of the frame a client sends after giving up on a request.
+pub(crate) const CANCEL: &str = "__cancel";
+
+/// Method name of the request every server answers at once, with an empty
+/// body, for liveness checks.
+pub(crate) const PING: &str = "__ping";
+
+/// Header holding how many milliseconds the caller will wait for a response.
+pub(crate) const DEADLINE_HEADER: &str = "__deadline";
+
+/// Header holding the caller's trace ID, for following a request across
+/// processes.
+pub(crate) const TRACE_HEADER: &str = "__trace";
+
+const HEADER_DELIM: u8 = 0x1D;
+
+const ESCAPE: u8 = 0x1B;
+
+const ESCAPE_MASK: u8 = 0x20;
+
+pub(crate) fn metadata_delim() -> &'static [u8] {
+    &[0x1E]
+}
+
+pub(crate) const fn message_delim() -> u8 {
+    0x1F
+}
+
+/// How messages are laid out on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Fields separated by 0x1E and messages terminated by 0x1F, as spoken by
+    /// the Go implementation. Bodies must not contain either byte.
+    #[default]
+    Delimited,
+    /// Delimited framing with the body escaped, so it may carry any bytes.
+    /// 0x1B, 0x1E and 0x1F in the body are sent as 0x1B followed by the byte
+    /// XOR 0x20. Both peers must escape; the other fields are sent as is.
+    Escaped,
+    /// A big-endian u32 frame length followed by each field as a big-endian
+    /// u32 length and its bytes. Safe for arbitrary binary bodies.
+    LengthPrefixed,
+}
+
+impl Framing {
+    pub(crate) fn encode(self, message: &Message) -> Bytes {
+        self.encode_frame(message).to_bytes()
+    }
+
+    /// Encodes `message` leaving its body where it is, to be written with
+    /// the rest of the frame around it by [`crate::Transport::write_parts`].
+    pub(crate) fn encode_frame(self, message: &Message) -> Frame {
+        match self {
+            Framing::Delimited => delimited_frame(message, message.body.clone()),
+            Framing::Escaped => delimited_frame(message, escape_body(&message.body)),
+            Framing::LengthPrefixed => prefixed_frame(message),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn read<R: BufRead>(self, reader: &mut R) -> Result<Message> {
+        self.read_limited(reader, None)
+    }
+
+    /// Like [`Framing::read`], but fails with [`Error::MessageTooLarge`] as
+    /// soon as the frame turns out longer than `max_len` bytes.
+    pub(crate) fn read_limited<R: BufRead>(self, reader: &mut R, max_len: Option<usize>) -> Result<Message> {
+        self.read_into(reader, max_len, &mut BytesMut::new())
+    }
+
+    /// Like [`Framing::read_limited`], reading the frame into `buffer`. A
+    /// connection reading frame after frame keeps one buffer for them all:
+    /// the message hands the bytes on, and the buffer takes its memory back
+    /// for the next frame once the message and its body are dropped.
+    pub(crate) fn read_into<R: BufRead>(self, reader: &mut R, max_len: Option<usize>, buffer: &mut BytesMut) -> Result<Message> {
+        buffer.clear();
+        match self {
+            Framing::Delimited => read_message(reader, max_len, buffer),
+            Framing::Escaped => read_message(reader, max_len, buffer).and_then(unescape_message),
+            Framing::LengthPrefixed => read_prefixed_message(reader, max_len, buffer),
+        }
+    }
+
+    /// Takes one complete frame off the front of `buffer`, leaving it
+    /// untouched if more bytes are needed.
+    #[cfg(test)]
+    fn split_frame(self, buffer: &mut BytesMut) -> Result<Option<Message>> {
+        match self.frame_len(buffer, 0) {
+            (len, true) => self.take_frame(buffer, len).map(Some),
+            (_, false) => Ok(None),
+        }
+    }
+
+    /// Takes the frame at the front of `buffer` off it, once
+    /// [`Framing::frame_len`] has found all `len` bytes of it there.
+    fn take_frame(self, buffer: &mut BytesMut, len: usize) -> Result<Message> {
+        match self {
+            Framing::Delimited | Framing::Escaped => {
+                let mut frame = buffer.split_to(len + 1).freeze();
+                frame.truncate(len);
+                let message = parse_message(frame)?;
+                if self == Framing::Escaped {
+                    return unescape_message(message);
+                }
+                Ok(message)
+            }
+            Framing::LengthPrefixed => {
+                let frame = buffer.split_to(LENGTH_PREFIX + len).freeze();
+                parse_prefixed_message(frame.slice(LENGTH_PREFIX..))
+            }
+        }
+    }
+
+    /// How long the frame at the front of `buffer` is, as far as can be
+    /// told from what has arrived of it, and whether all of it has. The end
+    /// of a delimited frame is searched for past the first `scanned` bytes,
+    /// which an earlier search found not to hold it.
+    fn frame_len(self, buffer: &[u8], scanned: usize) -> (usize, bool) {
+        match self {
+            Framing::Delimited | Framing::Escaped => match buffer[scanned..].iter().position(|&b| b == message_delim()) {
+                Some(pos) => (scanned + pos, true),
+                None => (buffer.len(), false),
+            },
+            Framing::LengthPrefixed => match buffer.get(..LENGTH_PREFIX) {
+                Some(len) => {
+                    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                    (len, buffer.len() >= LENGTH_PREFIX + len)
+                }
+                None => (0, false),
+            },
+        }
+    }
+}
+
+/// An encoded frame as the fields before the body, the body, and what ends
+/// the frame.
+pub(crate) struct Frame {
+    head: Bytes,
+    body: Bytes,
+    tail: &'static [u8],
+}
+
+impl Frame {
+    pub(crate) fn parts(&self) -> [IoSlice<'_>; 3] {
+        [IoSlice::new(&self.head), IoSlice::new(&self.body), IoSlice::new(self.tail)]
+    }
+
+    /// The frame in one buffer, copying the body into it.
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(self.head.len() + self.body.len() + self.tail.len());
+        for part in self.parts() {
+            buffer.put(&part[..]);
+        }
+        buffer.freeze()
+    }
+}
+
+/// The reading half of the protocol without any I/O: bytes go in as they
+/// arrive, however they are split up, and frames come out once whole. A
+/// frame over the limit fails as soon as that much of it has arrived.
+///
+/// Transports that wait for readiness or completion, rather than block
+/// in `read`, feed one of these. Blocking readers use [`Framing::read_into`]
+/// instead, which takes no bytes from the reader past the frame.
+///
+/// Decoders, [`Encoder`]s and the [`Message`]s they pass are internal to
+/// the crate, which keeps the frame layout free to change. Readiness loops
+/// such as mio's reach them through [`crate::NonBlockingClient`], and
+/// async runtimes through [`crate::aio::AsyncClient`].
+pub(crate) struct Decoder {
+    framing: Framing,
+    max_len: Option<usize>,
+    buffer: BytesMut,
+    /// How much of the buffer is known to hold no end of a delimited frame,
+    /// so a frame fed in many pieces is searched only once.
+    scanned: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(framing: Framing, max_len: Option<usize>) -> Self {
+        Decoder { framing, max_len, buffer: BytesMut::new(), scanned: 0 }
+    }
+
+    /// Switches framing for the frames after those already decoded, as
+    /// after a handshake.
+    pub(crate) fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.scanned = 0;
+    }
+
+    pub(crate) fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+    }
+
+    pub(crate) fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next whole frame, or `None` until more has been fed in. A frame
+    /// that fails to parse is consumed with the error, so the caller can
+    /// go on to the next one. A frame over the limit is not, as it is
+    /// refused before its end has arrived: the decoder fails with
+    /// [`Error::MessageTooLarge`] from then on, and the connection is to
+    /// be given up, as the blocking readers give it up.
+    pub(crate) fn decode(&mut self) -> Result<Option<Message>> {
+        let (len, whole) = self.framing.frame_len(&self.buffer, self.scanned);
+        if let Some(limit) = self.max_len.filter(|&limit| len > limit) {
+            return Err(Error::MessageTooLarge { limit });
+        }
+        if !whole {
+            self.scanned = match self.framing {
+                Framing::LengthPrefixed => 0,
+                Framing::Delimited | Framing::Escaped => len,
+            };
+            return Ok(None);
+        }
+        self.scanned = 0;
+        self.framing.take_frame(&mut self.buffer, len).map(Some)
+    }
+
+    /// Whether part of a frame is left over.
+    pub(crate) fn has_partial_frame(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Drops whatever has not been decoded, e.g. a frame cut off by the
+    /// peer hanging up.
+    pub(crate) fn clear(&mut self) {
+        self.buffer.clear();
+        self.scanned = 0;
+    }
+}
+
+/// The writing half of the protocol without any I/O: frames are queued as
+/// bytes, for the caller to write as far as its socket takes them and
+/// report back how much went.
+pub(crate) struct Encoder {
+    framing: Framing,
+    buffer: BytesMut,
+}
+
+impl Encoder {
+    pub(crate) fn new(framing: Framing) -> Self {
+        Encoder { framing, buffer: BytesMut::new() }
+    }
+
+    /// Switches framing for the frames queued from now on.
+    pub(crate) fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub(crate) fn encode(&mut self, message: &Message) {
+        for part in self.framing.encode_frame(message).parts() {
+            self.buffer.extend_from_slice(&part);
+        }
+    }
+
+    /// What is queued and not yet written.
+    pub(crate) fn pending(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Takes the first `n` pending bytes off the queue once written.
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.buffer.advance(n);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Message {
+    pub(crate) request_id: String,
+    pub(crate) method_name: String,
+    pub(crate) body: Bytes,
+    pub(crate) error: String,
+    /// Sent only when non-empty, which keeps frames without headers readable
+    /// by peers that predate them.
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Message {
+    pub(crate) fn request(method_name: &str, request_body: &[u8]) -> Self {
+        Message {
+            request_id: Uuid::new_v4().to_string(),
+            method_name: method_name.to_string(),
+            body: Bytes::from(request_body.to_vec()),
+            error: String::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// A request the peer must not answer, marked by an empty request_id.
+    pub(crate) fn notification(method_name: &str, request_body: &[u8]) -> Self {
+        Message {
+            request_id: String::new(),
+            method_name: method_name.to_string(),
+            body: Bytes::from(request_body.to_vec()),
+            error: String::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_notification(&self) -> bool {
+        self.request_id.is_empty()
+    }
+
+    /// A frame answering this one, carrying either a body or an error.
+    pub(crate) fn reply(&self, method_name: &str, result: Result<Bytes, Status>) -> Message {
+        let (body, error) = match result {
+            Ok(body) => (body, String::new()),
+            Err(status) => (Bytes::new(), status.encode()),
+        };
+
+        Message {
+            request_id: self.request_id.clone(),
+            method_name: method_name.to_string(),
+            body,
+            error,
+            headers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_body(&self, body: Bytes) -> Message {
+        Message {
+            request_id: self.request_id.clone(),
+            method_name: self.method_name.clone(),
+            body,
+            error: self.error.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn into_response(self, request_id: &str) -> Result<Bytes> {
+        if !self.error.is_empty() {
+            let status = Status::decode(&self.error);
+            return Err(Error::RemoteError { code: status.code(), message: status.message().to_string() });
+        }
+
+        if self.request_id != request_id {
+            return Err(Error::RequestIdMismatch { expected: request_id.to_string(), received: self.request_id });
+        }
+
+        Ok(self.body)
+    }
+}
+
+/// Parses the fields of a delimited frame, without its terminator. The
+/// body is a slice of `frame` rather than a copy of it.
+pub(crate) fn parse_message(frame: Bytes) -> Result<Message> {
+    let parts: Vec<&[u8]> = frame.split(|&b| b == metadata_delim()[0]).collect();
+    let headers = match parts.len() {
+        PROTOCOL_FIELDS => Vec::new(),
+        PROTOCOL_FIELDS_WITH_HEADERS => parse_headers(parts[3])?,
+        n => return Err(Error::Protocol(format!("received message with {} parts, expected {}", n, PROTOCOL_FIELDS))),
+    };
+
+    Ok(Message {
+        request_id: String::from_utf8(parts[0].to_vec())?,
+        method_name: String::from_utf8(parts[1].to_vec())?,
+        error: String::from_utf8(parts[2].to_vec())?,
+        body: frame.slice_ref(parts[parts.len() - 1]),
+        headers,
+    })
+}
+
+/// A delimited frame of `r`'s fields, carrying `body` as its body.
+fn delimited_frame(r: &Message, body: Bytes) -> Frame {
+    let mut buffer = BytesMut::new();
+
+    buffer.put(r.request_id.as_bytes());
+    buffer.put(metadata_delim());
+
+    buffer.put(r.method_name.as_bytes());
+    buffer.put(metadata_delim());
+
+    buffer.put(r.error.as_bytes());
+    buffer.put(metadata_delim());
+
+    if !r.headers.is_empty() {
+        buffer.put(&encode_headers(&r.headers)[..]);
+        buffer.put(metadata_delim());
+    }
+
+    const END: &[u8] = &[message_delim()];
+    Frame { head: buffer.freeze(), body, tail: END }
+}
+
+/// Reads up to the next terminator, as [`BufRead::read_until`] does, but
+/// into `message_body` and stopping once the frame is over `max_len`.
+fn read_message<R: BufRead>(reader: &mut R, max_len: Option<usize>, message_body: &mut BytesMut) -> Result<Message> {
+    loop {
+        let available = match reader.fill_buf() {
+            Ok([]) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let end = available.iter().position(|&b| b == message_delim());
+        let chunk = &available[..end.unwrap_or(available.len())];
+        if let Some(limit) = max_len.filter(|&limit| message_body.len() + chunk.len() > limit) {
+            return Err(Error::MessageTooLarge { limit });
+        }
+        message_body.extend_from_slice(chunk);
+        let used = chunk.len() + usize::from(end.is_some());
+        reader.consume(used);
+        if end.is_some() {
+            break;
+        }
+    }
+
+    parse_message(message_body.split().freeze())
+}
+
+/// Headers travel as `key=value` entries separated by 0x1D. Neither keys nor
+/// values may contain the frame delimiters or 0x1D, and keys no `=`.
+fn encode_headers(headers: &[(String, String)]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for (i, (key, value)) in headers.iter().enumerate() {
+        if i > 0 {
+            buffer.push(HEADER_DELIM);
+        }
+        buffer.extend_from_slice(key.as_bytes());
+        buffer.push(b'=');
+        buffer.extend_from_slice(value.as_bytes());
+    }
+    buffer
+}
+
+/// Rejects headers that would not survive [`encode_headers`], and names
+/// starting with `__`, which are reserved for the protocol itself.
+pub(crate) fn check_header(key: &str, value: &str) -> Result<()> {
+    if key.is_empty() || key.starts_with("__") || key.contains('=') || key.bytes().any(unsafe_byte) {
+        return Err(Error::Protocol(format!("invalid header name: {:?}", key)));
+    }
+    check_header_value(key, value)
+}
+
+/// Rejects a value that would not survive [`encode_headers`], for headers
+/// the protocol sets itself.
+pub(crate) fn check_header_value(key: &str, value: &str) -> Result<()> {
+    match value.bytes().any(unsafe_byte) {
+        true => Err(Error::Protocol(format!("invalid value for header {}", key))),
+        false => Ok(()),
+    }
+}
+
+fn unsafe_byte(b: u8) -> bool {
+    b == HEADER_DELIM || b == metadata_delim()[0] || b == message_delim()
+}
+
+fn parse_headers(raw: &[u8]) -> Result<Vec<(String, String)>> {
+    if raw.is_empty() {
+        return Ok(Vec::new());
+    }
+    raw.split(|&b| b == HEADER_DELIM)
+        .map(|entry| {
+            let entry = std::str::from_utf8(entry).map_err(|_| Error::Protocol("header is not utf-8".to_string()))?;
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| Error::Protocol(format!("header without a value: {:?}", entry)))?;
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// A body with nothing to escape is shared rather than copied.
+fn escape_body(body: &Bytes) -> Bytes {
+    if !body.iter().any(|&b| b == ESCAPE || b == metadata_delim()[0] || b == message_delim()) {
+        return body.clone();
+    }
+    let mut buffer = BytesMut::with_capacity(body.len());
+    for &b in body {
+        if b == ESCAPE || b == metadata_delim()[0] || b == message_delim() {
+            buffer.put_u8(ESCAPE);
+            buffer.put_u8(b ^ ESCAPE_MASK);
+        } else {
+            buffer.put_u8(b);
+        }
+    }
+    buffer.freeze()
+}
+
+fn unescape_message(message: Message) -> Result<Message> {
+    if !message.body.contains(&ESCAPE) {
+        return Ok(message);
+    }
+    let mut body = BytesMut::with_capacity(message.body.len());
+    let mut bytes = message.body.iter();
+    while let Some(&b) = bytes.next() {
+        if b != ESCAPE {
+            body.put_u8(b);
+            continue;
+        }
+        match bytes.next().map(|&b| b ^ ESCAPE_MASK) {
+            Some(b) if b == ESCAPE || b == metadata_delim()[0] || b == message_delim() => body.put_u8(b),
+            _ => return Err(Error::Protocol("invalid escape sequence in body".to_string())),
+        }
+    }
+
+    Ok(Message { body: body.freeze(), ..message })
+}
+
+/// Parses the fields of a length-prefixed frame after its length. As with
+/// [`parse_message`], the body is a slice of `body`.
+fn parse_prefixed_message(mut body: Bytes) -> Result<Message> {
+    let mut parts = Vec::with_capacity(PROTOCOL_FIELDS);
+    while body.has_remaining() {
+        if body.remaining() < LENGTH_PREFIX {
+            return Err(Error::Protocol("truncated field length".to_string()));
+        }
+        let len = body.get_u32() as usize;
+        if body.remaining() < len {
+            return Err(Error::Protocol(format!("field of {} bytes overruns frame", len)));
+        }
+        parts.push(body.copy_to_bytes(len));
+    }
+    let headers = match parts.len() {
+        PROTOCOL_FIELDS => Vec::new(),
+        PROTOCOL_FIELDS_WITH_HEADERS => parse_headers(&parts[3])?,
+        n => return Err(Error::Protocol(format!("received message with {} parts, expected {}", n, PROTOCOL_FIELDS))),
+    };
+
+    Ok(Message {
+        request_id: String::from_utf8(parts[0].to_vec())?,
+        method_name: String::from_utf8(parts[1].to_vec())?,
+        error: String::from_utf8(parts[2].to_vec())?,
+        body: parts.pop().expect("frame has a body field"),
+        headers,
+    })
+}
+
+fn prefixed_frame(r: &Message) -> Frame {
+    let headers = encode_headers(&r.headers);
+    let mut fields: Vec<&[u8]> = vec![r.request_id.as_bytes(), r.method_name.as_bytes(), r.error.as_bytes()];
+    if !r.headers.is_empty() {
+        fields.push(&headers);
+    }
+
+    let len: usize = fields.iter().map(|f| LENGTH_PREFIX + f.len()).sum::<usize>() + LENGTH_PREFIX + r.body.len();
+
+    let mut buffer = BytesMut::with_capacity(LENGTH_PREFIX + len - r.body.len());
+    buffer.put_u32(len as u32);
+    for field in fields {
+        buffer.put_u32(field.len() as u32);
+        buffer.put(field);
+    }
+    // The body's own length goes last in the head, right before it.
+    buffer.put_u32(r.body.len() as u32);
+
+    Frame { head: buffer.freeze(), body: r.body.clone(), tail: &[] }
+}
+
+/// How much of a length-prefixed frame is read at a time, so that the
+/// buffer only grows as the bytes arrive and not to a bogus length.
+const PREFIXED_CHUNK: usize = 64 * 1024;
+
+fn read_prefixed_message<R: Read>(reader: &mut R, max_len: Option<usize>, message_body: &mut BytesMut) -> Result<Message> {
+    let mut len = [0u8; LENGTH_PREFIX];
+    reader.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if let Some(limit) = max_len.filter(|&limit| len > limit) {
+        return Err(Error::MessageTooLarge { limit });
+    }
+    while message_body.len() < len {
+        let start = message_body.len();
+        message_body.resize(len.min(start + PREFIXED_CHUNK), 0);
+        reader.read_exact(&mut message_body[start..])?;
+    }
+
+    parse_prefixed_message(message_body.split().freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{for_all, Gen};
+    use std::io;
+
+    const FRAME_BYTES: [char; 2] = ['\u{1e}', '\u{1f}'];
+
+    const HEADER_BYTES: [char; 4] = ['\u{1d}', '\u{1e}', '\u{1f}', '='];
+
+    /// A message whose fields, `body` aside, fit in the delimited framing.
+    fn arbitrary_message(gen: &mut Gen, body: Vec<u8>) -> Message {
+        let headers = (0..gen.below(3))
+            .map(|_| (format!("h{}", gen.string(8, &HEADER_BYTES)), gen.string(8, &HEADER_BYTES[..3])))
+            .filter(|(key, value)| check_header(key, value).is_ok())
+            .collect();
+        Message {
+            request_id: gen.string(40, &FRAME_BYTES),
+            method_name: gen.string(20, &FRAME_BYTES),
+            error: gen.string(20, &FRAME_BYTES),
+            body: Bytes::from(body),
+            headers,
+        }
+    }
+
+    #[test]
+    fn delimited_frames_round_trip_bodies_without_delimiters() {
+        for_all(500, |gen| {
+            let body = gen.bytes(64).into_iter().filter(|b| ![metadata_delim()[0], message_delim()].contains(b)).collect();
+            let message = arbitrary_message(gen, body);
+            let raw = Framing::Delimited.encode(&message);
+            assert_eq!(parse_message(raw.slice(..raw.len() - 1)).unwrap(), message);
+            assert_eq!(Framing::Delimited.read(&mut &raw[..]).unwrap(), message);
+        });
+    }
+
+    /// The limitation [`Framing::Escaped`] and [`Framing::LengthPrefixed`]
+    /// exist for: a delimiter in the body ends its field or frame early.
+    #[test]
+    fn delimited_frames_mangle_bodies_with_delimiters() {
+        for_all(500, |gen| {
+            let mut body = gen.bytes(64);
+            body.insert(gen.below(body.len() + 1), [metadata_delim()[0], message_delim()][gen.below(2)]);
+            let message = arbitrary_message(gen, body);
+            let raw = Framing::Delimited.encode(&message);
+            assert_ne!(Framing::Delimited.read(&mut &raw[..]).ok(), Some(message));
+        });
+    }
+
+    #[test]
+    fn binary_safe_framings_round_trip_any_body() {
+        for_all(500, |gen| {
+            let body = gen.bytes(64);
+            let message = arbitrary_message(gen, body);
+            for framing in [Framing::Escaped, Framing::LengthPrefixed] {
+                let raw = framing.encode(&message);
+                assert_eq!(framing.read(&mut &raw[..]).unwrap(), message, "{:?}", framing);
+                assert_eq!(framing.split_frame(&mut BytesMut::from(&raw[..])).unwrap(), Some(message.clone()), "{:?}", framing);
+            }
+        });
+    }
+
+    #[test]
+    fn length_prefixed_frames_round_trip_any_field() {
+        for_all(500, |gen| {
+            let body = gen.bytes(64);
+            let mut message = arbitrary_message(gen, body);
+            message.request_id = gen.string(40, &[]);
+            message.error = gen.string(20, &[]);
+            let raw = Framing::LengthPrefixed.encode(&message);
+            assert_eq!(Framing::LengthPrefixed.read(&mut &raw[..]).unwrap(), message);
+        });
+    }
+
+    #[test]
+    fn length_prefixes_beyond_the_input_fail_without_allocating_for_them() {
+        let raw = [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 1, b'x'];
+        assert!(matches!(Framing::LengthPrefixed.read(&mut &raw[..]), Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn length_prefixed_framing_is_binary_safe() {
+        let mut message = Message::request("upload", &[0x1E, 0x00, 0x1F, 0x1E]);
+        message.error = "partial\u{1f}".to_string();
+
+        let raw = Framing::LengthPrefixed.encode(&message);
+        let decoded = Framing::LengthPrefixed.read(&mut &raw[..]).unwrap();
+
+        assert_eq!(decoded.request_id, message.request_id);
+        assert_eq!(decoded.method_name, "upload");
+        assert_eq!(decoded.error, message.error);
+        assert_eq!(decoded.body, message.body);
+    }
+
+    #[test]
+    fn headers_round_trip_and_are_omitted_when_empty() {
+        let mut message = Message::request("echo", b"body");
+        for framing in [Framing::Delimited, Framing::LengthPrefixed] {
+            let plain = framing.encode(&message);
+            assert!(framing.read(&mut &plain[..]).unwrap().headers.is_empty());
+        }
+        assert_eq!(Framing::Delimited.encode(&message).iter().filter(|&&b| b == metadata_delim()[0]).count(), 3);
+
+        message.headers = vec![("trace".to_string(), "abc".to_string()), ("ratio".to_string(), "a=b".to_string())];
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let raw = framing.encode(&message);
+            let decoded = framing.read(&mut &raw[..]).unwrap();
+            assert_eq!(decoded.headers, message.headers);
+            assert_eq!(decoded.header("ratio"), Some("a=b"));
+            assert_eq!(&decoded.body[..], b"body");
+        }
+    }
+
+    #[test]
+    fn escaped_framing_round_trips_delimiter_bytes() {
+        let body = [b'a', 0x1E, 0x1B, 0x1F, b'z'];
+        let raw = Framing::Escaped.encode(&Message::request("upload", &body));
+
+        assert_eq!(raw.iter().filter(|&&b| b == message_delim()).count(), 1);
+        assert_eq!(raw.iter().filter(|&&b| b == metadata_delim()[0]).count(), 3);
+
+        let decoded = Framing::Escaped.read(&mut &raw[..]).unwrap();
+        assert_eq!(&decoded.body[..], &body);
+    }
+
+    #[test]
+    fn escaped_framing_rejects_bad_escapes() {
+        let raw = Framing::Delimited.encode(&Message::request("upload", &[ESCAPE, b'x']));
+
+        assert!(Framing::Escaped.read(&mut &raw[..]).is_err());
+    }
+
+    #[test]
+    fn split_frame_waits_for_complete_frames() {
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let raw = framing.encode(&Message::request("echo", b"body"));
+            let mut buffer = BytesMut::from(&raw[..raw.len() - 1]);
+            assert!(framing.split_frame(&mut buffer).unwrap().is_none());
+
+            buffer.extend_from_slice(&raw[raw.len() - 1..]);
+            buffer.extend_from_slice(b"next");
+            let message = framing.split_frame(&mut buffer).unwrap().unwrap();
+            assert_eq!(&message.body[..], b"body");
+            assert_eq!(&buffer[..], b"next");
+        }
+    }
+
+    #[test]
+    fn bodies_are_slices_of_the_frame_they_arrived_in() {
+        let body = [b'x'; 4096];
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let mut buffer = BytesMut::from(&framing.encode(&Message::request("upload", &body))[..]);
+            let received = buffer.as_ptr_range();
+            let message = framing.split_frame(&mut buffer).unwrap().unwrap();
+            assert_eq!(&message.body[..], &body);
+            assert!(received.contains(&message.body.as_ptr()), "{:?} copied the body", framing);
+        }
+    }
+
+    #[test]
+    fn frames_are_encoded_around_the_body_rather_than_copying_it() {
+        let message = Message::request("upload", &[b'x'; 4096]);
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let frame = framing.encode_frame(&message);
+            assert_eq!(frame.parts()[1].as_ptr(), message.body.as_ptr(), "{:?} copied the body", framing);
+            let decoded = framing.read(&mut &frame.to_bytes()[..]).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn read_buffers_are_reused_once_messages_are_dropped() {
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let mut raw = framing.encode(&Message::request("echo", &[b'x'; 1024])).to_vec();
+            raw.extend_from_slice(&framing.encode(&Message::request("echo", &[b'y'; 1024])));
+            let mut reader = &raw[..];
+            let mut buffer = BytesMut::new();
+
+            let first = framing.read_into(&mut reader, None, &mut buffer).unwrap();
+            let storage = first.body.as_ptr();
+            drop(first);
+            let second = framing.read_into(&mut reader, None, &mut buffer).unwrap();
+            assert_eq!(&second.body[..], &[b'y'; 1024]);
+            assert_eq!(second.body.as_ptr(), storage, "{:?} allocated a new buffer", framing);
+        }
+    }
+
+    #[test]
+    fn delimited_frames_are_read_however_the_bytes_are_buffered() {
+        let mut raw = Framing::Delimited.encode(&Message::request("echo", b"first")).to_vec();
+        raw.extend_from_slice(&Framing::Delimited.encode(&Message::request("echo", b"second")));
+        for capacity in [1, 2, 7, raw.len()] {
+            let mut reader = io::BufReader::with_capacity(capacity, &raw[..]);
+            assert_eq!(&Framing::Delimited.read(&mut reader).unwrap().body[..], b"first");
+            assert_eq!(&Framing::Delimited.read(&mut reader).unwrap().body[..], b"second");
+            let ended = Framing::Delimited.read(&mut reader);
+            assert!(matches!(ended, Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof), "capacity {}", capacity);
+
+            let limited = Framing::Delimited.read_limited(&mut io::BufReader::with_capacity(capacity, &raw[..]), Some(10));
+            assert!(matches!(limited, Err(Error::MessageTooLarge { limit: 10 })), "capacity {}", capacity);
+        }
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_refused() {
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let raw = framing.encode(&Message::request("echo", &[b'x'; 100]));
+            assert!(framing.read_limited(&mut &raw[..], Some(raw.len())).is_ok());
+            let refused = framing.read_limited(&mut &raw[..], Some(64));
+            assert!(matches!(refused, Err(Error::MessageTooLarge { limit: 64 })));
+        }
+    }
+
+    #[test]
+    fn decoders_take_frames_however_the_bytes_are_fed_in() {
+        for_all(300, |gen| {
+            let framing = [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed][gen.below(3)];
+            let messages: Vec<_> = (0..gen.below(4) + 1)
+                .map(|_| {
+                    let body = gen.bytes(64).into_iter().filter(|b| ![metadata_delim()[0], message_delim()].contains(b)).collect();
+                    arbitrary_message(gen, body)
+                })
+                .collect();
+            let mut encoder = Encoder::new(framing);
+            for message in &messages {
+                encoder.encode(message);
+            }
+
+            let mut decoder = Decoder::new(framing, None);
+            let mut decoded = Vec::new();
+            while !encoder.pending().is_empty() {
+                let n = encoder.pending().len().min(gen.below(16) + 1);
+                decoder.feed(&encoder.pending()[..n]);
+                encoder.advance(n);
+                while let Some(message) = decoder.decode().unwrap() {
+                    decoded.push(message);
+                }
+            }
+            assert_eq!(decoded, messages, "{:?}", framing);
+            assert!(!decoder.has_partial_frame());
+        });
+    }
+
+    #[test]
+    fn decoders_search_delimited_frames_only_once() {
+        let raw = Framing::Delimited.encode(&Message::request("upload", &[b'x'; 4096]));
+        let mut decoder = Decoder::new(Framing::Delimited, None);
+        for (fed, byte) in raw[..raw.len() - 1].iter().enumerate() {
+            decoder.feed(&[*byte]);
+            assert!(decoder.decode().unwrap().is_none());
+            assert_eq!(decoder.scanned, fed + 1);
+        }
+        decoder.feed(&raw[raw.len() - 1..]);
+        assert_eq!(&decoder.decode().unwrap().unwrap().body[..], &[b'x'; 4096]);
+        assert_eq!(decoder.scanned, 0);
+    }
+
+    #[test]
+    fn decoders_refuse_frames_over_the_limit_before_they_are_whole() {
+        for framing in [Framing::Delimited, Framing::Escaped, Framing::LengthPrefixed] {
+            let raw = framing.encode(&Message::request("echo", &[b'x'; 100]));
+            let mut decoder = Decoder::new(framing, Some(64));
+            decoder.feed(&raw[..raw.len() / 2]);
+            assert!(matches!(decoder.decode(), Err(Error::MessageTooLarge { limit: 64 })), "{:?}", framing);
+
+            decoder.feed(&raw[raw.len() / 2..]);
+            decoder.feed(&framing.encode(&Message::request("echo", b"small")));
+            assert!(matches!(decoder.decode(), Err(Error::MessageTooLarge { limit: 64 })), "{:?}", framing);
+        }
+    }
+}
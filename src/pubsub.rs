@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::error::{Error, Result};
+use crate::status::{Code, Status};
+use crate::subscription::Subscriber;
+
+pub(crate) const PUBLISH: &str = "__publish";
+
+pub(crate) const SUBSCRIBE: &str = "__subscribe";
+
+/// Named topics that connected clients subscribe and publish to, fanning
+/// each published payload out to every current subscriber of its topic.
+///
+/// Serve them with [`crate::Server::register_topics`]; clones share the same
+/// topics, so the server process can publish too.
+#[derive(Clone, Default)]
+pub struct Topics {
+    subscribers: Arc<Mutex<HashMap<String, Vec<Subscriber>>>>,
+}
+
+impl Topics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `payload` to the subscribers of `topic`, returning how many it
+    /// reached.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> usize {
+        // Sending happens outside the lock so one slow subscriber does not
+        // hold up subscribing and publishing to other topics.
+        let subscribers = match self.lock().get(topic) {
+            Some(subscribers) => subscribers.clone(),
+            None => return 0,
+        };
+        let reached = subscribers.iter().filter(|subscriber| subscriber.send(payload).is_ok()).count();
+
+        let mut topics = self.lock();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.retain(|subscriber| !subscriber.is_closed());
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+        reached
+    }
+
+    pub fn subscriber_count(&self, topic: &str) -> usize {
+        self.lock()
+            .get(topic)
+            .map_or(0, |subscribers| subscribers.iter().filter(|subscriber| !subscriber.is_closed()).count())
+    }
+
+    pub(crate) fn subscribe(&self, topic: &str, subscriber: Subscriber) {
+        self.lock().entry(topic.to_string()).or_default().push(subscriber);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<Subscriber>>> {
+        self.subscribers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Joins topic and payload into a publish body: the topic, a newline, then
+/// the payload as is.
+pub(crate) fn publish_body(topic: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    if topic.contains('\n') {
+        return Err(Error::Protocol(format!("topic name must not contain a newline: {:?}", topic)));
+    }
+    let mut body = Vec::with_capacity(topic.len() + 1 + payload.len());
+    body.extend_from_slice(topic.as_bytes());
+    body.push(b'\n');
+    body.extend_from_slice(payload);
+    Ok(body)
+}
+
+pub(crate) fn split_publish_body(body: &[u8]) -> Result<(&str, &[u8]), Status> {
+    let at = body
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| Status::new(Code::InvalidArgument, "publish body has no topic"))?;
+    let topic = std::str::from_utf8(&body[..at]).map_err(|_| Status::new(Code::InvalidArgument, "topic name is not utf-8"))?;
+    Ok((topic, &body[at + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Server};
+    use std::thread;
+
+    #[test]
+    fn payloads_reach_subscribers_of_their_topic_only() {
+        let path = socket_path();
+        let topics = Topics::new();
+        let mut server = Server::bind(&path).unwrap();
+        server.set_workers(4);
+        server.register_topics(&topics);
+        thread::spawn(move || server.run());
+
+        let mut alice = Client::new(&path, 5).unwrap();
+        let mut bob = Client::new(&path, 5).unwrap();
+        let mut carol = Client::new(&path, 5).unwrap();
+        let mut news_for_alice = alice.subscribe_topic("news").unwrap();
+        let mut news_for_bob = bob.subscribe_topic("news").unwrap();
+        let mut sport = carol.subscribe_topic("sport").unwrap();
+
+        while topics.subscriber_count("news") < 2 || topics.subscriber_count("sport") < 1 {
+            thread::yield_now();
+        }
+
+        let mut publisher = Client::new(&path, 5).unwrap();
+        assert_eq!(publisher.publish("news", b"headline").unwrap(), 2);
+        assert_eq!(publisher.publish("weather", b"rain").unwrap(), 0);
+        assert_eq!(topics.publish("sport", b"score"), 1);
+        assert_eq!(&news_for_alice.next().unwrap().unwrap()[..], b"headline");
+        assert_eq!(&news_for_bob.next().unwrap().unwrap()[..], b"headline");
+        assert_eq!(&sport.next().unwrap().unwrap()[..], b"score");
+
+        news_for_bob.unsubscribe().unwrap();
+        assert_eq!(publisher.publish("news", b"later").unwrap(), 1);
+        assert_eq!(&news_for_alice.next().unwrap().unwrap()[..], b"later");
+
+        drop((news_for_alice, sport));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! The reserved `__methods` request, answered with the methods a server has
+//! registered, one `name kind` line each, sorted by name:
+//!
+//! ```text
+//! echo unary
+//! logs stream
+//! ```
+
+use std::fmt;
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+
+pub(crate) const METHODS: &str = "__methods";
+
+/// How a method is called, i.e. which `register_*` call registered it on
+/// the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodKind {
+    /// [`crate::Client::do_request`] and its variants.
+    Unary,
+    /// [`crate::Client::do_request_stream`].
+    Stream,
+    /// [`crate::Client::do_request_streamed`].
+    Upload,
+    /// [`crate::Client::open_duplex`].
+    Duplex,
+    /// [`crate::Client::subscribe`].
+    Subscription,
+}
+
+impl MethodKind {
+    const ALL: [MethodKind; 5] = [MethodKind::Unary, MethodKind::Stream, MethodKind::Upload, MethodKind::Duplex, MethodKind::Subscription];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MethodKind::Unary => "unary",
+            MethodKind::Stream => "stream",
+            MethodKind::Upload => "upload",
+            MethodKind::Duplex => "duplex",
+            MethodKind::Subscription => "subscription",
+        }
+    }
+}
+
+impl fmt::Display for MethodKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A method a server offers, from [`crate::Client::methods`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodInfo {
+    name: String,
+    kind: MethodKind,
+}
+
+impl MethodInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> MethodKind {
+        self.kind
+    }
+}
+
+pub(crate) fn describe<'a>(methods: impl Iterator<Item = (&'a str, MethodKind)>) -> Bytes {
+    let mut methods: Vec<_> = methods.collect();
+    methods.sort_unstable_by_key(|(name, _)| *name);
+    methods.iter().map(|(name, kind)| format!("{} {}\n", name, kind)).collect::<String>().into()
+}
+
+/// Reads a `__methods` answer. Kinds this client does not know are left
+/// out, so newer servers can add them.
+pub(crate) fn parse(body: &[u8]) -> Result<Vec<MethodInfo>> {
+    let body = std::str::from_utf8(body).map_err(|_| Error::Protocol("method list is not utf-8".to_string()))?;
+    let mut methods = Vec::new();
+    for line in body.lines() {
+        let (name, kind) = line.rsplit_once(' ').ok_or_else(|| Error::Protocol(format!("malformed method list line: {:?}", line)))?;
+        if let Some(kind) = MethodKind::ALL.into_iter().find(|known| known.as_str() == kind) {
+            methods.push(MethodInfo { name: name.to_string(), kind });
+        }
+    }
+    Ok(methods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Server, Status};
+
+    #[test]
+    fn servers_list_their_methods() {
+        let mut server = Server::new();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register_stream("logs", |_, sink| sink.send(b"line"));
+        server.register_upload("store", |_| Ok(Vec::new()));
+        let mut client = testing::connect(server).unwrap();
+
+        let methods: Vec<_> = client.methods().unwrap().iter().map(|m| format!("{} {}", m.name(), m.kind())).collect();
+        assert_eq!(methods, ["echo unary", "logs stream", "store upload"]);
+    }
+
+    #[test]
+    fn unknown_kinds_are_skipped() {
+        let methods = parse(b"echo unary\nwatch telepathy\n").unwrap();
+        assert_eq!(methods, [MethodInfo { name: "echo".to_string(), kind: MethodKind::Unary }]);
+        assert!(parse(b"no-kind\n").is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Recording a session's traffic, and serving it back without the server.
+//!
+//! A recording is a sequence of chunks as they went over the connection,
+//! each a direction byte (`>` sent, `<` received), a 4-byte big-endian
+//! length and that many bytes. Replaying parses both directions into
+//! frames, so it needs the framing the session used throughout; record
+//! with the handshake off. Frames are rewritten on replay, so recordings
+//! of signed sessions do not replay.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::protocol::{Decoder, Framing, Message};
+use crate::status::{Code, Status};
+use crate::transport::Transport;
+
+const SENT: u8 = b'>';
+const RECEIVED: u8 = b'<';
+
+/// A [`Transport`] that passes everything through to another one and
+/// appends it to a recording file, e.g. to capture what a production
+/// server answers for [`ReplayTransport`] to serve offline.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    log: Arc<Mutex<File>>,
+}
+
+impl RecordingTransport {
+    /// Records to `path`, appending to what is there. Each connection
+    /// should have a file of its own.
+    pub fn new<T: Transport + 'static>(inner: T, path: impl AsRef<Path>) -> io::Result<Self> {
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecordingTransport { inner: Box::new(inner), log: Arc::new(Mutex::new(log)) })
+    }
+
+    fn record(&self, direction: u8, bytes: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(bytes.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "chunk too large to record"))?;
+        let mut chunk = Vec::with_capacity(5 + bytes.len());
+        chunk.push(direction);
+        chunk.extend_from_slice(&len.to_be_bytes());
+        chunk.extend_from_slice(bytes);
+        self.log.lock().unwrap_or_else(PoisonError::into_inner).write_all(&chunk)
+    }
+}
+
+impl Read for RecordingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(RECEIVED, &buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl Write for RecordingTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(SENT, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(RecordingTransport { inner: self.inner.try_clone()?, log: self.log.clone() }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+/// Plays the server of a recording made with [`RecordingTransport`].
+///
+/// Each request written is matched to the next recorded request for the
+/// same method, in the order they were recorded, and answered with the
+/// frames recorded for it under the new request ID. A request for which
+/// none is left is answered with [`Code::Unavailable`].
+pub struct ReplayTransport {
+    state: Arc<Mutex<Replay>>,
+}
+
+struct Replay {
+    framing: Framing,
+    /// Recorded request IDs by method, in the order they were sent.
+    requests: HashMap<String, VecDeque<String>>,
+    /// Recorded frames from the server, by request ID.
+    responses: HashMap<String, VecDeque<Message>>,
+    /// Request IDs of this session already answered.
+    answered: HashSet<String>,
+    written: Decoder,
+    readable: VecDeque<u8>,
+    closed: bool,
+}
+
+impl ReplayTransport {
+    pub fn open(path: impl AsRef<Path>, framing: Framing) -> io::Result<Self> {
+        let recording = fs::read(path)?;
+        let (mut sent, mut received) = (Vec::new(), Vec::new());
+        let mut rest = &recording[..];
+        while !rest.is_empty() {
+            let malformed = || io::Error::new(io::ErrorKind::InvalidData, "truncated recording");
+            let (header, body) = rest.split_at_checked(5).ok_or_else(malformed)?;
+            let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            let (chunk, after) = body.split_at_checked(len).ok_or_else(malformed)?;
+            match header[0] {
+                SENT => sent.extend_from_slice(chunk),
+                RECEIVED => received.extend_from_slice(chunk),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown direction in recording")),
+            }
+            rest = after;
+        }
+
+        let mut requests: HashMap<String, VecDeque<String>> = HashMap::new();
+        for request in frames(framing, &sent) {
+            let ids = requests.entry(request.method_name.clone()).or_default();
+            if !request.is_notification() && !ids.contains(&request.request_id) {
+                ids.push_back(request.request_id);
+            }
+        }
+        let mut responses: HashMap<String, VecDeque<Message>> = HashMap::new();
+        for response in frames(framing, &received) {
+            responses.entry(response.request_id.clone()).or_default().push_back(response);
+        }
+
+        let replay = Replay {
+            framing,
+            requests,
+            responses,
+            answered: HashSet::new(),
+            written: Decoder::new(framing, None),
+            readable: VecDeque::new(),
+            closed: false,
+        };
+        Ok(ReplayTransport { state: Arc::new(Mutex::new(replay)) })
+    }
+
+    fn state(&self) -> MutexGuard<'_, Replay> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The whole frames in `bytes`; a frame cut off at the end is left out.
+fn frames(framing: Framing, bytes: &[u8]) -> Vec<Message> {
+    let mut decoder = Decoder::new(framing, None);
+    decoder.feed(bytes);
+    let mut frames = Vec::new();
+    while let Ok(Some(frame)) = decoder.decode() {
+        frames.push(frame);
+    }
+    frames
+}
+
+impl Replay {
+    /// Answers the frames completed by what was written so far.
+    fn answer(&mut self) {
+        loop {
+            let Ok(Some(frame)) = self.written.decode() else { return };
+            // Later frames of an upload, and notifications, get no answer.
+            if frame.is_notification() || !self.answered.insert(frame.request_id.clone()) {
+                continue;
+            }
+
+            let recorded = self.requests.get_mut(&frame.method_name).and_then(VecDeque::pop_front);
+            let responses = recorded.and_then(|id| self.responses.remove(&id)).unwrap_or_default();
+            let mut answer = Vec::new();
+            for response in responses {
+                answer.extend_from_slice(&self.framing.encode(&Message { request_id: frame.request_id.clone(), ..response }));
+            }
+            if answer.is_empty() {
+                let missing = Status::new(Code::Unavailable, format!("no recorded call to {} left", frame.method_name));
+                answer.extend_from_slice(&self.framing.encode(&frame.reply(&frame.method_name, Err(missing))));
+            }
+            self.readable.extend(answer);
+        }
+    }
+}
+
+impl Read for ReplayTransport {
+    /// Fails as a timed-out read would when nothing is left to answer.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.readable.is_empty() {
+            return match state.closed {
+                true => Ok(0),
+                false => Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing recorded to answer with")),
+            };
+        }
+        let n = buf.len().min(state.readable.len());
+        for (byte, recorded) in buf.iter_mut().zip(state.readable.drain(..n)) {
+            *byte = recorded;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.written.feed(buf);
+        state.answer();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(ReplayTransport { state: self.state.clone() }))
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.state().closed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Error, Server};
+    use bytes::Bytes;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    #[test]
+    fn recorded_sessions_replay_without_the_server() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("lookup", |_| Err(Status::new(Code::NotFound, "no such user")));
+        server.register_stream("count", |_, sink| (1..=3).try_for_each(|i| sink.send(i.to_string().as_bytes())));
+        thread::spawn(move || server.run());
+
+        let recording = std::env::temp_dir().join(format!("unixconn-{}.rec", uuid::Uuid::new_v4()));
+        let (dial, log) = (path.clone(), recording.clone());
+        let mut client = Client::builder("recorded")
+            .timeout(Some(Duration::from_secs(5)))
+            .transport(move || RecordingTransport::new(UnixStream::connect(&dial)?, &log))
+            .connect()
+            .unwrap();
+        client.do_request("echo", b"first").unwrap();
+        client.do_request("echo", b"second").unwrap();
+        assert!(client.do_request("lookup", b"carol").is_err());
+        assert_eq!(client.do_request_stream("count", b"").unwrap().count(), 3);
+        drop(client);
+        std::fs::remove_file(&path).unwrap();
+
+        let replayed = recording.clone();
+        let mut client = Client::builder("replayed").transport(move || ReplayTransport::open(&replayed, Framing::default())).connect().unwrap();
+        assert_eq!(&client.do_request("echo", b"anything").unwrap()[..], b"first");
+        assert_eq!(&client.do_request("echo", b"").unwrap()[..], b"second");
+        assert!(matches!(client.do_request("lookup", b""), Err(Error::RemoteError { code: Code::NotFound, .. })));
+        let counted: Vec<_> = client.do_request_stream("count", b"").unwrap().map(|chunk| chunk.unwrap()).collect();
+        assert_eq!(counted, [b"1", b"2", b"3"].map(|chunk| Bytes::from(&chunk[..])));
+        assert!(matches!(client.do_request("echo", b""), Err(Error::RemoteError { code: Code::Unavailable, .. })));
+
+        std::fs::remove_file(&recording).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// Decides whether and when a failed request is attempted again.
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`. With
+/// jitter on, each delay is drawn uniformly from its upper half so that
+/// clients failing together do not retry in lockstep.
+///
+/// A timed-out request may already have been executed by the server, so
+/// only retry on timeouts for methods that are safe to repeat.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    retryable: Arc<dyn Fn(&Error) -> bool + Send + Sync>,
+}
+
+impl RetryPolicy {
+    /// Allows up to `max_attempts` tries in total, including the first one.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+            jitter: true,
+            retryable: Arc::new(is_transient),
+        }
+    }
+
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    pub fn jitter(mut self, enabled: bool) -> Self {
+        self.jitter = enabled;
+        self
+    }
+
+    /// Replaces the default choice of retryable errors, which is
+    /// [`is_transient`].
+    pub fn retry_if<F>(mut self, retryable: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Returns how long to wait before the next try, or `None` to give up
+    /// after `attempt` tries failed with `error`.
+    pub(crate) fn backoff(&self, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts || !(self.retryable)(error) {
+            return None;
+        }
+        Some(self.delay(attempt))
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(1 << (attempt - 1).min(31))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        if !self.jitter {
+            return delay;
+        }
+
+        let half = delay / 2;
+        let random = RandomState::new().build_hasher().finish();
+        half + half.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(3)
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Timeouts and errors from a connection that went away or is not up yet,
+/// including a socket path that does not exist yet.
+pub fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Timeout => true,
+        Error::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::NotFound
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy::new(10)
+            .base_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(50))
+            .jitter(false);
+
+        let delays: Vec<_> = (1..5).map(|attempt| policy.backoff(attempt, &Error::Timeout).unwrap()).collect();
+
+        assert_eq!(delays, [10, 20, 40, 50].map(Duration::from_millis));
+        assert_eq!(policy.backoff(10, &Error::Timeout), None);
+    }
+
+    #[test]
+    fn jitter_stays_within_the_upper_half() {
+        let policy = RetryPolicy::new(2).base_delay(Duration::from_millis(100));
+
+        for _ in 0..100 {
+            let delay = policy.backoff(1, &Error::Timeout).unwrap();
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn remote_errors_are_not_retried_by_default() {
+        let error = Error::RemoteError { code: crate::Code::Internal, message: String::new() };
+
+        assert_eq!(RetryPolicy::default().backoff(1, &error), None);
+    }
+}
@@ -0,0 +1,165 @@
+//! Unix sockets of type SOCK_SEQPACKET, which keep message boundaries.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::transport::Transport;
+
+/// A connected SOCK_SEQPACKET unix socket. Every write is sent as one
+/// packet, and the peer receives it whole or not at all, so a frame can
+/// never arrive split or run into the next one.
+///
+/// Reads hand out one packet after another as a byte stream, so any
+/// framing works on top; the server must send each frame as its own packet.
+pub struct SeqpacketStream {
+    // As for vsock, UnixStream only owns the descriptor and provides the
+    // family-independent timeout, shutdown and dup calls; data goes through
+    // send(2) and recv(2) to keep packet boundaries.
+    inner: UnixStream,
+    /// What is left of the packet being read.
+    packet: Vec<u8>,
+    read: usize,
+}
+
+impl SeqpacketStream {
+    pub fn connect(path: &str) -> io::Result<Self> {
+        // SAFETY: plain socket(2) call; the result is checked before use.
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and is owned by nothing else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let (address, len) = sockaddr_un(path)?;
+        // SAFETY: `address` is a live sockaddr_un and `len` does not exceed it.
+        if unsafe { libc::connect(fd.as_raw_fd(), &address as *const libc::sockaddr_un as *const libc::sockaddr, len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SeqpacketStream { inner: UnixStream::from(fd), packet: Vec::new(), read: 0 })
+    }
+
+    /// Receives the next packet whole, sizing the buffer by peeking first.
+    fn recv_packet(&mut self) -> io::Result<()> {
+        let fd = self.inner.as_raw_fd();
+        // SAFETY: a zero-length peek writes nothing; MSG_TRUNC makes it
+        // return the full length of the waiting packet.
+        let len = cvt(unsafe { libc::recv(fd, std::ptr::null_mut(), 0, libc::MSG_PEEK | libc::MSG_TRUNC) })?;
+        self.packet.resize(len, 0);
+        // SAFETY: the buffer holds `len` writable bytes.
+        let received = cvt(unsafe { libc::recv(fd, self.packet.as_mut_ptr().cast(), len, 0) })?;
+        self.packet.truncate(received);
+        self.read = 0;
+        Ok(())
+    }
+}
+
+impl Read for SeqpacketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read == self.packet.len() {
+            self.recv_packet()?;
+        }
+        let n = buf.len().min(self.packet.len() - self.read);
+        buf[..n].copy_from_slice(&self.packet[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+impl Write for SeqpacketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: `buf` is valid for reads of its length.
+        cvt(unsafe { libc::send(self.inner.as_raw_fd(), buf.as_ptr().cast(), buf.len(), libc::MSG_NOSIGNAL) })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for SeqpacketStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(SeqpacketStream { inner: self.inner.try_clone()?, packet: Vec::new(), read: 0 }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown(Shutdown::Both)
+    }
+}
+
+fn sockaddr_un(path: &str) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    // SAFETY: sockaddr_un is plain data, valid when zeroed.
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // Leave room for the terminating NUL, which zeroing already put there.
+    if path.len() >= address.sun_path.len() || path.contains('\0') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "socket path too long or contains NUL"));
+    }
+    for (dst, &src) in address.sun_path.iter_mut().zip(path.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+    Ok((address, len as libc::socklen_t))
+}
+
+fn cvt(result: isize) -> io::Result<usize> {
+    match result {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Framing;
+    use crate::test_support::socket_path;
+    use crate::Client;
+    use std::thread;
+
+    #[test]
+    fn frames_travel_one_per_packet() {
+        let path = socket_path();
+        // SAFETY: as in `connect`, with bind, listen and accept in place of
+        // connect; every result is checked.
+        let listener = unsafe {
+            let fd = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0);
+            assert!(fd >= 0);
+            let (address, len) = sockaddr_un(&path).unwrap();
+            assert_eq!(libc::bind(fd, &address as *const libc::sockaddr_un as *const libc::sockaddr, len), 0);
+            assert_eq!(libc::listen(fd, 1), 0);
+            OwnedFd::from_raw_fd(fd)
+        };
+        thread::spawn(move || {
+            // SAFETY: accept(2) on the listening socket; checked below.
+            let fd = unsafe { libc::accept(listener.as_raw_fd(), std::ptr::null_mut(), std::ptr::null_mut()) };
+            assert!(fd >= 0);
+            // SAFETY: `fd` is a fresh connection owned by nothing else.
+            let inner = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            let mut conn = SeqpacketStream { inner, packet: Vec::new(), read: 0 };
+            let mut reader = io::BufReader::new(conn.try_clone().unwrap());
+            while let Ok(request) = Framing::LengthPrefixed.read(&mut reader) {
+                conn.write_all(&Framing::LengthPrefixed.encode(&request)).unwrap();
+            }
+        });
+
+        let mut client = Client::connect_seqpacket(&path, 5, Framing::LengthPrefixed).unwrap();
+        let body = vec![0x1F; 100_000];
+        assert_eq!(client.do_request("echo", &body).unwrap(), body);
+        assert_eq!(&client.do_request("echo", b"small").unwrap()[..], b"small");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
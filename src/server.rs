@@ -0,0 +1,1150 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Write};
+use std::net::Shutdown;
+use std::os::fd::AsFd;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::activation;
+use crate::error::{Error, Result};
+use crate::pubsub::{split_publish_body, Topics, PUBLISH, SUBSCRIBE};
+use crate::protocol::{Framing, Message, PING, STREAM_END, UNSUBSCRIBE};
+use crate::status::{Code, Status};
+use crate::codec::Codec;
+use crate::compression::{self, Compression, Compressor};
+use crate::context::RequestContext;
+#[cfg(target_os = "linux")]
+use crate::credentials::PeerCredentials;
+#[cfg(target_os = "linux")]
+use crate::event_loop;
+use crate::fds;
+use crate::duplex::DuplexSession;
+use crate::handshake::{self, HANDSHAKE};
+use crate::limits::{Limit, Overload};
+use crate::middleware::{Middleware, Outcome};
+use crate::info::{self, INFO};
+use crate::reflection::{self, MethodKind, METHODS};
+use crate::service::Service;
+use crate::shutdown::ShutdownHandle;
+use crate::signing::{Integrity, SigningKey};
+use crate::stream::{ConnWriter, Incoming, ResponseSink, UploadReader};
+use crate::subscription::Subscriber;
+use crate::transport::{bind_unix, bind_unix_with, SocketFile, SocketPermissions};
+
+type UnaryHandler = Box<dyn Fn(&RequestContext<'_>, &[u8]) -> Result<Vec<u8>, Status> + Send + Sync>;
+
+type StreamHandler = Box<dyn Fn(&[u8], &mut ResponseSink<'_>) -> Result<(), Status> + Send + Sync>;
+
+type UploadHandler = Box<dyn Fn(&mut UploadReader<'_>) -> Result<Vec<u8>, Status> + Send + Sync>;
+
+type DuplexHandler = Box<dyn Fn(&mut DuplexSession<'_>) -> Result<(), Status> + Send + Sync>;
+
+type SubscriptionHandler = Box<dyn Fn(&[u8], Subscriber) -> Result<(), Status> + Send + Sync>;
+
+type ErrorObserver = Box<dyn Fn(&Error) + Send + Sync>;
+
+enum Handler {
+    Unary(UnaryHandler),
+    Stream(StreamHandler),
+    Upload(UploadHandler),
+    Duplex(DuplexHandler),
+    Subscription(SubscriptionHandler),
+}
+
+impl Handler {
+    fn kind(&self) -> MethodKind {
+        match self {
+            Handler::Unary(_) => MethodKind::Unary,
+            Handler::Stream(_) => MethodKind::Stream,
+            Handler::Upload(_) => MethodKind::Upload,
+            Handler::Duplex(_) => MethodKind::Duplex,
+            Handler::Subscription(_) => MethodKind::Subscription,
+        }
+    }
+}
+
+/// Serves the unixconn protocol on a unix socket, dispatching each request
+/// to the handler registered for its method name.
+pub struct Server {
+    listener: Option<UnixListener>,
+    /// Where to connect to reach the listener, when that is not its own
+    /// address.
+    listening_at: Option<SocketAddr>,
+    socket_file: Option<SocketFile>,
+    handlers: HashMap<String, Handler>,
+    fallback: Option<Handler>,
+    workers: Limit,
+    pub(crate) framing: Framing,
+    compression: Vec<Compression>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) integrity: Integrity,
+    middleware: Vec<Box<dyn Middleware>>,
+    error_observers: Vec<ErrorObserver>,
+    pub(crate) shutdown: ShutdownHandle,
+    pub(crate) connections: Limit,
+    requests: Limit,
+    pub(crate) overload: Overload,
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+/// How long a connection turned away at the connection limit has to send
+/// the request that is answered with the refusal.
+const TURN_AWAY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the accept loop waits before accepting again when the process is
+/// out of descriptors.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+impl Server {
+    /// Listens on the unix socket at `address`. On Linux, an address
+    /// starting with `@` is a name in the abstract namespace rather than a
+    /// path, which leaves no stale socket file when the server exits.
+    ///
+    /// A socket file is removed again when the server is dropped. One left
+    /// by a server that crashed is taken over, while binding the path of a
+    /// server still running fails with [`io::ErrorKind::AddrInUse`].
+    pub fn bind(address: &str) -> Result<Self> {
+        let mut server = Server::from_listener(bind_unix(address)?);
+        server.socket_file = SocketFile::claim(address);
+        Ok(server)
+    }
+
+    /// Like [`Server::bind`], with the socket file's mode and ownership set
+    /// to `permissions` before any client can connect, rather than changed
+    /// afterwards.
+    pub fn bind_with_permissions(address: &str, permissions: &SocketPermissions) -> Result<Self> {
+        let mut server = Server::from_listener(bind_unix_with(address, permissions)?);
+        server.listening_at = Some(SocketAddr::from_pathname(address)?);
+        server.socket_file = SocketFile::claim(address);
+        Ok(server)
+    }
+
+    /// Accepts connections on a listener bound elsewhere, e.g. inherited
+    /// from a parent process.
+    pub fn from_listener(listener: UnixListener) -> Self {
+        let mut server = Server::new();
+        server.listener = Some(listener);
+        server
+    }
+
+    /// Accepts connections on the socket systemd bound for the service when
+    /// it was socket activated, or returns `None` if it was not, so it can
+    /// bind its own:
+    ///
+    /// ```no_run
+    /// # use unixconn_rust::Server;
+    /// let server = match Server::from_systemd()? {
+    ///     Some(server) => server,
+    ///     None => Server::bind("/run/agent.sock")?,
+    /// };
+    /// # Ok::<(), unixconn_rust::Error>(())
+    /// ```
+    ///
+    /// The unit must pass exactly one socket. Call this early, before other
+    /// threads start, as it reads and clears `LISTEN_FDS` and its siblings.
+    pub fn from_systemd() -> Result<Option<Self>> {
+        Ok(activation::listener()?.map(Server::from_listener))
+    }
+
+    /// A server without a socket of its own, which only serves connections
+    /// handed to [`Server::serve`].
+    pub fn new() -> Self {
+        Server {
+            listener: None,
+            listening_at: None,
+            socket_file: None,
+            handlers: HashMap::new(),
+            fallback: None,
+            workers: Limit::default(),
+            framing: Framing::default(),
+            compression: Vec::new(),
+            max_message_size: None,
+            integrity: Integrity::default(),
+            middleware: Vec::new(),
+            error_observers: Vec::new(),
+            shutdown: ShutdownHandle::default(),
+            connections: Limit::default(),
+            requests: Limit::default(),
+            overload: Overload::default(),
+            idle_timeout: None,
+        }
+    }
+
+    /// Sets how many unary and subscription handlers run at once across all
+    /// connections; by default there is no bound. Every connection
+    /// [`Server::run`] accepts is served on a thread of its own, so an open
+    /// connection never keeps another from being read; a request arriving
+    /// while all workers are busy waits for one to finish its handler.
+    /// Stream, upload and duplex handlers last as long as their client keeps
+    /// the call going, so they take no worker.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers.set_max(Some(workers));
+    }
+
+    /// Bounds how many connections are served at once, across
+    /// [`Server::run`] and calls to [`Server::serve`]; the others are dealt
+    /// with as [`Server::set_overload`] says. `None`, the default, serves
+    /// every connection accepted.
+    pub fn set_max_connections(&mut self, max: Option<usize>) {
+        self.connections.set_max(max);
+    }
+
+    /// Bounds how many handlers run at once across all connections; further
+    /// requests are dealt with as [`Server::set_overload`] says. Middleware
+    /// runs first, so refused requests take no slot.
+    pub fn set_max_concurrent_requests(&mut self, max: Option<usize>) {
+        self.requests.set_max(max);
+    }
+
+    /// Sets whether connections and requests over their limits wait for a
+    /// slot, the default, or are refused.
+    pub fn set_overload(&mut self, overload: Overload) {
+        self.overload = overload;
+    }
+
+    /// Hangs up on clients that send nothing for `timeout` between
+    /// requests, so ones that leaked or abandoned their connection do not
+    /// hold its descriptor forever. Time spent in handlers does not count,
+    /// nor does a connection with an open subscription idle. `None`, the
+    /// default, waits indefinitely.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Sets the wire format expected from clients; it must match theirs.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Bounds the size of a request frame. A client sending a longer one is
+    /// disconnected without reading the rest of it; a compressed body that
+    /// restores to more is answered with [`Code::ResourceExhausted`].
+    /// `None`, the default, accepts any size.
+    pub fn set_max_message_size(&mut self, max_len: Option<usize>) {
+        self.max_message_size = max_len;
+    }
+
+    /// When enabled, every frame sent carries a CRC32 of its body, and
+    /// requests carrying one are verified; a client whose body fails the
+    /// check is disconnected. See [`crate::Client::set_checksums`].
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.integrity.checksums = enabled;
+    }
+
+    /// Signs every frame sent with HMAC-SHA256 under `key`, and disconnects
+    /// clients sending a request that is not signed with it, before any
+    /// handler sees the request. See [`crate::Client::set_signing_key`].
+    pub fn set_signing_key(&mut self, key: &[u8]) {
+        self.integrity.key = Some(SigningKey::new(key));
+    }
+
+    /// Accepts request bodies compressed with `compressor`, and compresses
+    /// large responses to clients that configured the same codec with
+    /// [`crate::ClientBuilder::compression`]. Several codecs may be added;
+    /// each client is answered with the one it asked for.
+    pub fn add_compression(&mut self, compressor: Arc<dyn Compressor>) {
+        self.compression.push(Compression(compressor));
+    }
+
+    /// Runs `middleware` around the handler of every request, inside any
+    /// added earlier; see [`Middleware`] for the order hooks run in.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Calls `observer` with every error that ends the serving of a
+    /// connection, or that keeps the server from accepting one, e.g. to log
+    /// it. Without observers such errors go unreported.
+    pub fn on_error<F>(&mut self, observer: F)
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.error_observers.push(Box::new(observer));
+    }
+
+    /// Registers `handler` to answer requests for `method_name`. It may
+    /// fail with a [`Status`], or with a plain message, sent as
+    /// [`Code::Unknown`].
+    pub fn register<F, E>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>, E> + Send + Sync + 'static,
+        E: Into<Status>,
+    {
+        self.register_with_context(method_name, move |_, body| handler(body).map_err(Into::into));
+    }
+
+    /// Answers requests for every method without a handler of its own,
+    /// e.g. for a stand-in server that echoes whatever it is sent. The
+    /// handler reads the method name from the [`RequestContext`]. Reserved
+    /// methods, starting with `__`, never reach it.
+    pub fn register_fallback<F>(&mut self, handler: F)
+    where
+        F: Fn(&RequestContext<'_>, &[u8]) -> Result<Vec<u8>, Status> + Send + Sync + 'static,
+    {
+        self.fallback = Some(Handler::Unary(Box::new(handler)));
+    }
+
+    /// Like [`Server::register`], for handlers that also look at the
+    /// request's [`RequestContext`], e.g. to skip work the caller will no
+    /// longer wait for. Requests whose deadline passed while queued are
+    /// answered with [`Code::DeadlineExceeded`] without calling the handler.
+    pub fn register_with_context<F>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(&RequestContext<'_>, &[u8]) -> Result<Vec<u8>, Status> + Send + Sync + 'static,
+    {
+        self.handlers.insert(method_name.to_string(), Handler::Unary(Box::new(handler)));
+    }
+
+    /// Registers a handler taking and returning typed values, with `codec`
+    /// decoding the request body and encoding the response. A body that does
+    /// not decode is answered with [`Code::InvalidArgument`].
+    pub fn register_typed<C, Req, Resp, F>(&mut self, method_name: &str, codec: C, handler: F)
+    where
+        C: Codec<Req> + Codec<Resp> + Send + Sync + 'static,
+        F: Fn(Req) -> Result<Resp, Status> + Send + Sync + 'static,
+    {
+        self.register(method_name, move |body| {
+            let request = Codec::<Req>::decode(&codec, body).map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
+            let response = handler(request)?;
+            codec.encode(&response).map_err(|e| Status::new(Code::Internal, format!("encoding response: {}", e)))
+        });
+    }
+
+    /// Registers all handlers of `service`, e.g. an implementation of a trait
+    /// declared with [`crate::service!`].
+    pub fn register_service<S: Service>(&mut self, service: S) {
+        service.register(self);
+    }
+
+    /// Registers a handler that answers with any number of body frames, sent
+    /// through the sink as they are produced. The stream ends when the
+    /// handler returns; an error is delivered to the client after the frames
+    /// already sent. Clients read these with [`crate::Client::do_request_stream`].
+    pub fn register_stream<F>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(&[u8], &mut ResponseSink<'_>) -> Result<(), Status> + Send + Sync + 'static,
+    {
+        self.handlers.insert(method_name.to_string(), Handler::Stream(Box::new(handler)));
+    }
+
+    /// Registers a handler for requests uploaded in chunks by
+    /// [`crate::Client::do_request_streamed`]. The handler reads the body
+    /// as it arrives; whatever it leaves unread is discarded.
+    pub fn register_upload<F>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(&mut UploadReader<'_>) -> Result<Vec<u8>, Status> + Send + Sync + 'static,
+    {
+        self.handlers.insert(method_name.to_string(), Handler::Upload(Box::new(handler)));
+    }
+
+    /// Registers a handler for bidirectional calls opened with
+    /// [`crate::Client::open_duplex`]. The handler receives and sends frames
+    /// through the session in whatever order its exchange needs; returning
+    /// ends the call, with the error if any delivered after the frames sent.
+    pub fn register_duplex<F>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(&mut DuplexSession<'_>) -> Result<(), Status> + Send + Sync + 'static,
+    {
+        self.handlers.insert(method_name.to_string(), Handler::Duplex(Box::new(handler)));
+    }
+
+    /// Registers a handler for [`crate::Client::subscribe`]. The handler is
+    /// given a [`Subscriber`] to keep for pushing messages later, from any
+    /// thread; the connection goes on serving requests meanwhile. An error
+    /// refuses the subscription.
+    pub fn register_subscription<F>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(&[u8], Subscriber) -> Result<(), Status> + Send + Sync + 'static,
+    {
+        self.handlers.insert(method_name.to_string(), Handler::Subscription(Box::new(handler)));
+    }
+
+    /// Serves `topics` to clients, which publish with
+    /// [`crate::Client::publish`] and subscribe with
+    /// [`crate::Client::subscribe_topic`].
+    pub fn register_topics(&mut self, topics: &Topics) {
+        let publisher = topics.clone();
+        self.register(PUBLISH, move |body| -> Result<Vec<u8>, Status> {
+            let (topic, payload) = split_publish_body(body)?;
+            Ok(publisher.publish(topic, payload).to_string().into_bytes())
+        });
+        let topics = topics.clone();
+        self.register_subscription(SUBSCRIBE, move |body, subscriber| {
+            let topic = std::str::from_utf8(body).map_err(|_| Status::new(Code::InvalidArgument, "topic name is not utf-8"))?;
+            topics.subscribe(topic, subscriber);
+            Ok(())
+        });
+    }
+
+    /// Accepts connections until shut down, serving each on a thread of its
+    /// own, and returns once the last of them is done.
+    pub fn run(&self) -> Result<()> {
+        let listener = self.listener()?;
+        self.shutdown.listening(self.address(listener)?, 1);
+        if self.shutdown.is_stopping() {
+            return Ok(());
+        }
+        Ok(thread::scope(|scope| self.accept_loop(scope, listener))?)
+    }
+
+    /// Serves every connection from the calling thread, which waits on all
+    /// of them at once with epoll, in place of [`Server::run`] and its
+    /// thread per connection; for daemons answering cheap lookups.
+    ///
+    /// Only unary methods and notifications are served: calls to stream,
+    /// upload, duplex and subscription handlers are refused with
+    /// [`Code::Unimplemented`], and descriptors are neither received with
+    /// requests nor sent with responses. A handler holds up every
+    /// connection while it runs. Connections over
+    /// [`Server::set_max_connections`] are left unread until another
+    /// closes, or turned away as with [`Overload::Reject`].
+    #[cfg(target_os = "linux")]
+    pub fn run_event_loop(&self) -> Result<()> {
+        let listener = self.listener()?;
+        self.shutdown.listening(self.address(listener)?, 1);
+        if self.shutdown.is_stopping() {
+            return Ok(());
+        }
+        Ok(event_loop::run(self, listener)?)
+    }
+
+    /// Stops the server as [`ShutdownHandle::shutdown`] does, for servers
+    /// shared with the threads running them.
+    pub fn shutdown(&self, grace: Duration) {
+        self.shutdown.shutdown(grace);
+    }
+
+    /// A handle that can stop the server once it has been moved to the
+    /// thread running it.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    fn listener(&self) -> io::Result<&UnixListener> {
+        self.listener
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "server has no socket to accept on"))
+    }
+
+    /// Where clients reach `listener`, for shutting down to connect to.
+    fn address(&self, listener: &UnixListener) -> io::Result<SocketAddr> {
+        match &self.listening_at {
+            Some(address) => Ok(address.clone()),
+            None => listener.local_addr(),
+        }
+    }
+
+    fn accept_loop<'scope>(&'scope self, scope: &'scope thread::Scope<'scope, '_>, listener: &UnixListener) -> io::Result<()> {
+        loop {
+            let conn = match listener.accept() {
+                Ok((conn, _)) => conn,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Dropped by the client before it was accepted.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                // Descriptors are freed as connections close, so accepting
+                // is tried again after a while.
+                Err(e) if is_out_of_descriptors(&e) => {
+                    self.report_error(&e.into());
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if self.shutdown.is_stopping() {
+                return Ok(());
+            }
+            let serving = thread::Builder::new().name("unixconn-conn".into()).spawn_scoped(scope, move || {
+                if let Err(e) = self.serve(conn) {
+                    self.report_error(&e);
+                }
+            });
+            // The connection is dropped, and so closed, with the closure.
+            if let Err(e) = serving {
+                self.report_error(&e.into());
+            }
+        }
+    }
+
+    /// Hands `error` to the observers added with [`Server::on_error`].
+    pub(crate) fn report_error(&self, error: &Error) {
+        for observer in &self.error_observers {
+            observer(error);
+        }
+    }
+
+    /// Serves one connected socket until the client hangs up, such as one
+    /// end of [`UnixStream::pair`].
+    pub fn serve(&self, conn: UnixStream) -> Result<()> {
+        let _tracked = self.shutdown.track(&conn)?;
+        let _permit = match self.connections.acquire(self.overload) {
+            Some(permit) => permit,
+            None => return self.turn_away(conn),
+        };
+        let writer = Arc::new(ConnWriter::new(conn.try_clone()?, self.framing, self.integrity.clone()));
+        thread::scope(|scope| {
+            let incoming = Incoming::spawn(scope, &conn, self.framing, self.max_message_size, self.integrity.clone());
+            let result = self.serve_connection(incoming, &writer);
+            // Subscribers may still hold the connection; shutting it down
+            // makes their next push fail instead of going nowhere, and stops
+            // the read-ahead thread.
+            let _ = conn.shutdown(Shutdown::Both);
+            result
+        })
+    }
+
+    /// Answers the first request on a connection over the connection limit
+    /// with [`Code::Unavailable`] and hangs up. A client sending nothing
+    /// within [`TURN_AWAY_TIMEOUT`] is hung up on without an answer.
+    fn turn_away(&self, mut conn: UnixStream) -> Result<()> {
+        conn.set_read_timeout(Some(TURN_AWAY_TIMEOUT))?;
+        let request = match self.framing.read_limited(&mut BufReader::new(&conn), self.max_message_size) {
+            Ok(request) => request,
+            Err(_) => return Ok(()),
+        };
+        self.integrity.verify(&request)?;
+        if request.is_notification() {
+            return Ok(());
+        }
+        let busy = Status::new(Code::Unavailable, "server is at its connection limit");
+        if let Some(reply) = self.refusal(&request, busy) {
+            conn.write_all(&self.framing.encode(&self.integrity.seal(&reply)))?;
+        }
+        Ok(())
+    }
+
+    fn serve_connection(&self, incoming: Incoming, writer: &Arc<ConnWriter>) -> Result<()> {
+        let mut subscriptions: HashMap<String, Subscriber> = HashMap::new();
+        let mut first = true;
+        loop {
+            subscriptions.retain(|_, subscriber| !subscriber.is_closed());
+            let idle_timeout = self.idle_timeout.filter(|_| subscriptions.is_empty());
+            let (mut request, received) = match incoming.next_received_within(idle_timeout) {
+                Ok(Some(received)) => received,
+                Ok(None) => return Ok(()),
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            if request.method_name == HANDSHAKE {
+                self.serve_handshake(&request, first, writer)?;
+                first = false;
+                continue;
+            }
+            first = false;
+
+            if let Err(e) = compression::decode(&mut request, &self.compression, self.max_message_size) {
+                if !request.is_notification() {
+                    writer.send(&request.reply(&request.method_name, Err(undecodable(&e))))?;
+                }
+                incoming.finish(&request.request_id);
+                continue;
+            }
+
+            // Answered ahead of middleware and limits: it checks that the
+            // server is alive, not what the caller may do.
+            if request.method_name == PING {
+                if !request.is_notification() {
+                    writer.send(&request.reply(PING, Ok(Bytes::new())))?;
+                }
+                incoming.finish(&request.request_id);
+                continue;
+            }
+
+            if request.method_name == UNSUBSCRIBE {
+                if let Some(subscriber) = subscriptions.remove(&request.request_id) {
+                    subscriber.close(Ok(()));
+                }
+                continue;
+            }
+
+            let started = Instant::now();
+            let context = RequestContext::new(&request, &incoming, received);
+            let mut passed = 0;
+            let checked: Result<(), Status> = self.middleware.iter().try_for_each(|middleware| {
+                middleware.check(&context)?;
+                passed += 1;
+                Ok(())
+            });
+            if let Err(status) = checked {
+                self.refuse(&request, status.clone(), &incoming, writer)?;
+                self.report(passed, &context, Err(status), started);
+                incoming.finish(&request.request_id);
+                continue;
+            }
+
+            let _permit = match self.requests.acquire(self.overload) {
+                Some(permit) => permit,
+                None => {
+                    let busy = Status::new(Code::Unavailable, "server is at its request limit");
+                    self.refuse(&request, busy.clone(), &incoming, writer)?;
+                    self.report(passed, &context, Err(busy), started);
+                    incoming.finish(&request.request_id);
+                    continue;
+                }
+            };
+            let fallback = || self.fallback.as_ref().filter(|_| !request.method_name.starts_with("__"));
+            let handler = self.handlers.get(&request.method_name).or_else(fallback);
+            let _worker = match handler {
+                Some(Handler::Stream(_) | Handler::Upload(_) | Handler::Duplex(_)) => None,
+                _ => self.workers.acquire(Overload::Wait),
+            };
+            let served = match handler {
+                Some(Handler::Stream(handler)) if !request.is_notification() => {
+                    self.serve_stream(handler, &request, &incoming, writer)?.map(|_| 0)
+                }
+                Some(Handler::Duplex(handler)) if !request.is_notification() => {
+                    self.serve_duplex(handler, &request, &incoming, writer)?.map(|_| 0)
+                }
+                Some(Handler::Subscription(handler)) if !request.is_notification() => {
+                    let subscriber = Subscriber::new(writer.clone(), request.clone());
+                    match handler(&request.body, subscriber.clone()) {
+                        Ok(()) => {
+                            subscriptions.insert(request.request_id.clone(), subscriber);
+                            Ok(0)
+                        }
+                        Err(status) => {
+                            subscriber.close(Err(status.clone()));
+                            Err(status)
+                        }
+                    }
+                }
+                Some(Handler::Upload(handler)) => {
+                    let result = self.serve_upload(handler, &request, &incoming)?;
+                    let served = result.as_ref().map(Bytes::len).map_err(Status::clone);
+                    if !request.is_notification() {
+                        let mut response = request.reply(&request.method_name, result);
+                        compression::encode_response(&self.compression, &request, &mut response)?;
+                        writer.send(&response)?;
+                    }
+                    served
+                }
+                handler => {
+                    let result = self.call(handler, &request, &context);
+                    let served = result.as_ref().map(Bytes::len).map_err(Status::clone);
+                    let reply_fds = context.take_reply_fds();
+                    let fds: Vec<_> = reply_fds.iter().map(AsFd::as_fd).collect();
+                    if !request.is_notification() {
+                        let mut response = request.reply(&request.method_name, result);
+                        compression::encode_response(&self.compression, &request, &mut response)?;
+                        fds::mark(&mut response, fds.len());
+                        writer.lock().send_with_fds(&response, &fds)?;
+                    }
+                    served
+                }
+            };
+            self.report(passed, &context, served, started);
+            incoming.finish(&request.request_id);
+        }
+    }
+
+    /// Runs a unary handler, or answers a method that has none.
+    fn call(&self, handler: Option<&Handler>, request: &Message, context: &RequestContext<'_>) -> Result<Bytes, Status> {
+        match handler {
+            // Given up on while queued behind other requests.
+            _ if context.is_cancelled() => Err(Status::new(Code::Cancelled, "request cancelled by the client")),
+            Some(Handler::Unary(_)) if context.is_expired() => {
+                Err(Status::new(Code::DeadlineExceeded, "deadline passed before the request was handled"))
+            }
+            Some(Handler::Unary(handler)) => handler(context, &request.body).map(Bytes::from),
+            Some(_) => Ok(Bytes::new()),
+            None if request.method_name == METHODS => {
+                Ok(reflection::describe(self.handlers.iter().map(|(name, handler)| (name.as_str(), handler.kind()))))
+            }
+            None if request.method_name == INFO => {
+                let codecs: Vec<_> = self.compression.iter().map(Compression::name).collect();
+                Ok(info::describe(self.framing, &codecs))
+            }
+            None => Err(Status::new(Code::Unimplemented, format!("unknown method: {}", request.method_name))),
+        }
+    }
+
+    /// Answers a request read by the event loop, which serves unary calls
+    /// only, returning the reply if there is one and whether the frames
+    /// that follow under the request's ID are to be dropped unread.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn answer_polled(&self, mut request: Message, peer: Option<PeerCredentials>, received: Instant) -> Result<(Option<Message>, bool)> {
+        if let Err(e) = compression::decode(&mut request, &self.compression, self.max_message_size) {
+            return Ok(((!request.is_notification()).then(|| request.reply(&request.method_name, Err(undecodable(&e)))), false));
+        }
+        if request.method_name == PING {
+            return Ok(((!request.is_notification()).then(|| request.reply(PING, Ok(Bytes::new()))), false));
+        }
+
+        let started = Instant::now();
+        let context = RequestContext::polled(&request, peer, received);
+        let has_body_frames = matches!(self.handlers.get(&request.method_name), Some(Handler::Upload(_) | Handler::Duplex(_)));
+        let mut passed = 0;
+        let checked: Result<(), Status> = self.middleware.iter().try_for_each(|middleware| {
+            middleware.check(&context)?;
+            passed += 1;
+            Ok(())
+        });
+        if let Err(status) = checked {
+            self.report(passed, &context, Err(status.clone()), started);
+            return Ok((self.refusal(&request, status), has_body_frames));
+        }
+        let Some(_permit) = self.requests.acquire(Overload::Reject) else {
+            let busy = Status::new(Code::Unavailable, "server is at its request limit");
+            self.report(passed, &context, Err(busy.clone()), started);
+            return Ok((self.refusal(&request, busy), has_body_frames));
+        };
+
+        let fallback = || self.fallback.as_ref().filter(|_| !request.method_name.starts_with("__"));
+        let (reply, served) = match self.handlers.get(&request.method_name).or_else(fallback) {
+            handler @ (Some(Handler::Unary(_)) | None) => {
+                let result = self.call(handler, &request, &context);
+                let served = result.as_ref().map(Bytes::len).map_err(Status::clone);
+                let reply = match request.is_notification() {
+                    true => None,
+                    false => {
+                        let mut response = request.reply(&request.method_name, result);
+                        compression::encode_response(&self.compression, &request, &mut response)?;
+                        Some(response)
+                    }
+                };
+                (reply, served)
+            }
+            Some(_) => {
+                let unsupported = Status::new(Code::Unimplemented, format!("{} is not served by the event loop", request.method_name));
+                (self.refusal(&request, unsupported.clone()), Err(unsupported))
+            }
+        };
+        self.report(passed, &context, served, started);
+        Ok((reply, has_body_frames))
+    }
+
+    /// The frame refusing a request, shaped as its handler's failure would
+    /// be; `None` for a notification.
+    pub(crate) fn refusal(&self, request: &Message, status: Status) -> Option<Message> {
+        match self.handlers.get(&request.method_name) {
+            _ if request.is_notification() => None,
+            Some(Handler::Stream(_) | Handler::Duplex(_) | Handler::Subscription(_)) => Some(request.reply(STREAM_END, Err(status))),
+            _ => Some(request.reply(&request.method_name, Err(status))),
+        }
+    }
+
+    /// Runs the post hooks of the first `passed` middleware, innermost
+    /// first, with how the request was answered.
+    fn report(&self, passed: usize, context: &RequestContext<'_>, served: Result<usize, Status>, started: Instant) {
+        let outcome = Outcome {
+            status: served.as_ref().err(),
+            response_len: served.as_ref().map_or(0, |len| *len),
+            elapsed: started.elapsed(),
+        };
+        for middleware in self.middleware[..passed].iter().rev() {
+            middleware.after(context, &outcome);
+        }
+    }
+
+    /// Answers a handshake and switches the connection to the agreed
+    /// framing. Only the first frame on a connection may be one.
+    fn serve_handshake(&self, request: &Message, first: bool, writer: &ConnWriter) -> Result<()> {
+        let (reply, framing) = self.answer_handshake(request, first);
+        writer.send(&reply)?;
+        if let Some(framing) = framing {
+            writer.set_framing(framing);
+        }
+        Ok(())
+    }
+
+    /// The reply to a handshake, and the framing to switch to once it is
+    /// written if it was agreed.
+    pub(crate) fn answer_handshake(&self, request: &Message, first: bool) -> (Message, Option<Framing>) {
+        let answer = match first {
+            true => {
+                let codecs: Vec<_> = self.compression.iter().map(Compression::name).collect();
+                handshake::answer(&request.body, self.framing, &codecs)
+            }
+            false => Err(Status::new(Code::FailedPrecondition, "handshake must be the first request")),
+        };
+        match answer {
+            Ok((body, framing)) => (request.reply(HANDSHAKE, Ok(body)), Some(framing)),
+            Err(status) => (request.reply(HANDSHAKE, Err(status)), None),
+        }
+    }
+
+    /// Answers a request refused by middleware the way its handler's
+    /// failure would be, draining whatever the client sends after it.
+    fn refuse(&self, request: &Message, status: Status, incoming: &Incoming, writer: &ConnWriter) -> Result<()> {
+        let handler = self.handlers.get(&request.method_name);
+        if let Some(Handler::Upload(_)) = handler {
+            UploadReader::new(incoming, request).drain()?;
+        }
+        if let Some(reply) = self.refusal(request, status) {
+            writer.send(&reply)?;
+        }
+        if let Some(Handler::Duplex(_)) = handler.filter(|_| !request.is_notification()) {
+            UploadReader::without_body(incoming, request).drain()?;
+        }
+        Ok(())
+    }
+
+    fn serve_upload(&self, handler: &UploadHandler, request: &Message, incoming: &Incoming) -> Result<Result<Bytes, Status>> {
+        let mut upload = UploadReader::new(incoming, request);
+        let result = handler(&mut upload).map(Bytes::from);
+        upload.drain()?;
+        Ok(result)
+    }
+
+    /// Runs a stream handler and ends its stream, returning what the handler
+    /// did.
+    fn serve_stream(&self, handler: &StreamHandler, request: &Message, incoming: &Incoming, writer: &ConnWriter) -> Result<Result<(), Status>> {
+        let mut sink = ResponseSink::new(writer, incoming, request);
+        let result = handler(&request.body, &mut sink);
+        sink.into_result()?;
+
+        writer.send(&request.reply(STREAM_END, result.clone().map(|_| Bytes::new())))?;
+        Ok(result)
+    }
+
+    fn serve_duplex(&self, handler: &DuplexHandler, request: &Message, incoming: &Incoming, writer: &ConnWriter) -> Result<Result<(), Status>> {
+        let mut session = DuplexSession::new(incoming, writer, request);
+        let result = handler(&mut session);
+        let (mut incoming, outgoing) = session.into_parts();
+        outgoing.into_result()?;
+
+        // The end marker goes out first: the client may be waiting for it
+        // before closing its own side.
+        writer.send(&request.reply(STREAM_END, result.clone().map(|_| Bytes::new())))?;
+        incoming.drain()?;
+        Ok(result)
+    }
+}
+
+/// How a request whose body could not be decompressed is answered.
+fn undecodable(error: &Error) -> Status {
+    match error {
+        Error::MessageTooLarge { .. } => Status::new(Code::ResourceExhausted, error.to_string()),
+        _ => Status::new(Code::InvalidArgument, error.to_string()),
+    }
+}
+
+/// Whether accepting failed for want of a descriptor, in the process or
+/// the system.
+pub(crate) fn is_out_of_descriptors(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM))
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{testing, Client};
+    use std::io::Read;
+    use std::time::Duration;
+
+    fn spawn_server(path: &str, workers: usize) {
+        spawn_server_with(path, workers, Framing::default());
+    }
+
+    fn spawn_server_with(path: &str, workers: usize, framing: Framing) {
+        let mut server = Server::bind(path).unwrap();
+        server.set_workers(workers);
+        server.set_framing(framing);
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register("fail", |_| Err("boom".to_string()));
+        server.register("lookup", |_| Err(Status::new(Code::NotFound, "no such user")));
+        thread::spawn(move || server.run());
+    }
+
+    #[test]
+    fn dispatches_to_registered_handlers() {
+        let path = socket_path();
+        spawn_server(&path, 1);
+
+        let mut client = Client::new(&path, 5).unwrap();
+        assert_eq!(&client.do_request("echo", b"ping").unwrap()[..], b"ping");
+
+        match client.do_request("fail", b"") {
+            Err(Error::RemoteError { code, message }) => assert_eq!((code, message.as_str()), (Code::Unknown, "boom")),
+            other => panic!("expected remote error, got {:?}", other),
+        }
+
+        match client.do_request("lookup", b"") {
+            Err(Error::RemoteError { code, message }) => assert_eq!((code, message.as_str()), (Code::NotFound, "no such user")),
+            other => panic!("expected remote error, got {:?}", other),
+        }
+
+        match client.do_request("missing", b"") {
+            Err(Error::RemoteError { code, .. }) => assert_eq!(code, Code::Unimplemented),
+            other => panic!("expected remote error, got {:?}", other),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn methods_without_a_handler_go_to_the_fallback() {
+        let mut server = Server::new();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        server.register_fallback(|context, body| Ok([context.method_name().as_bytes(), b":", body].concat()));
+        let mut client = testing::connect(server).unwrap();
+
+        assert_eq!(&client.do_request("echo", b"hi").unwrap()[..], b"hi");
+        assert_eq!(&client.do_request("lookup", b"carol").unwrap()[..], b"lookup:carol");
+        assert_eq!(client.methods().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn notifications_are_handled_without_a_response() {
+        let path = socket_path();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("event", move |body| {
+            tx.send(body.to_vec()).unwrap();
+            Ok::<_, Status>(Vec::new())
+        });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::new(&path, 5).unwrap();
+        client.notify("event", b"first").unwrap();
+        client.notify("event", b"second").unwrap();
+        assert_eq!(&client.do_request("echo", b"ping").unwrap()[..], b"ping");
+
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [b"first".to_vec(), b"second".to_vec()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uploads_sent_as_notifications_get_no_response() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut server = Server::new();
+        server.register_upload("store", move |upload| {
+            let mut body = Vec::new();
+            upload.read_to_end(&mut body).unwrap();
+            tx.send(body).unwrap();
+            Ok(Vec::new())
+        });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        let (mut client_end, server_end) = UnixStream::pair().unwrap();
+        thread::spawn(move || server.serve(server_end));
+
+        let framing = Framing::default();
+        let upload = Message::notification("store", b"chunk");
+        let request = Message::request("echo", b"after");
+        client_end.write_all(&framing.encode(&upload)).unwrap();
+        client_end.write_all(&framing.encode(&upload.reply(STREAM_END, Ok(Bytes::new())))).unwrap();
+        client_end.write_all(&framing.encode(&request)).unwrap();
+
+        let response = framing.read(&mut BufReader::new(&client_end)).unwrap();
+        assert_eq!(response.request_id, request.request_id);
+        assert_eq!(&response.body[..], b"after");
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [b"chunk".to_vec()]);
+    }
+
+    #[test]
+    fn handlers_see_the_callers_deadline() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register_with_context("budget", |context, _| {
+            let left = context.deadline().map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()));
+            Ok(format!("{:?}", left.map(|left| left.as_millis() / 100)).into_bytes())
+        });
+        thread::spawn(move || server.run());
+
+        let mut client = Client::builder(&path).timeout(Some(Duration::from_secs(5))).propagate_deadline(true).connect().unwrap();
+        assert_eq!(&client.do_request_with_timeout("budget", b"", Duration::from_millis(850)).unwrap()[..], b"Some(8)");
+
+        client.set_propagate_deadline(false);
+        assert_eq!(&client.do_request("budget", b"").unwrap()[..], b"None");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn handlers_read_request_headers() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register_with_context("whoami", |context, _| match context.header("authorization") {
+            Some("Bearer s3cret") => Ok(context.headers().map(|(key, _)| key).collect::<Vec<_>>().join(",").into_bytes()),
+            _ => Err(Status::new(Code::Unauthenticated, "missing token")),
+        });
+        thread::spawn(move || server.run());
+
+        let mut client = Client::builder(&path).timeout(Some(Duration::from_secs(5))).propagate_deadline(true).connect().unwrap();
+        let headers = [("authorization", "Bearer s3cret"), ("trace-id", "4bf92f")];
+        assert_eq!(&client.do_request_with_headers("whoami", b"", &headers).unwrap()[..], b"authorization,trace-id");
+        assert!(matches!(client.do_request("whoami", b""), Err(Error::RemoteError { code: Code::Unauthenticated, .. })));
+
+        for bad in [("__deadline", "1"), ("a=b", "c"), ("key", "line\u{1e}")] {
+            assert!(matches!(client.do_request_with_headers("whoami", b"", &[bad]), Err(Error::Protocol(_))));
+        }
+        assert!(!client.is_broken());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pings_are_answered_ahead_of_middleware() {
+        let mut server = Server::new();
+        server.add_middleware(|_: &RequestContext<'_>| Err(Status::new(Code::Unauthenticated, "no token")));
+        let mut client = crate::testing::connect(server).unwrap();
+
+        assert!(client.ping().unwrap() < Duration::from_secs(5));
+        assert!(matches!(client.do_request("status", b""), Err(Error::RemoteError { code: Code::Unauthenticated, .. })));
+    }
+
+    #[test]
+    fn idle_connections_are_closed() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.set_workers(2);
+        server.set_idle_timeout(Some(Duration::from_millis(100)));
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let mut left_open = Client::new(&path, 5).unwrap();
+        let mut client = Client::builder(&path).idle_timeout(Some(Duration::from_millis(20))).connect().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(left_open.do_request("echo", b"too late").is_err());
+        assert_eq!(&client.do_request("echo", b"fresh").unwrap()[..], b"fresh");
+        assert_eq!(client.stats().reconnects(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trace_ids_reach_handlers() {
+        let mut server = Server::new();
+        server.register_with_context("trace", |context, _| Ok(format!("{:?}", context.trace_id()).into_bytes()));
+        let mut client = crate::testing::connect(server).unwrap();
+
+        client.set_trace_id(Some("4bf92f3577b34da6a3ce929d0e0e4736")).unwrap();
+        assert_eq!(&client.do_request("trace", b"").unwrap()[..], br#"Some("4bf92f3577b34da6a3ce929d0e0e4736")"#);
+        assert!(matches!(client.set_trace_id(Some("a\u{1d}b")), Err(Error::Protocol(_))));
+        client.set_trace_id(None).unwrap();
+        assert_eq!(&client.do_request("trace", b"").unwrap()[..], b"None");
+    }
+
+    #[test]
+    fn oversized_messages_are_refused_on_both_ends() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.set_max_message_size(Some(256));
+        server.register("grow", |body| Ok::<_, Status>(body.repeat(8)));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::builder(&path).timeout(Some(Duration::from_secs(5))).max_message_size(512).connect().unwrap();
+        assert!(matches!(client.do_request("grow", &[b'x'; 100]), Err(Error::MessageTooLarge { limit: 512 })));
+        assert!(client.is_broken());
+        drop(client);
+
+        let mut client = Client::new(&path, 5).unwrap();
+        assert_eq!(client.do_request("grow", &[b'x'; 10]).unwrap().len(), 80);
+        assert!(matches!(client.do_request("grow", &[b'x'; 300]), Err(Error::Io(_))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connection_errors_go_to_the_observers() {
+        let path = socket_path();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let mut server = Server::bind(&path).unwrap();
+        server.set_max_message_size(Some(16));
+        server.on_error(move |e| tx.lock().unwrap().send(e.to_string()).unwrap());
+        thread::spawn(move || server.run());
+
+        let mut client = Client::new(&path, 5).unwrap();
+        assert!(client.do_request("echo", &[b'x'; 64]).is_err());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Error::MessageTooLarge { limit: 16 }.to_string());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_connections_do_not_hold_up_others() {
+        let path = socket_path();
+        spawn_server(&path, 1);
+
+        let _idle = UnixStream::connect(&path).unwrap();
+        let mut first = Client::new(&path, 5).unwrap();
+        let mut second = Client::new(&path, 5).unwrap();
+        assert_eq!(&first.do_request("echo", b"one").unwrap()[..], b"one");
+        assert_eq!(&second.do_request("echo", b"two").unwrap()[..], b"two");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn blocked_handlers_do_not_hold_up_other_connections() {
+        let path = socket_path();
+        let (started, holding) = std::sync::mpsc::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let (started, released) = (std::sync::Mutex::new(started), std::sync::Mutex::new(released));
+        let mut server = Server::bind(&path).unwrap();
+        server.register("hold", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            let _ = released.lock().unwrap().recv();
+            Ok::<_, Status>(b"done".to_vec())
+        });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let mut first = Client::new(&path, 5).unwrap();
+        let pending = first.send("hold", b"").unwrap();
+        holding.recv_timeout(Duration::from_secs(5)).unwrap();
+        let mut second = Client::new(&path, 5).unwrap();
+        assert_eq!(&second.do_request("echo", b"two").unwrap()[..], b"two");
+        release.send(()).unwrap();
+        assert_eq!(&pending.wait(&mut first).unwrap()[..], b"done");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn workers_bound_the_handlers_running_at_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let path = socket_path();
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut server = Server::bind(&path).unwrap();
+        server.set_workers(2);
+        let (counter, peak) = (running.clone(), most.clone());
+        server.register("work", move |_| {
+            peak.fetch_max(counter.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            counter.fetch_sub(1, Ordering::SeqCst);
+            Ok::<_, Status>(Vec::new())
+        });
+        thread::spawn(move || server.run());
+
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || Client::new(&path, 5).unwrap().do_request("work", b"").unwrap())
+            })
+            .collect();
+        for caller in callers {
+            caller.join().unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn length_prefixed_round_trip_keeps_binary_bodies() {
+        let path = socket_path();
+        spawn_server_with(&path, 1, Framing::LengthPrefixed);
+
+        let mut client = Client::with_framing(&path, 5, Framing::LengthPrefixed).unwrap();
+        let body = [0x1F, 0x1E, 0x00, 0xFF];
+        assert_eq!(&client.do_request("echo", &body).unwrap()[..], &body);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
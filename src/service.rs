@@ -0,0 +1,193 @@
+//! Typed service definitions shared by client and server.
+
+use crate::Server;
+
+/// Handlers registered together with [`Server::register_service`], such as
+/// the server half generated by [`crate::service!`].
+pub trait Service {
+    fn register(self, server: &mut Server);
+}
+
+/// Declares a service trait together with a typed client stub and a server
+/// wrapper for it, so method names and types are written once instead of in
+/// every caller and every router.
+///
+/// Each method takes one request and returns one response, both carried by
+/// a [`crate::Codec`] chosen when the stub or wrapper is created. The
+/// method's name is the method name on the wire. Implementations return a
+/// [`crate::Status`] to fail a call, and are served by registering the
+/// wrapper with [`crate::Server::register_service`].
+///
+/// ```no_run
+/// # use std::io;
+/// # use unixconn_rust::{Client, Codec};
+/// /// Lines of text, one value per call.
+/// struct Text;
+///
+/// impl Codec<String> for Text {
+///     fn encode(&self, value: &String) -> io::Result<Vec<u8>> {
+///         Ok(value.clone().into_bytes())
+///     }
+///
+///     fn decode(&self, body: &[u8]) -> io::Result<String> {
+///         String::from_utf8(body.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+///     }
+/// }
+///
+/// unixconn_rust::service! {
+///     pub trait Greeter => GreeterClient, GreeterServer {
+///         fn greet(&self, name: String) -> String;
+///     }
+/// }
+///
+/// struct Polite;
+///
+/// impl Greeter for Polite {
+///     fn greet(&self, name: String) -> Result<String, unixconn_rust::Status> {
+///         Ok(format!("Hello, {}!", name))
+///     }
+/// }
+///
+/// let mut server = unixconn_rust::Server::bind("/tmp/greeter.sock")?;
+/// server.register_service(GreeterServer::new(Polite, Text));
+/// std::thread::spawn(move || server.run());
+///
+/// let mut greeter = GreeterClient::new(Client::new("/tmp/greeter.sock", 5)?, Text);
+/// let greeting = greeter.greet(&"world".to_string())?;
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+#[macro_export]
+macro_rules! service {
+    (
+        $(#[$attr:meta])*
+        $vis:vis trait $service:ident => $client:ident, $server:ident {
+            $(
+                $(#[$method_attr:meta])*
+                fn $method:ident(&self, $arg:ident: $request:ty) -> $response:ty;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis trait $service {
+            $(
+                $(#[$method_attr])*
+                fn $method(&self, $arg: $request) -> ::std::result::Result<$response, $crate::Status>;
+            )*
+        }
+
+        #[doc = concat!("Typed client for [`", stringify!($service), "`].")]
+        $vis struct $client<C> {
+            client: $crate::Client,
+            codec: C,
+        }
+
+        impl<C> $client<C>
+        where
+            C: $($crate::Codec<$request> + $crate::Codec<$response> +)*,
+        {
+            pub fn new(client: $crate::Client, codec: C) -> Self {
+                $client { client, codec }
+            }
+
+            /// The underlying client, e.g. to adjust its timeout.
+            pub fn client(&mut self) -> &mut $crate::Client {
+                &mut self.client
+            }
+
+            $(
+                $(#[$method_attr])*
+                pub fn $method(&mut self, $arg: &$request) -> $crate::Result<$response> {
+                    self.client.call(&self.codec, stringify!($method), $arg)
+                }
+            )*
+        }
+
+        #[doc = concat!("Serves an implementation of [`", stringify!($service), "`].")]
+        $vis struct $server<T, C> {
+            service: ::std::sync::Arc<T>,
+            codec: ::std::sync::Arc<C>,
+        }
+
+        impl<T, C> $server<T, C> {
+            pub fn new(service: T, codec: C) -> Self {
+                $server { service: ::std::sync::Arc::new(service), codec: ::std::sync::Arc::new(codec) }
+            }
+        }
+
+        impl<T, C> $crate::Service for $server<T, C>
+        where
+            T: $service + Send + Sync + 'static,
+            C: $($crate::Codec<$request> + $crate::Codec<$response> +)* Send + Sync + 'static,
+        {
+            fn register(self, server: &mut $crate::Server) {
+                $(
+                    let service = self.service.clone();
+                    server.register_typed(stringify!($method), self.codec.clone(), move |$arg: $request| service.$method($arg));
+                )*
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::socket_path;
+    use crate::{Client, Code, Codec, Error, Server, Status};
+    use std::io;
+    use std::thread;
+
+    /// Numbers as decimal strings.
+    struct Decimal;
+
+    impl Codec<u64> for Decimal {
+        fn encode(&self, value: &u64) -> io::Result<Vec<u8>> {
+            Ok(value.to_string().into_bytes())
+        }
+
+        fn decode(&self, body: &[u8]) -> io::Result<u64> {
+            std::str::from_utf8(body)
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a number"))
+        }
+    }
+
+    crate::service! {
+        /// Arithmetic on one number at a time.
+        trait Calculator => CalculatorClient, CalculatorServer {
+            fn square(&self, n: u64) -> u64;
+            fn halve(&self, n: u64) -> u64;
+        }
+    }
+
+    struct Local;
+
+    impl Calculator for Local {
+        fn square(&self, n: u64) -> Result<u64, Status> {
+            Ok(n * n)
+        }
+
+        fn halve(&self, n: u64) -> Result<u64, Status> {
+            match n.is_multiple_of(2) {
+                true => Ok(n / 2),
+                false => Err(Status::new(Code::InvalidArgument, "odd number")),
+            }
+        }
+    }
+
+    #[test]
+    fn stubs_call_methods_by_name() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register_service(CalculatorServer::new(Local, Decimal));
+        thread::spawn(move || server.run());
+
+        let mut calculator = CalculatorClient::new(Client::new(&path, 5).unwrap(), Decimal);
+        assert_eq!(calculator.square(&12).unwrap(), 144);
+        assert_eq!(calculator.halve(&10).unwrap(), 5);
+        assert!(matches!(calculator.halve(&3), Err(Error::RemoteError { code: Code::InvalidArgument, .. })));
+        assert!(!calculator.client().is_broken());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
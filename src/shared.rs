@@ -0,0 +1,90 @@
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::error::Result;
+use crate::Client;
+
+/// A [`Client`] that can be cloned and used from many threads at once.
+///
+/// Clones share one connection; requests take turns on it, so each one
+/// still waits for its own response before the next is written. Use a
+/// [`crate::ClientPool`] when requests should run in parallel.
+#[derive(Clone)]
+pub struct SharedClient {
+    client: Arc<Mutex<Client>>,
+}
+
+impl SharedClient {
+    pub fn new(client: Client) -> Self {
+        SharedClient { client: Arc::new(Mutex::new(client)) }
+    }
+
+    pub fn do_request(&self, method_name: &str, request_body: &[u8]) -> Result<Bytes> {
+        self.lock().do_request(method_name, request_body)
+    }
+
+    pub fn do_request_with_timeout(&self, method_name: &str, request_body: &[u8], timeout: Duration) -> Result<Bytes> {
+        self.lock().do_request_with_timeout(method_name, request_body, timeout)
+    }
+
+    pub fn do_batch(&self, requests: &[(&str, &[u8])]) -> Vec<Result<Bytes>> {
+        self.lock().do_batch(requests)
+    }
+
+    pub fn notify(&self, method_name: &str, request_body: &[u8]) -> Result<()> {
+        self.lock().notify(method_name, request_body)
+    }
+
+    pub fn close(&self) -> io::Result<()> {
+        self.lock().close()
+    }
+
+    /// Client state stays consistent across a panicking caller, so a
+    /// poisoned lock is simply taken over.
+    fn lock(&self) -> MutexGuard<'_, Client> {
+        self.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl From<Client> for SharedClient {
+    fn from(client: Client) -> Self {
+        SharedClient::new(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::echo_server;
+    use std::thread;
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedClient>();
+    }
+
+    #[test]
+    fn clones_serialize_requests_across_threads() {
+        let path = echo_server(1);
+        let client = SharedClient::new(Client::new(&path, 5).unwrap());
+
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let body = format!("worker {}", i);
+                    assert_eq!(client.do_request("echo", body.as_bytes()).unwrap(), body.as_bytes());
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::Shutdown;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Stops a running [`crate::Server`] from another thread, from
+/// [`crate::Server::shutdown_handle`].
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    stopping: AtomicBool,
+    next_id: AtomicU64,
+    /// The connections being served, to stop reading from and to close.
+    open: Mutex<HashMap<u64, UnixStream>>,
+    closed: Condvar,
+    /// Where the server accepts, and how many threads there are to wake.
+    listening: Mutex<Option<(SocketAddr, usize)>>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and reading new requests. Requests
+    /// already read are answered for up to `grace`, after which the
+    /// connections still open are closed; returns once all connections
+    /// are done either way.
+    ///
+    /// A handler still running then is not interrupted, but its response
+    /// goes nowhere. [`crate::Server::run`] returns once its connections
+    /// are closed.
+    pub fn shutdown(&self, grace: Duration) {
+        if self.state.stopping.swap(true, Ordering::AcqRel) {
+            return self.wait(grace);
+        }
+
+        // Threads blocked in accept(2) only look at the flag once a
+        // connection arrives, so each is sent one.
+        if let Some((address, workers)) = lock(&self.state.listening).take() {
+            for _ in 0..workers {
+                let _ = UnixStream::connect_addr(&address);
+            }
+        }
+        for conn in lock(&self.state.open).values() {
+            let _ = conn.shutdown(Shutdown::Read);
+        }
+        self.wait(grace);
+    }
+
+    fn wait(&self, grace: Duration) {
+        let deadline = Instant::now() + grace;
+        let mut open = lock(&self.state.open);
+        while !open.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                for conn in open.values() {
+                    let _ = conn.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            open = self.state.closed.wait_timeout(open, left).unwrap_or_else(PoisonError::into_inner).0;
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.state.stopping.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn listening(&self, address: SocketAddr, workers: usize) {
+        *lock(&self.state.listening) = Some((address, workers));
+    }
+
+    /// Tracks `conn` until the returned guard is dropped. A connection
+    /// opened while stopping gets no requests read.
+    pub(crate) fn track(&self, conn: &UnixStream) -> std::io::Result<Tracked<'_>> {
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        let tracked = conn.try_clone()?;
+        let mut open = lock(&self.state.open);
+        if self.is_stopping() {
+            let _ = tracked.shutdown(Shutdown::Read);
+        }
+        open.insert(id, tracked);
+        Ok(Tracked { handle: self, id })
+    }
+}
+
+/// A connection counted as open by its [`ShutdownHandle`].
+pub(crate) struct Tracked<'a> {
+    handle: &'a ShutdownHandle,
+    id: u64,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        lock(&self.handle.state.open).remove(&self.id);
+        self.handle.state.closed.notify_all();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Client, Error, Server, Status};
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn spawn_sleeper(path: &str, workers: usize) -> (ShutdownHandle, mpsc::Receiver<()>, thread::JoinHandle<crate::Result<()>>) {
+        let (started, handling) = mpsc::channel();
+        let started = Mutex::new(started);
+        let mut server = Server::bind(path).unwrap();
+        server.set_workers(workers);
+        server.register("sleep", move |body| {
+            let _ = lock(&started).send(());
+            thread::sleep(Duration::from_millis(std::str::from_utf8(body).unwrap().parse().unwrap()));
+            Ok::<_, Status>(b"rested".to_vec())
+        });
+        let handle = server.shutdown_handle();
+        (handle, handling, thread::spawn(move || server.run()))
+    }
+
+    #[test]
+    fn in_flight_requests_finish_before_the_server_stops() {
+        let path = socket_path();
+        let (handle, handling, running) = spawn_sleeper(&path, 2);
+        let mut client = Client::new(&path, 5).unwrap();
+        let pending = client.send("sleep", b"200").unwrap();
+        handling.recv().unwrap();
+
+        let idle = Client::new(&path, 5).unwrap();
+        let stopping = thread::spawn(move || handle.shutdown(Duration::from_secs(5)));
+        assert_eq!(&pending.wait(&mut client).unwrap()[..], b"rested");
+        stopping.join().unwrap();
+        running.join().unwrap().unwrap();
+
+        assert!(client.do_request("sleep", b"0").is_err());
+        drop(idle);
+    }
+
+    #[test]
+    fn servers_bound_with_permissions_stop_too() {
+        let path = socket_path();
+        let mut server = Server::bind_with_permissions(&path, &crate::SocketPermissions::new().mode(0o600)).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        let handle = server.shutdown_handle();
+        let (stopped, running) = mpsc::channel();
+        thread::spawn(move || stopped.send(server.run()));
+        assert_eq!(&Client::new(&path, 5).unwrap().do_request("echo", b"up").unwrap()[..], b"up");
+
+        handle.shutdown(Duration::from_secs(1));
+        running.recv_timeout(Duration::from_secs(5)).expect("server still running").unwrap();
+    }
+
+    #[test]
+    fn connections_still_busy_after_the_grace_period_are_closed() {
+        let path = socket_path();
+        let (handle, handling, running) = spawn_sleeper(&path, 1);
+        let mut client = Client::new(&path, 5).unwrap();
+        let pending = client.send("sleep", b"500").unwrap();
+        handling.recv().unwrap();
+
+        let started = Instant::now();
+        handle.shutdown(Duration::from_millis(50));
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert!(matches!(pending.wait(&mut client), Err(Error::Io(_))));
+        running.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+//! Graceful shutdown on SIGTERM and SIGINT.
+
+use std::io;
+use std::mem;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::shutdown::ShutdownHandle;
+
+impl ShutdownHandle {
+    /// Shuts the server down with `grace` when the process receives SIGTERM
+    /// or SIGINT, as service managers send on stop and a terminal on
+    /// Ctrl-C. The signals are waited for on a thread of their own, which
+    /// the returned handle joins once the shutdown is done.
+    ///
+    /// The signals are blocked in the calling thread, and so in threads it
+    /// spawns afterwards; call this from `main` before starting any others.
+    /// A thread left with them unblocked would still be killed by them.
+    pub fn shutdown_on_signals(&self, grace: Duration) -> io::Result<JoinHandle<()>> {
+        // SAFETY: sigset_t is plain data, initialised by sigemptyset before
+        // use; the calls only read and write the set and the thread mask.
+        let set = unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            match libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) {
+                0 => set,
+                errno => return Err(io::Error::from_raw_os_error(errno)),
+            }
+        };
+
+        let handle = self.clone();
+        Ok(thread::spawn(move || {
+            let mut signal = 0;
+            // SAFETY: `set` was initialised above; sigwait writes the signal
+            // number into `signal`.
+            while unsafe { libc::sigwait(&set, &mut signal) } != 0 {}
+            handle.shutdown(grace);
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::socket_path;
+    use crate::Server;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn sigterm_stops_the_server() {
+        let path = socket_path();
+        let server = Server::bind(&path).unwrap();
+        let waiter = server.shutdown_handle().shutdown_on_signals(Duration::from_secs(1)).unwrap();
+        let running = thread::spawn(move || server.run());
+
+        // Sent to the waiting thread only, so no other test thread can be
+        // the one to receive it.
+        // SAFETY: the thread is alive until it has received the signal.
+        assert_eq!(unsafe { libc::pthread_kill(waiter.as_pthread_t(), libc::SIGTERM) }, 0);
+        waiter.join().unwrap();
+        running.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Optional HMAC-SHA256 of every frame under a key shared by client and
+//! server, so that a process able to write to the socket but not knowing
+//! the key cannot inject requests or responses.
+//!
+//! The MAC covers every field of the frame, headers included, and travels
+//! in a header of its own as 64 hex digits. Unlike checksums, a receiver
+//! with a key refuses frames without a valid MAC: a missing one is exactly
+//! what a forger sends.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::protocol::Message;
+
+const HMAC_HEADER: &str = "__hmac";
+
+const BLOCK: usize = 64;
+
+/// Round constants: the first 32 bits of the fractional parts of the cube
+/// roots of the first 64 primes.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be,
+    0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa,
+    0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85,
+    0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
+    0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f,
+    0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, fed in pieces so signed fields need not be copied together.
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK],
+    filled: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+            block: [0; BLOCK],
+            filled: 0,
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = data.len().min(BLOCK - self.filled);
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == BLOCK {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.len * 8;
+        self.update(&[0x80]);
+        while self.filled != BLOCK - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (word, add) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+}
+
+/// A shared secret for [`crate::ClientBuilder::signing_key`] and
+/// [`crate::Server::set_signing_key`]. Debug output leaves it out.
+#[derive(Clone)]
+pub(crate) struct SigningKey(Arc<[u8]>);
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+impl SigningKey {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        SigningKey(key.into())
+    }
+
+    /// HMAC-SHA256 (RFC 2104) of the concatenation of `parts`.
+    fn mac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut block = [0u8; BLOCK];
+        if self.0.len() > BLOCK {
+            let mut hash = Sha256::new();
+            hash.update(&self.0);
+            block[..32].copy_from_slice(&hash.finish());
+        } else {
+            block[..self.0.len()].copy_from_slice(&self.0);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|b| b ^ 0x36));
+        for part in parts {
+            inner.update(part);
+        }
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|b| b ^ 0x5c));
+        outer.update(&inner.finish());
+        outer.finish()
+    }
+
+    /// The MAC of a message: its request ID, method name, body, error and
+    /// every header but the MAC's own, in the order sent, each preceded by
+    /// its length so different splits of the same bytes sign differently.
+    fn sign(&self, message: &Message) -> [u8; 32] {
+        let mut fields: Vec<&[u8]> = vec![message.request_id.as_bytes(), message.method_name.as_bytes(), &message.body, message.error.as_bytes()];
+        for (name, value) in message.headers.iter().filter(|(name, _)| name != HMAC_HEADER) {
+            fields.extend([name.as_bytes(), value.as_bytes()]);
+        }
+        let lens: Vec<[u8; 8]> = fields.iter().map(|field| (field.len() as u64).to_be_bytes()).collect();
+        let parts: Vec<&[u8]> = lens.iter().zip(fields).flat_map(|(len, field)| [&len[..], field]).collect();
+        self.mac(&parts)
+    }
+}
+
+/// What a connection adds to the frames it sends and checks on the frames
+/// it receives.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Integrity {
+    pub(crate) checksums: bool,
+    pub(crate) key: Option<SigningKey>,
+}
+
+impl Integrity {
+    /// `message` as it is to be encoded.
+    pub(crate) fn seal<'a>(&self, message: &'a Message) -> Cow<'a, Message> {
+        let mut sealed = match self.checksums {
+            true => Cow::Owned(checksum::sign(message)),
+            false => Cow::Borrowed(message),
+        };
+        if let Some(key) = &self.key {
+            let mac = hex(&key.sign(&sealed));
+            let headers = &mut sealed.to_mut().headers;
+            headers.retain(|(name, _)| name != HMAC_HEADER);
+            headers.push((HMAC_HEADER.to_string(), mac));
+        }
+        sealed
+    }
+
+    pub(crate) fn verify(&self, message: &Message) -> Result<()> {
+        if self.checksums {
+            checksum::verify(message)?;
+        }
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let mac = message.header(HMAC_HEADER).ok_or_else(|| Error::Protocol("frame is not signed".to_string()))?;
+        let expected = hex(&key.sign(message));
+        // Compared in full whatever differs, so timing reveals nothing.
+        let differ = mac.len() != expected.len() || mac.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0;
+        match differ {
+            true => Err(Error::Protocol("frame signature does not match".to_string())),
+            false => Ok(()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Client, Server, Status};
+    use bytes::Bytes;
+
+    #[test]
+    fn sha256_and_hmac_match_published_vectors() {
+        let mut hash = Sha256::new();
+        hash.update(b"abc");
+        assert_eq!(hex(&hash.finish()), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let mut hash = Sha256::new();
+        hash.update(&[b'a'; 1000]);
+        assert_eq!(hex(&hash.finish()), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+
+        // RFC 4231, test cases 2 and 6.
+        let mac = SigningKey::new(b"Jefe").mac(&[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(hex(&mac), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let mac = SigningKey::new(&[0xaa; 131]).mac(&[b"Test Using Larger Than Block-Size Key - Hash Key First"]);
+        assert_eq!(hex(&mac), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn tampered_and_unsigned_frames_are_refused() {
+        let integrity = Integrity { checksums: false, key: Some(SigningKey::new(b"shared")) };
+        let request = Message::request("transfer", b"10");
+        let sealed = integrity.seal(&request).into_owned();
+        assert!(integrity.verify(&sealed).is_ok());
+
+        assert!(integrity.verify(&sealed.with_body(Bytes::from_static(b"1000"))).is_err());
+        assert!(integrity.verify(&request).is_err());
+
+        let mut failed = sealed.clone();
+        failed.error = "injected".to_string();
+        assert!(integrity.verify(&failed).is_err());
+        let mut widened = sealed.clone();
+        widened.headers.insert(0, ("authorization".to_string(), "Bearer stolen".to_string()));
+        assert!(integrity.verify(&widened).is_err());
+
+        let both = Integrity { checksums: true, key: Some(SigningKey::new(b"shared")) };
+        let mut compressed = request.clone();
+        compressed.headers.push(("__encoding".to_string(), "zstd".to_string()));
+        let sealed = both.seal(&compressed).into_owned();
+        assert!(both.verify(&sealed).is_ok());
+        let mut stripped = sealed.clone();
+        stripped.headers.retain(|(name, _)| name != "__encoding");
+        assert!(both.verify(&stripped).is_err());
+        let other = Integrity { checksums: false, key: Some(SigningKey::new(b"guessed")) };
+        assert!(integrity.verify(&other.seal(&request)).is_err());
+    }
+
+    #[test]
+    fn signed_clients_and_servers_talk_and_others_are_cut_off() {
+        let serve = || {
+            let mut server = Server::new();
+            server.set_signing_key(b"shared secret");
+            server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+            server
+        };
+        let (mut client, server_end) = testing::pair_with(Client::builder("signed").signing_key(b"shared secret")).unwrap();
+        let server = serve();
+        std::thread::spawn(move || server.serve(server_end));
+        assert_eq!(&client.do_request("echo", b"signed").unwrap()[..], b"signed");
+
+        let mut unsigned = testing::connect(serve()).unwrap();
+        assert!(unsigned.do_request("echo", b"forged").is_err());
+    }
+}
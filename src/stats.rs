@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// How many of the latest round trips latency percentiles are taken over.
+const LATENCY_WINDOW: usize = 256;
+
+/// Counters a client keeps about its own traffic.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    pub(crate) requests_sent: u64,
+    pub(crate) frames_received: u64,
+    pub(crate) protocol_errors: u64,
+    pub(crate) connects: u64,
+    latencies: VecDeque<Duration>,
+}
+
+impl Stats {
+    /// Counts `error` if it says the peer broke the protocol.
+    pub(crate) fn saw_error(&mut self, error: &Error) {
+        if matches!(error, Error::Protocol(_) | Error::RequestIdMismatch { .. } | Error::MessageTooLarge { .. }) {
+            self.protocol_errors += 1;
+        }
+    }
+
+    pub(crate) fn round_trip(&mut self, latency: Duration) {
+        if self.latencies.len() == LATENCY_WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+    }
+
+    pub(crate) fn snapshot(&self) -> ClientStats {
+        let mut latencies: Vec<_> = self.latencies.iter().copied().collect();
+        latencies.sort_unstable();
+        ClientStats {
+            requests_sent: self.requests_sent,
+            frames_received: self.frames_received,
+            protocol_errors: self.protocol_errors,
+            reconnects: self.connects.saturating_sub(1),
+            latencies,
+        }
+    }
+}
+
+/// A snapshot of a client's traffic since it was built, from
+/// [`crate::Client::stats`].
+#[derive(Debug, Clone)]
+pub struct ClientStats {
+    requests_sent: u64,
+    frames_received: u64,
+    protocol_errors: u64,
+    reconnects: u64,
+    latencies: Vec<Duration>,
+}
+
+impl ClientStats {
+    /// Requests, notifications and streams opened, counting each retry and
+    /// each request of a batch.
+    pub fn requests_sent(&self) -> u64 {
+        self.requests_sent
+    }
+
+    /// Frames read from the server: responses, and every frame of streams
+    /// and subscriptions.
+    pub fn frames_received(&self) -> u64 {
+        self.frames_received
+    }
+
+    /// Failures caused by what the server sent: malformed or oversized
+    /// frames, and responses to the wrong request.
+    pub fn protocol_errors(&self) -> u64 {
+        self.protocol_errors
+    }
+
+    /// How often the client connected again after its first connection.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// The round-trip time below which the fraction `quantile` of recent
+    /// answered calls fell, e.g. `0.99` for the 99th percentile; `None`
+    /// before any call was answered. Taken over the latest 256 calls.
+    pub fn latency(&self, quantile: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let rank = (quantile.clamp(0.0, 1.0) * last as f64).round() as usize;
+        Some(self.latencies[rank])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::echo_server;
+    use crate::Client;
+
+    #[test]
+    fn percentiles_cover_the_latest_round_trips() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.snapshot().latency(0.5), None);
+        for millis in (1..=300).rev() {
+            stats.round_trip(Duration::from_millis(millis));
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.latency(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(snapshot.latency(0.5), Some(Duration::from_millis(129)));
+        assert_eq!(snapshot.latency(1.0), Some(Duration::from_millis(256)));
+    }
+
+    #[test]
+    fn clients_count_their_traffic() {
+        let path = echo_server(1);
+        let mut client = Client::builder(&path).auto_reconnect(true).max_message_size(64).connect().unwrap();
+        client.do_request("echo", b"ping").unwrap();
+        client.notify("echo", b"ignored").unwrap();
+        assert!(matches!(client.do_request("echo", &[b'x'; 100]), Err(Error::MessageTooLarge { .. })));
+        client.do_request("echo", b"again").unwrap();
+
+        let stats = client.stats();
+        assert_eq!((stats.requests_sent(), stats.frames_received()), (4, 2));
+        assert_eq!((stats.protocol_errors(), stats.reconnects()), (1, 1));
+        assert!(stats.latency(0.99).is_some());
+    }
+}
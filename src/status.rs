@@ -0,0 +1,166 @@
+use std::fmt;
+
+/// Machine-readable failure kind sent alongside an error message.
+///
+/// Codes travel in the error field as `<number>:<message>`, numbered like
+/// gRPC status codes. An error field without a numeric prefix, as sent by
+/// servers that predate codes, decodes as [`Code::Unknown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+impl Code {
+    pub fn from_u16(code: u16) -> Option<Code> {
+        Some(match code {
+            1 => Code::Cancelled,
+            2 => Code::Unknown,
+            3 => Code::InvalidArgument,
+            4 => Code::DeadlineExceeded,
+            5 => Code::NotFound,
+            6 => Code::AlreadyExists,
+            7 => Code::PermissionDenied,
+            8 => Code::ResourceExhausted,
+            9 => Code::FailedPrecondition,
+            10 => Code::Aborted,
+            11 => Code::OutOfRange,
+            12 => Code::Unimplemented,
+            13 => Code::Internal,
+            14 => Code::Unavailable,
+            15 => Code::DataLoss,
+            16 => Code::Unauthenticated,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::Cancelled => "CANCELLED",
+            Code::Unknown => "UNKNOWN",
+            Code::InvalidArgument => "INVALID_ARGUMENT",
+            Code::DeadlineExceeded => "DEADLINE_EXCEEDED",
+            Code::NotFound => "NOT_FOUND",
+            Code::AlreadyExists => "ALREADY_EXISTS",
+            Code::PermissionDenied => "PERMISSION_DENIED",
+            Code::ResourceExhausted => "RESOURCE_EXHAUSTED",
+            Code::FailedPrecondition => "FAILED_PRECONDITION",
+            Code::Aborted => "ABORTED",
+            Code::OutOfRange => "OUT_OF_RANGE",
+            Code::Unimplemented => "UNIMPLEMENTED",
+            Code::Internal => "INTERNAL",
+            Code::Unavailable => "UNAVAILABLE",
+            Code::DataLoss => "DATA_LOSS",
+            Code::Unauthenticated => "UNAUTHENTICATED",
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error returned by a server handler, sent to the client as its error field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    code: Code,
+    message: String,
+}
+
+impl Status {
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Status { code, message: message.into() }
+    }
+
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Every status is sent with its code, Unknown ones too, so a message
+    /// that itself starts with a number is never taken for a code.
+    pub(crate) fn encode(&self) -> String {
+        format!("{}:{}", self.code as u16, self.message)
+    }
+
+    pub(crate) fn decode(error: &str) -> Status {
+        let coded = error
+            .split_once(':')
+            .and_then(|(code, message)| Some((Code::from_u16(code.parse().ok()?)?, message)));
+
+        match coded {
+            Some((code, message)) => Status::new(code, message),
+            None => Status::new(Code::Unknown, error),
+        }
+    }
+}
+
+impl From<String> for Status {
+    fn from(message: String) -> Self {
+        Status::new(Code::Unknown, message)
+    }
+}
+
+impl From<&str> for Status {
+    fn from(message: &str) -> Self {
+        Status::new(Code::Unknown, message)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Code::Unknown => f.write_str(&self.message),
+            code => write!(f, "{}: {}", code, self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip_through_the_error_field() {
+        let status = Status::new(Code::NotFound, "no such user: a:b");
+
+        assert_eq!(status.encode(), "5:no such user: a:b");
+        assert_eq!(Status::decode(&status.encode()), status);
+    }
+
+    #[test]
+    fn unknown_messages_that_look_coded_round_trip() {
+        let status = Status::new(Code::Unknown, "5:disk gone");
+
+        assert_eq!(status.encode(), "2:5:disk gone");
+        assert_eq!(Status::decode(&status.encode()), status);
+    }
+
+    #[test]
+    fn plain_errors_decode_as_unknown() {
+        for error in ["boom", "dial: refused", "99:not a code"] {
+            let status = Status::decode(error);
+            assert_eq!(status.code(), Code::Unknown);
+            assert_eq!(status.message(), error);
+        }
+    }
+}
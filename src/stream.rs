@@ -0,0 +1,467 @@
+use std::io::{self, Read};
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::credentials::{peer_credentials, PeerCredentials};
+use crate::error::{Error, Result};
+use crate::fds::{self, FdStream};
+use crate::handshake::{self, HANDSHAKE};
+use crate::protocol::{Framing, Message, CANCEL, STREAM_END, UNSUBSCRIBE};
+use crate::signing::Integrity;
+use crate::status::{Code, Status};
+use crate::transport::write_all_vectored;
+use crate::Client;
+
+/// Write half of a server connection. Frames may come from the connection's
+/// own handlers and from subscribers on other threads, so each one is
+/// written whole under the lock.
+pub(crate) struct ConnWriter {
+    outbound: Mutex<Outbound>,
+}
+
+pub(crate) struct Outbound {
+    conn: UnixStream,
+    framing: Framing,
+    integrity: Integrity,
+}
+
+impl Outbound {
+    pub(crate) fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.send_with_fds(message, &[])
+    }
+
+    /// Sends `message` with `fds` passed alongside; it must already carry
+    /// their count.
+    pub(crate) fn send_with_fds(&mut self, message: &Message, fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        let frame = self.framing.encode_frame(&self.integrity.seal(message));
+        match fds {
+            [] => write_all_vectored(&mut &self.conn, &frame.parts()),
+            fds => fds::write_with_fds(&self.conn, &frame.to_bytes(), fds),
+        }
+    }
+}
+
+impl ConnWriter {
+    pub(crate) fn new(conn: UnixStream, framing: Framing, integrity: Integrity) -> Self {
+        ConnWriter { outbound: Mutex::new(Outbound { conn, framing, integrity }) }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Outbound> {
+        lock(&self.outbound)
+    }
+
+    pub(crate) fn send(&self, message: &Message) -> io::Result<()> {
+        self.lock().send(message)
+    }
+
+    /// Switches framing for the frames written from now on.
+    pub(crate) fn set_framing(&self, framing: Framing) {
+        self.lock().framing = framing;
+    }
+}
+
+/// Read half of a server connection. Frames are read ahead on a thread of
+/// their own, so a cancel frame is seen while the request it names is still
+/// queued or being served.
+pub(crate) struct Incoming {
+    /// Frames with the time each was read, before any wait in the queue.
+    frames: Receiver<Result<(Message, Instant)>>,
+    /// Requests read and not yet finished, and whether each was cancelled.
+    requests: Arc<Mutex<HashMap<String, bool>>>,
+    /// Descriptors passed with requests, until their handler takes them.
+    passed_fds: Arc<Mutex<HashMap<String, Vec<OwnedFd>>>>,
+    peer: Option<PeerCredentials>,
+}
+
+impl Incoming {
+    /// Starts reading frames from `conn` on a thread of `scope`. The thread
+    /// stops at the first error, or once the returned `Incoming` is dropped
+    /// and the connection shut down.
+    pub(crate) fn spawn<'scope>(
+        scope: &'scope std::thread::Scope<'scope, '_>,
+        conn: &'scope UnixStream,
+        framing: Framing,
+        max_len: Option<usize>,
+        integrity: Integrity,
+    ) -> Self {
+        let (sender, frames) = std::sync::mpsc::sync_channel(READ_AHEAD);
+        let requests = Arc::new(Mutex::new(HashMap::new()));
+        let passed_fds = Arc::new(Mutex::new(HashMap::new()));
+        let (tracked, passed) = (requests.clone(), passed_fds.clone());
+        scope.spawn(move || read_ahead(conn, framing, max_len, &integrity, sender, &tracked, &passed));
+        Incoming { frames, requests, passed_fds, peer: peer_credentials(conn).ok() }
+    }
+
+    pub(crate) fn peer(&self) -> Option<PeerCredentials> {
+        self.peer
+    }
+
+    pub(crate) fn next(&self) -> Result<Message> {
+        self.next_received().map(|(frame, _)| frame)
+    }
+
+    pub(crate) fn next_received(&self) -> Result<(Message, Instant)> {
+        self.frames
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+    }
+
+    /// Like [`Incoming::next_received`], giving up with `None` once nothing
+    /// arrived for `timeout`.
+    pub(crate) fn next_received_within(&self, timeout: Option<Duration>) -> Result<Option<(Message, Instant)>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.next_received().map(Some),
+        };
+        match self.frames.recv_timeout(timeout) {
+            Ok(frame) => frame.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self, request_id: &str) -> bool {
+        lock(&self.requests).get(request_id).copied().unwrap_or(false)
+    }
+
+    pub(crate) fn take_fds(&self, request_id: &str) -> Vec<OwnedFd> {
+        lock(&self.passed_fds).remove(request_id).unwrap_or_default()
+    }
+
+    /// Forgets a served request, closing any descriptors left untaken.
+    pub(crate) fn finish(&self, request_id: &str) {
+        lock(&self.requests).remove(request_id);
+        lock(&self.passed_fds).remove(request_id);
+    }
+}
+
+/// How many frames may be read ahead of the one being served.
+const READ_AHEAD: usize = 32;
+
+fn read_ahead(
+    conn: &UnixStream,
+    mut framing: Framing,
+    max_len: Option<usize>,
+    integrity: &Integrity,
+    frames: SyncSender<Result<(Message, Instant)>>,
+    requests: &Mutex<HashMap<String, bool>>,
+    passed_fds: &Mutex<HashMap<String, Vec<OwnedFd>>>,
+) {
+    let mut reader = io::BufReader::new(FdStream::new(conn));
+    let mut buffer = BytesMut::new();
+    let mut first = true;
+    loop {
+        let frame = framing.read_into(&mut reader, max_len, &mut buffer).and_then(|frame| integrity.verify(&frame).map(|_| frame));
+        let frame = frame.and_then(|frame| {
+            let passed = reader.get_mut().take(fds::count(&frame)?)?;
+            if !passed.is_empty() {
+                lock(passed_fds).entry(frame.request_id.clone()).or_default().extend(passed);
+            }
+            Ok(frame)
+        });
+        let frame = frame.map(|frame| (frame, Instant::now()));
+        if let Ok((frame, _)) = &frame {
+            // The client waits for the answer before sending more, so the
+            // next frame is the first one in the agreed framing.
+            if first && frame.method_name == HANDSHAKE {
+                framing = handshake::answered_framing(&frame.body, framing);
+            }
+            first = false;
+
+            if frame.method_name == CANCEL {
+                if let Some(cancelled) = lock(requests).get_mut(&frame.request_id) {
+                    *cancelled = true;
+                }
+                continue;
+            }
+            if !frame.is_notification() && frame.method_name != STREAM_END && frame.method_name != UNSUBSCRIBE {
+                lock(requests).entry(frame.request_id.clone()).or_insert(false);
+            }
+        }
+
+        let failed = frame.is_err();
+        if frames.send(frame).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Server side of a streamed response: each call to [`ResponseSink::send`]
+/// writes one body frame to the client straight away.
+pub struct ResponseSink<'a> {
+    writer: &'a ConnWriter,
+    incoming: &'a Incoming,
+    request: &'a Message,
+    failed: Option<io::Error>,
+}
+
+impl<'a> ResponseSink<'a> {
+    pub(crate) fn new(writer: &'a ConnWriter, incoming: &'a Incoming, request: &'a Message) -> Self {
+        ResponseSink { writer, incoming, request, failed: None }
+    }
+
+    /// Sends one chunk. Fails once the client has gone away or cancelled the
+    /// request, so handlers can stop producing with `?`.
+    pub fn send(&mut self, chunk: &[u8]) -> Result<(), Status> {
+        if self.incoming.is_cancelled(&self.request.request_id) {
+            return Err(Status::new(Code::Cancelled, "request cancelled by the client"));
+        }
+        if self.failed.is_none() {
+            let frame = self.request.reply(&self.request.method_name, Ok(Bytes::copy_from_slice(chunk)));
+            match self.writer.send(&frame) {
+                Ok(()) => return Ok(()),
+                Err(e) => self.failed = Some(e),
+            }
+        }
+        Err(Status::new(Code::Aborted, "stream closed by the client"))
+    }
+
+    pub(crate) fn into_result(self) -> io::Result<()> {
+        self.failed.map_or(Ok(()), Err)
+    }
+}
+
+/// Server side of a chunked upload: reads the request body frame by frame
+/// as the client sends it.
+pub struct UploadReader<'a> {
+    incoming: &'a Incoming,
+    request_id: &'a str,
+    chunk: Bytes,
+    done: bool,
+}
+
+impl<'a> UploadReader<'a> {
+    pub(crate) fn new(incoming: &'a Incoming, request: &'a Message) -> Self {
+        UploadReader {
+            incoming,
+            request_id: &request.request_id,
+            chunk: request.body.clone(),
+            done: false,
+        }
+    }
+
+    /// A reader for exchanges whose opening frame carries no data.
+    pub(crate) fn without_body(incoming: &'a Incoming, request: &'a Message) -> Self {
+        UploadReader { chunk: Bytes::new(), ..UploadReader::new(incoming, request) }
+    }
+
+    /// Returns the next frame as sent by the client, or `None` at the end.
+    pub(crate) fn recv_frame(&mut self) -> Result<Option<Bytes>> {
+        if self.chunk.is_empty() {
+            if !self.done {
+                self.next_chunk()?;
+            }
+            if self.done {
+                return Ok(None);
+            }
+        }
+        Ok(Some(std::mem::take(&mut self.chunk)))
+    }
+
+    fn next_chunk(&mut self) -> Result<()> {
+        let frame = self.incoming.next()?;
+        if frame.request_id != self.request_id {
+            return Err(Error::RequestIdMismatch {
+                expected: self.request_id.to_string(),
+                received: frame.request_id,
+            });
+        }
+
+        if frame.method_name == STREAM_END {
+            self.done = true;
+        } else {
+            self.chunk = frame.body;
+        }
+        Ok(())
+    }
+
+    /// Skips the rest of the upload so the next request can be read.
+    pub(crate) fn drain(&mut self) -> Result<()> {
+        while !self.done {
+            self.next_chunk()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for UploadReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.next_chunk() {
+                Ok(()) => {}
+                Err(Error::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
+
+/// Client side of a streamed response, from [`Client::do_request_stream`].
+///
+/// Yields body frames as they arrive, either as an iterator or through
+/// [`Read`] as one continuous byte stream. A stream dropped before its end
+/// leaves unread frames on the connection, so the client is marked broken.
+pub struct ResponseStream<'a> {
+    client: &'a mut Client,
+    request_id: String,
+    done: bool,
+    chunk: Bytes,
+}
+
+impl<'a> ResponseStream<'a> {
+    pub(crate) fn new(client: &'a mut Client, request_id: String) -> Self {
+        ResponseStream { client, request_id, done: false, chunk: Bytes::new() }
+    }
+}
+
+impl Iterator for ResponseStream<'_> {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Result<Bytes>> {
+        if self.done {
+            return None;
+        }
+
+        let frame = match self.client.read_stream_frame(&self.request_id) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        if frame.method_name == STREAM_END {
+            self.done = true;
+            return match frame.into_response(&self.request_id) {
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+        Some(frame.into_response(&self.request_id))
+    }
+}
+
+impl Read for ResponseStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.next() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(Error::Io(e))) => return Err(e),
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
+
+impl Drop for ResponseStream<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.client.abandon_connection();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::Server;
+    use std::thread;
+
+    fn spawn_counter(path: &str) {
+        let mut server = Server::bind(path).unwrap();
+        server.register_stream("count", |body, sink| {
+            let n: u32 = std::str::from_utf8(body).unwrap().parse().unwrap();
+            for i in 0..n {
+                sink.send(format!("{}\n", i).as_bytes())?;
+            }
+            if n > 2 {
+                return Err(Status::new(Code::OutOfRange, "too many"));
+            }
+            Ok(())
+        });
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+    }
+
+    #[test]
+    fn frames_are_yielded_until_the_end_marker() {
+        let path = socket_path();
+        spawn_counter(&path);
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let mut text = String::new();
+        client.do_request_stream("count", b"2").unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "0\n1\n");
+
+        let frames: Vec<_> = client.do_request_stream("count", b"3").unwrap().collect();
+        assert_eq!(frames.len(), 4);
+        assert!(matches!(frames[3], Err(Error::RemoteError { code: Code::OutOfRange, .. })));
+
+        assert_eq!(&client.do_request("echo", b"after").unwrap()[..], b"after");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uploads_arrive_in_chunks_and_leftovers_are_skipped() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register_upload("size", |upload| {
+            let mut body = Vec::new();
+            upload.read_to_end(&mut body).map_err(|e| Status::new(Code::DataLoss, e.to_string()))?;
+            Ok(body.len().to_string().into_bytes())
+        });
+        server.register_upload("peek", |upload| {
+            let mut first = [0u8; 4];
+            upload.read_exact(&mut first).unwrap();
+            Ok(first.to_vec())
+        });
+        thread::spawn(move || server.run());
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let body = vec![7u8; 200_000];
+        assert_eq!(&client.do_request_streamed("size", &body[..]).unwrap()[..], b"200000");
+        assert_eq!(&client.do_request_streamed("peek", &b"abcdefgh"[..]).unwrap()[..], b"abcd");
+        assert_eq!(&client.do_request_streamed("size", &b""[..]).unwrap()[..], b"0");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dropping_a_stream_early_breaks_the_connection() {
+        let path = socket_path();
+        spawn_counter(&path);
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let mut stream = client.do_request_stream("count", b"2").unwrap();
+        stream.next().unwrap().unwrap();
+        drop(stream);
+
+        assert!(client.is_broken());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
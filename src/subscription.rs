@@ -0,0 +1,171 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::error::Result;
+use crate::protocol::{Message, STREAM_END, UNSUBSCRIBE};
+use crate::status::{Code, Status};
+use crate::stream::ConnWriter;
+use crate::Client;
+
+/// Messages pushed by the server after [`Client::subscribe`].
+///
+/// Iterating waits for the next message with no timeout, since pushes may be
+/// far apart. Leave with [`Subscription::unsubscribe`]; a subscription
+/// dropped while the server is still pushing marks the client broken.
+pub struct Subscription<'a> {
+    client: &'a mut Client,
+    request_id: String,
+    done: bool,
+}
+
+impl<'a> Subscription<'a> {
+    pub(crate) fn new(client: &'a mut Client, request_id: String) -> Self {
+        Subscription { client, request_id, done: false }
+    }
+
+    /// Asks the server to stop pushing and skips whatever was already on the
+    /// way, leaving the client ready for the next request.
+    pub fn unsubscribe(mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+
+        let leave = Message { request_id: self.request_id.clone(), ..Message::notification(UNSUBSCRIBE, b"") };
+        self.client.write_frame(&leave)?;
+        while !self.done {
+            let frame = self.client.read_stream_frame(&self.request_id)?;
+            self.done = frame.method_name == STREAM_END;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription<'_> {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Result<Bytes>> {
+        if self.done {
+            return None;
+        }
+
+        let frame = match self.client.read_pushed_frame(&self.request_id) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        if frame.method_name == STREAM_END {
+            self.done = true;
+            return frame.into_response(&self.request_id).err().map(Err);
+        }
+        Some(frame.into_response(&self.request_id))
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.client.abandon_connection();
+        }
+    }
+}
+
+/// Server side of a subscription, handed to handlers registered with
+/// [`crate::Server::register_subscription`].
+///
+/// It can be cloned and kept anywhere, such as a list of listeners for some
+/// event, and pushes to the client from any thread until the client
+/// unsubscribes or disconnects.
+#[derive(Clone)]
+pub struct Subscriber {
+    writer: Arc<ConnWriter>,
+    request: Arc<Message>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    pub(crate) fn new(writer: Arc<ConnWriter>, request: Message) -> Self {
+        Subscriber { writer, request: Arc::new(request), closed: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Pushes one message. Fails once the client has left.
+    pub fn send(&self, body: &[u8]) -> Result<(), Status> {
+        let frame = self.request.reply(&self.request.method_name, Ok(Bytes::copy_from_slice(body)));
+        let mut conn = self.writer.lock();
+        if self.is_closed() {
+            return Err(Status::new(Code::Cancelled, "client unsubscribed"));
+        }
+        conn.send(&frame).map_err(|e| {
+            self.closed.store(true, Ordering::Release);
+            Status::new(Code::Aborted, format!("subscriber connection closed: {}", e))
+        })
+    }
+
+    /// Ends the subscription from the server side, with an error for the
+    /// client if `result` is one.
+    pub fn close(&self, result: Result<(), Status>) {
+        let end = self.request.reply(STREAM_END, result.map(|_| Bytes::new()));
+        let mut conn = self.writer.lock();
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            let _ = conn.send(&end);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::{Error, Server};
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn pushes_arrive_until_either_side_leaves() {
+        let path = socket_path();
+        let (subscribers, subscribed) = mpsc::channel();
+        let mut server = Server::bind(&path).unwrap();
+        server.register_subscription("events", move |_, subscriber| {
+            subscribers.send(subscriber).unwrap();
+            Ok(())
+        });
+        server.register_subscription("refused", |_, _| Err(Status::new(Code::PermissionDenied, "not allowed")));
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+        let mut client = Client::new(&path, 5).unwrap();
+
+        let mut subscription = client.subscribe("events", b"").unwrap();
+        let subscriber = subscribed.recv().unwrap();
+        subscriber.send(b"first").unwrap();
+        subscriber.send(b"second").unwrap();
+        assert_eq!(&subscription.next().unwrap().unwrap()[..], b"first");
+        subscription.unsubscribe().unwrap();
+        assert!(subscriber.is_closed());
+        assert!(subscriber.send(b"late").is_err());
+        assert_eq!(&client.do_request("echo", b"after").unwrap()[..], b"after");
+
+        let mut subscription = client.subscribe("events", b"").unwrap();
+        let subscriber = subscribed.recv().unwrap();
+        subscriber.send(b"last").unwrap();
+        subscriber.close(Err(Status::new(Code::Unavailable, "shutting down")));
+        assert_eq!(&subscription.next().unwrap().unwrap()[..], b"last");
+        assert!(matches!(subscription.next(), Some(Err(Error::RemoteError { code: Code::Unavailable, .. }))));
+        assert!(subscription.next().is_none());
+        drop(subscription);
+
+        let mut subscription = client.subscribe("refused", b"").unwrap();
+        assert!(matches!(subscription.next(), Some(Err(Error::RemoteError { code: Code::PermissionDenied, .. }))));
+        drop(subscription);
+
+        assert!(!client.is_broken());
+        assert_eq!(&client.do_request("echo", b"again").unwrap()[..], b"again");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use std::thread;
+
+use crate::{Server, Status};
+
+pub(crate) fn socket_path() -> String {
+    std::env::temp_dir()
+        .join(format!("unixconn-{}.sock", uuid::Uuid::new_v4()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Starts a server answering `echo` on a fresh socket and returns its path.
+pub(crate) fn echo_server(workers: usize) -> String {
+    let path = socket_path();
+    let mut server = Server::bind(&path).unwrap();
+    server.set_workers(workers);
+    server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+    thread::spawn(move || server.run());
+    path
+}
+
+/// A seeded source of test inputs for property tests, leaning towards the
+/// bytes the framings give meaning to.
+pub(crate) struct Gen(u64);
+
+const SPECIAL_BYTES: [u8; 7] = [0x1B, 0x1D, 0x1E, 0x1F, b'=', 0x00, 0xFF];
+
+const SPECIAL_CHARS: [char; 9] = ['\u{1b}', '\u{1d}', '\u{1e}', '\u{1f}', '=', '\0', 'é', '日', '🦀'];
+
+impl Gen {
+    fn next(&mut self) -> u64 {
+        // xorshift64*, which is plenty for picking test cases.
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.below(max_len + 1);
+        (0..len)
+            .map(|_| match self.below(3) {
+                0 => SPECIAL_BYTES[self.below(SPECIAL_BYTES.len())],
+                _ => self.next() as u8,
+            })
+            .collect()
+    }
+
+    /// A string of up to `max_chars` characters, none of them in `excluded`.
+    pub(crate) fn string(&mut self, max_chars: usize, excluded: &[char]) -> String {
+        let len = self.below(max_chars + 1);
+        let mut string = String::new();
+        while string.chars().count() < len {
+            let c = match self.below(3) {
+                0 => SPECIAL_CHARS[self.below(SPECIAL_CHARS.len())],
+                _ => char::from_u32(self.below(0x800) as u32).unwrap_or('?'),
+            };
+            if !excluded.contains(&c) {
+                string.push(c);
+            }
+        }
+        string
+    }
+}
+
+/// Checks `property` against `cases` generated inputs. A failing case
+/// prints the seed that reproduces it, to be passed back through
+/// `UNIXCONN_TEST_SEED`.
+pub(crate) fn for_all(cases: u64, mut property: impl FnMut(&mut Gen)) {
+    let seeds = match std::env::var("UNIXCONN_TEST_SEED") {
+        Ok(seed) => {
+            let seed = seed.parse().expect("UNIXCONN_TEST_SEED is not a number");
+            seed..seed + 1
+        }
+        Err(_) => 1..cases + 1,
+    };
+    for seed in seeds {
+        let mut gen = Gen(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1);
+        if let Err(panic) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| property(&mut gen))) {
+            eprintln!("property failed with UNIXCONN_TEST_SEED={}", seed);
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
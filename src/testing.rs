@@ -0,0 +1,260 @@
+//! Helpers for testing code built on this crate without socket files or
+//! listeners: connections are socket pairs that exist only in the process.
+//! Code that dials a path of its own can be pointed at a [`MockServer`], and
+//! a [`FaultyTransport`] makes a connection misbehave on cue.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::error::Result;
+use crate::fds::FdStream;
+use crate::{Client, ClientBuilder, RequestContext, Server, ShutdownHandle, Status};
+
+pub use crate::faults::{FaultPolicy, FaultyTransport};
+
+/// A client and the server end of its connection, for tests that play the
+/// server by reading and writing frames themselves.
+pub fn pair() -> Result<(Client, UnixStream)> {
+    pair_with(Client::builder("testing"))
+}
+
+/// Like [`pair`], with the client configured by `builder`; its address is
+/// ignored.
+pub fn pair_with(builder: ClientBuilder) -> Result<(Client, UnixStream)> {
+    let (client_end, server_end) = UnixStream::pair()?;
+    let client_end = Mutex::new(Some(client_end));
+    let client = builder
+        .transport(move || {
+            client_end
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+                .map(FdStream::new)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "a test connection cannot be redialled"))
+        })
+        .connect()?;
+    Ok((client, server_end))
+}
+
+/// Serves the handlers of `server` on a thread of their own and returns a
+/// client connected to them. The thread ends when the client is dropped.
+pub fn connect(server: Server) -> Result<Client> {
+    let (client, server_end) = pair()?;
+    thread::spawn(move || server.serve(server_end));
+    Ok(client)
+}
+
+/// A server on a socket file of its own, answering scripted methods with
+/// canned responses and recording every request it gets, for testing
+/// client code without the real backend.
+///
+/// ```
+/// # use unixconn_rust::testing::MockServer;
+/// # use unixconn_rust::{Client, Code, Status};
+/// let mock = MockServer::builder()
+///     .respond("getnssusers", b"alice\nbob")
+///     .fail("lookup", Status::new(Code::NotFound, "no such user"))
+///     .start()?;
+///
+/// let mut client = Client::new(mock.path(), 5)?;
+/// assert_eq!(&client.do_request("getnssusers", b"")?[..], b"alice\nbob");
+/// assert!(client.do_request("lookup", b"carol").is_err());
+///
+/// assert_eq!(&mock.received()[1].body()[..], b"carol");
+/// mock.verify();
+/// # Ok::<(), unixconn_rust::Error>(())
+/// ```
+///
+/// Methods not scripted are answered with [`crate::Code::Unimplemented`],
+/// and recorded too. The server stops and its socket file is removed when
+/// the mock is dropped.
+pub struct MockServer {
+    path: String,
+    scripted: Vec<String>,
+    received: Arc<Mutex<Vec<ReceivedRequest>>>,
+    shutdown: ShutdownHandle,
+    serving: Option<JoinHandle<Result<()>>>,
+}
+
+/// Scripts a [`MockServer`] before starting it.
+#[derive(Default)]
+pub struct MockServerBuilder {
+    responses: HashMap<String, VecDeque<Result<Bytes, Status>>>,
+}
+
+/// A request as a [`MockServer`] received it.
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    method_name: String,
+    body: Bytes,
+    headers: Vec<(String, String)>,
+}
+
+impl MockServer {
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder::default()
+    }
+
+    /// The socket to connect clients to.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Every request received so far, in the order they arrived.
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        self.received.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// How many requests for `method_name` were received.
+    pub fn calls(&self, method_name: &str) -> usize {
+        self.received().iter().filter(|request| request.method_name == method_name).count()
+    }
+
+    /// Panics naming the scripted methods that were never called.
+    pub fn verify(&self) {
+        let uncalled: Vec<_> = self.scripted.iter().filter(|method| self.calls(method) == 0).collect();
+        assert!(uncalled.is_empty(), "scripted methods never called: {:?}", uncalled);
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown(Duration::ZERO);
+        if let Some(serving) = self.serving.take() {
+            let _ = serving.join();
+        }
+    }
+}
+
+impl MockServerBuilder {
+    /// Answers `method_name` with `body`. Responses scripted for one method
+    /// are given in turn, the last one to every call after.
+    pub fn respond(self, method_name: &str, body: &[u8]) -> Self {
+        self.script(method_name, Ok(Bytes::copy_from_slice(body)))
+    }
+
+    /// Answers `method_name` with the error `status`, in turn with its
+    /// other responses as for [`MockServerBuilder::respond`].
+    pub fn fail(self, method_name: &str, status: Status) -> Self {
+        self.script(method_name, Err(status))
+    }
+
+    fn script(mut self, method_name: &str, response: Result<Bytes, Status>) -> Self {
+        self.responses.entry(method_name.to_string()).or_default().push_back(response);
+        self
+    }
+
+    /// Binds a fresh socket in the temporary directory and serves it on a
+    /// thread of its own.
+    pub fn start(self) -> Result<MockServer> {
+        let path = std::env::temp_dir().join(format!("unixconn-mock-{}.sock", uuid::Uuid::new_v4())).to_string_lossy().into_owned();
+        let mut server = Server::bind(&path)?;
+        server.set_workers(4);
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        server.add_middleware(move |context: &RequestContext<'_>| {
+            let request = ReceivedRequest {
+                method_name: context.method_name().to_string(),
+                body: Bytes::copy_from_slice(context.body()),
+                headers: context.headers().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            };
+            recorded.lock().unwrap_or_else(PoisonError::into_inner).push(request);
+            Ok(())
+        });
+
+        let scripted = self.responses.keys().cloned().collect();
+        for (method_name, responses) in self.responses {
+            let responses = Mutex::new(responses);
+            server.register(&method_name, move |_| {
+                let mut responses = responses.lock().unwrap_or_else(PoisonError::into_inner);
+                let response = match responses.len() {
+                    1 => responses[0].clone(),
+                    _ => responses.pop_front().expect("scripted methods have a response"),
+                };
+                response.map(Vec::from)
+            });
+        }
+
+        let shutdown = server.shutdown_handle();
+        let serving = thread::spawn(move || server.run());
+        Ok(MockServer { path, scripted, received, shutdown, serving: Some(serving) })
+    }
+}
+
+impl ReceivedRequest {
+    pub fn method_name(&self) -> &str {
+        &self.method_name
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Framing;
+    use crate::{Code, Error};
+    use std::io::Write;
+
+    #[test]
+    fn clients_reach_handlers_without_a_socket_file() {
+        let mut server = Server::new();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        let mut client = connect(server).unwrap();
+        assert_eq!(&client.do_request("echo", b"in memory").unwrap()[..], b"in memory");
+    }
+
+    #[test]
+    fn tests_can_play_the_server() {
+        let (mut client, mut server_end) = pair().unwrap();
+        let pending = client.send("lookup", b"alice").unwrap();
+        let request = Framing::default().read(&mut io::BufReader::new(&server_end)).unwrap();
+        assert_eq!((request.method_name.as_str(), &request.body[..]), ("lookup", &b"alice"[..]));
+
+        server_end.write_all(&Framing::default().encode(&request.reply("lookup", Ok("uid=1000".into())))).unwrap();
+        assert_eq!(&pending.wait(&mut client).unwrap()[..], b"uid=1000");
+
+        drop(server_end);
+        assert!(matches!(client.do_request("lookup", b"bob"), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn mock_servers_answer_in_script_order_and_record_requests() {
+        let mock = MockServer::builder()
+            .fail("flaky", Status::new(Code::Unavailable, "warming up"))
+            .respond("flaky", b"ready")
+            .respond("idle", b"")
+            .start()
+            .unwrap();
+        let mut client = Client::builder(mock.path()).bearer_token("secret").connect().unwrap();
+
+        assert!(matches!(client.do_request("flaky", b"1"), Err(Error::RemoteError { code: Code::Unavailable, .. })));
+        for _ in 0..2 {
+            assert_eq!(&client.do_request("flaky", b"again").unwrap()[..], b"ready");
+        }
+        assert!(matches!(client.do_request("unscripted", b""), Err(Error::RemoteError { code: Code::Unimplemented, .. })));
+
+        let received = mock.received();
+        assert_eq!((received.len(), mock.calls("flaky")), (4, 3));
+        assert_eq!((received[3].method_name(), received[0].header("authorization")), ("unscripted", Some("Bearer secret")));
+        let verified = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mock.verify()));
+        assert!(verified.is_err(), "idle was never called");
+
+        let path = mock.path().to_string();
+        drop(mock);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,148 @@
+//! W3C Trace Context propagation: the `traceparent` header OpenTelemetry and
+//! other tracers use to join the spans of one request across processes.
+
+use std::fmt;
+
+use uuid::Uuid;
+
+/// The standard header name, sent as an ordinary header so that peers
+/// built on other stacks recognise it.
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// A position in a distributed trace: which trace, and which span in it is
+/// the parent of the next one.
+///
+/// A client given one with [`crate::Client::set_trace_context`] sends each
+/// request as a new span under it, and a handler reads the caller's with
+/// [`crate::RequestContext::trace_context`] and can pass it on to requests
+/// of its own. Recording the spans is left to the tracer in use; the IDs
+/// it reports are those shown by [`TraceContext::trace_id`] and
+/// [`TraceContext::span_id`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    flags: u8,
+}
+
+const SAMPLED: u8 = 0x01;
+
+impl TraceContext {
+    /// Starts a new trace with a random ID.
+    pub fn new_root(sampled: bool) -> Self {
+        TraceContext { trace_id: *Uuid::new_v4().as_bytes(), span_id: random_span_id(), flags: if sampled { SAMPLED } else { 0 } }
+    }
+
+    /// Parses a `traceparent` value, e.g. from a tracer's propagator.
+    /// Versions other than the current one are read by its layout, as the
+    /// specification asks.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let fields: Vec<&str> = traceparent.splitn(5, '-').collect();
+        let (version, trace_id, span_id, flags) = match fields[..] {
+            ["00", trace_id, span_id, flags] => ("00", trace_id, span_id, flags),
+            [version, trace_id, span_id, flags, ..] if version != "00" => (version, trace_id, span_id, flags),
+            _ => return None,
+        };
+        if version.len() != 2 || version == "ff" || hex_bytes::<1>(version).is_none() {
+            return None;
+        }
+        let context = TraceContext { trace_id: hex_bytes(trace_id)?, span_id: hex_bytes(span_id)?, flags: hex_bytes::<1>(flags)?[0] };
+        match context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            true => None,
+            false => Some(context),
+        }
+    }
+
+    /// A new span in the same trace, below this one.
+    pub fn child(&self) -> Self {
+        TraceContext { span_id: random_span_id(), ..*self }
+    }
+
+    /// The trace ID as 32 lowercase hex digits.
+    pub fn trace_id(&self) -> String {
+        hex(&self.trace_id)
+    }
+
+    /// The span ID as 16 lowercase hex digits.
+    pub fn span_id(&self) -> String {
+        hex(&self.span_id)
+    }
+
+    /// Whether the caller records this trace.
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+}
+
+/// Formats the context as a current-version `traceparent` value.
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{}-{}-{:02x}", self.trace_id(), self.span_id(), self.flags)
+    }
+}
+
+fn random_span_id() -> [u8; 8] {
+    let mut span_id = [0; 8];
+    span_id.copy_from_slice(&Uuid::new_v4().as_bytes()[..8]);
+    span_id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes exactly `N` bytes of lowercase hex, as the specification
+/// requires.
+fn hex_bytes<const N: usize>(digits: &str) -> Option<[u8; N]> {
+    if digits.len() != N * 2 || !digits.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(digits.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Server};
+
+    #[test]
+    fn traceparent_values_round_trip_and_bad_ones_are_ignored() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(value).unwrap();
+        assert_eq!(context.to_string(), value);
+        assert_eq!((context.trace_id().as_str(), context.is_sampled()), ("4bf92f3577b34da6a3ce929d0e0e4736", true));
+        assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-future").is_some());
+
+        for bad in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceContext::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn requests_are_sent_as_child_spans() {
+        let mut server = Server::new();
+        server.register_with_context("whereami", |context, _| Ok(context.trace_context().map(|c| c.to_string()).unwrap_or_default().into_bytes()));
+        let mut client = testing::connect(server).unwrap();
+
+        let root = TraceContext::new_root(true);
+        client.set_trace_context(Some(root));
+        let seen = client.do_request("whereami", b"").unwrap();
+        let seen = TraceContext::parse(std::str::from_utf8(&seen).unwrap()).unwrap();
+        assert_eq!((seen.trace_id(), seen.is_sampled()), (root.trace_id(), true));
+        assert_ne!(seen.span_id(), root.span_id());
+
+        client.set_trace_context(None);
+        assert!(client.do_request("whereami", b"").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,541 @@
+//! The connection a [`crate::Client`] runs the protocol over.
+
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A byte stream to the server. Unix sockets are the default and TCP is
+/// supported too; implement this for other sockets, in-memory pipes or
+/// mocks and hand it to [`crate::ClientBuilder::transport`] to reuse the
+/// protocol code as is.
+pub trait Transport: Read + Write + Send {
+    /// A second handle to the same connection, which the client reads
+    /// through while writing through the first.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Bounds how long a read may block; `None` blocks indefinitely. An
+    /// expired read fails with [`io::ErrorKind::WouldBlock`] or
+    /// [`io::ErrorKind::TimedOut`].
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes both directions, for every handle to the connection.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Writes all of `parts`, in order, as one frame. By default they are
+    /// joined and written at once, for transports that take each write as
+    /// a unit, such as packet sockets; stream sockets use
+    /// [`io::Write::write_vectored`] to send them without joining them.
+    fn write_parts(&mut self, parts: &[IoSlice<'_>]) -> io::Result<()> {
+        self.write_all(&parts.iter().flat_map(|part| part.iter().copied()).collect::<Vec<u8>>())
+    }
+
+    /// Writes all of `data` with `fds` passed alongside, for
+    /// [`crate::Client::do_request_with_fds`]. Only unix sockets can carry
+    /// descriptors; the default refuses.
+    fn write_with_fds(&mut self, data: &[u8], fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        let _ = (data, fds);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "transport cannot pass file descriptors"))
+    }
+
+    /// Takes the `count` oldest descriptors passed with the data read so far,
+    /// on the handle the client reads through.
+    fn take_fds(&mut self, count: usize) -> io::Result<Vec<OwnedFd>> {
+        match count {
+            0 => Ok(Vec::new()),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "transport cannot pass file descriptors")),
+        }
+    }
+}
+
+/// Descriptors sent to a bare `UnixStream` are lost, as it reads with
+/// `read(2)`; the client wraps the sockets it dials itself so they arrive.
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn write_parts(&mut self, parts: &[IoSlice<'_>]) -> io::Result<()> {
+        write_all_vectored(self, parts)
+    }
+}
+
+/// For servers reachable only over the network, see
+/// [`crate::Client::connect_tcp`]. Nagle's algorithm is best turned off, as
+/// requests are small and a response is awaited after each.
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn write_parts(&mut self, parts: &[IoSlice<'_>]) -> io::Result<()> {
+        write_all_vectored(self, parts)
+    }
+}
+
+/// Writes all of `parts` with as few `writev` calls as the socket takes.
+pub(crate) fn write_all_vectored<W: Write + ?Sized>(writer: &mut W, parts: &[IoSlice<'_>]) -> io::Result<()> {
+    let mut parts: Vec<IoSlice<'_>> = parts.iter().filter(|part| !part.is_empty()).copied().collect();
+    let mut remaining = &mut parts[..];
+    while !remaining.is_empty() {
+        match writer.write_vectored(remaining) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut remaining, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the unix socket at `address`. On Linux, an address starting
+/// with `@` or a NUL byte names a socket in the abstract namespace instead,
+/// which never leaves a file behind.
+pub(crate) fn connect_unix(address: &str) -> io::Result<UnixStream> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = abstract_name(address) {
+        return UnixStream::connect_addr(&abstract_addr(name)?);
+    }
+    UnixStream::connect(address)
+}
+
+/// Binds a listener at `address`, abstract as with [`connect_unix`]. A
+/// socket file left behind by a server that is no longer running, e.g.
+/// after a crash, is replaced.
+pub(crate) fn bind_unix(address: &str) -> io::Result<UnixListener> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = abstract_name(address) {
+        return UnixListener::bind_addr(&abstract_addr(name)?);
+    }
+    replacing_stale(Path::new(address), || UnixListener::bind(address))
+}
+
+/// Runs `bind`, and again after removing the socket file at `path` if it
+/// failed on one nobody listens behind.
+fn replacing_stale<T>(path: &Path, bind: impl Fn() -> io::Result<T>) -> io::Result<T> {
+    match bind() {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && remove_stale(path)? => bind(),
+        result => result,
+    }
+}
+
+/// Removes the socket file at `path` if connecting to it is refused. Other
+/// kinds of file are never touched.
+fn remove_stale(path: &Path) -> io::Result<bool> {
+    if !fs::symlink_metadata(path)?.file_type().is_socket() {
+        return Ok(false);
+    }
+    match UnixStream::connect(path) {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            fs::remove_file(path)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The socket file a server bound, removed when the server is dropped
+/// unless another one has replaced it since.
+#[derive(Debug)]
+pub(crate) struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    /// Records the file just bound at `address`; abstract sockets have none.
+    pub(crate) fn claim(address: &str) -> Option<SocketFile> {
+        #[cfg(target_os = "linux")]
+        if abstract_name(address).is_some() {
+            return None;
+        }
+        let metadata = fs::symlink_metadata(address).ok()?;
+        Some(SocketFile { path: PathBuf::from(address), dev: metadata.dev(), ino: metadata.ino() })
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let ours = fs::symlink_metadata(&self.path).is_ok_and(|metadata| (metadata.dev(), metadata.ino()) == (self.dev, self.ino));
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Who may connect to a server's socket file, set by
+/// [`crate::Server::bind_with_permissions`] before the file appears at its
+/// path, so there is no window in which anyone else can connect.
+#[derive(Debug, Clone, Default)]
+pub struct SocketPermissions {
+    mode: Option<u32>,
+    owner: Option<u32>,
+    group: Option<u32>,
+}
+
+impl SocketPermissions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Permission bits, e.g. `0o660`; connecting needs write permission.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// The owning user; changing it needs privileges.
+    pub fn owner(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// The owning group, which must be one the process is in unless it is
+    /// privileged.
+    pub fn group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+}
+
+/// Binds a listener at `address` like [`bind_unix`], with the socket file
+/// given `permissions`. The socket is bound in a temporary directory next
+/// to `address` that only the process's user can enter, under a name
+/// eleven bytes longer, and hard-linked into place once they are set. The
+/// listener's own address is then that vanished name, so connecting to it
+/// needs `address` instead. Abstract sockets have no file to set them on
+/// and are refused.
+pub(crate) fn bind_unix_with(address: &str, permissions: &SocketPermissions) -> io::Result<UnixListener> {
+    #[cfg(target_os = "linux")]
+    if abstract_name(address).is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "abstract sockets have no file permissions"));
+    }
+
+    let path = Path::new(address);
+    let staging_dir = create_staging_dir(path)?;
+    let staging = staging_dir.join("s");
+    let bound = UnixListener::bind(&staging).and_then(|listener| {
+        if let Some(mode) = permissions.mode {
+            fs::set_permissions(&staging, Permissions::from_mode(mode))?;
+        }
+        if permissions.owner.is_some() || permissions.group.is_some() {
+            std::os::unix::fs::chown(&staging, permissions.owner, permissions.group)?;
+        }
+        replacing_stale(path, || place(&staging, path))?;
+        Ok(listener)
+    });
+    if bound.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    let _ = fs::remove_dir(&staging_dir);
+    bound
+}
+
+/// Creates a directory next to `path` that only the process's user may
+/// enter, so a socket bound in it is unreachable to others whatever mode
+/// the umask gave it.
+fn create_staging_dir(path: &Path) -> io::Result<PathBuf> {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(format!(".{:08x}", uuid::Uuid::new_v4().as_u128() as u32));
+    let dir = PathBuf::from(dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Moves the socket file at `from` to `to`, failing like bind(2) would if
+/// something is already there rather than replacing it.
+fn place(from: &Path, to: &Path) -> io::Result<()> {
+    // A hard link fails if `to` exists, where rename would replace it.
+    fs::hard_link(from, to).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::from(io::ErrorKind::AddrInUse),
+        _ => e,
+    })?;
+    fs::remove_file(from)
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_name(address: &str) -> Option<&str> {
+    address.strip_prefix('@').or_else(|| address.strip_prefix('\0'))
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<std::os::unix::net::SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    std::os::unix::net::SocketAddr::from_abstract_name(name)
+}
+
+/// Opens a new connection, on connect and on every reconnect.
+#[derive(Clone)]
+pub(crate) struct Dialer(Arc<dyn Fn() -> io::Result<Box<dyn Transport>> + Send + Sync>);
+
+impl fmt::Debug for Dialer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Dialer")
+    }
+}
+
+impl Dialer {
+    pub(crate) fn new<F, T>(dial: F) -> Self
+    where
+        F: Fn() -> io::Result<T> + Send + Sync + 'static,
+        T: Transport + 'static,
+    {
+        Dialer(Arc::new(move || Ok(Box::new(dial()?) as Box<dyn Transport>)))
+    }
+
+    pub(crate) fn dial(&self) -> io::Result<Box<dyn Transport>> {
+        (self.0)()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socket_path;
+    use crate::protocol::Framing;
+    use crate::{Client, Server, Status};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// A unix socket that counts the bytes written through it, and the
+    /// writes.
+    struct Counting {
+        stream: UnixStream,
+        written: Arc<AtomicUsize>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.stream.read(buf)
+        }
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = self.stream.write(buf)?;
+            self.written.fetch_add(n, Ordering::SeqCst);
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    impl Transport for Counting {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Counting { stream: self.stream.try_clone()?, written: self.written.clone(), writes: self.writes.clone() }))
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.stream.set_read_timeout(timeout)
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.stream.set_write_timeout(timeout)
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.stream.shutdown(Shutdown::Both)
+        }
+    }
+
+    #[test]
+    fn clients_run_over_custom_transports_across_reconnects() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let (written, dialed) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (counter, dial_path, dials) = (written.clone(), path.clone(), dialed.clone());
+        let mut client = Client::builder("counting")
+            .auto_reconnect(true)
+            .transport(move || {
+                dials.fetch_add(1, Ordering::SeqCst);
+                Ok(Counting { stream: UnixStream::connect(&dial_path)?, written: counter.clone(), writes: Arc::default() })
+            })
+            .connect()
+            .unwrap();
+
+        assert_eq!(&client.do_request("echo", b"one").unwrap()[..], b"one");
+        assert!(written.load(Ordering::SeqCst) > 0);
+
+        client.close().unwrap();
+        assert_eq!(&client.do_request("echo", b"two").unwrap()[..], b"two");
+        assert_eq!(dialed.load(Ordering::SeqCst), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn each_frame_goes_out_in_one_write() {
+        let path = socket_path();
+        let mut server = Server::bind(&path).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let (counter, dial_path) = (writes.clone(), path.clone());
+        let mut client = Client::builder("counting")
+            .timeout(Some(Duration::from_secs(5)))
+            .transport(move || Ok(Counting { stream: UnixStream::connect(&dial_path)?, written: Arc::default(), writes: counter.clone() }))
+            .connect()
+            .unwrap();
+
+        client.do_request_with_headers("echo", b"with headers", &[("tenant", "a"), ("region", "eu")]).unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        client.notify("echo", b"").unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+        let body = [b'x'; 4];
+        let batch: Vec<(&str, &[u8])> = vec![("echo", &body); 3];
+        assert!(client.do_batch(&batch).iter().all(Result::is_ok));
+        assert_eq!(writes.load(Ordering::SeqCst), 3, "a batch is one write");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn vectored_writes_finish_across_short_writes() {
+        /// Takes a few bytes, of the first part only, per call.
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = buf.len().min(3);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut trickle = Trickle(Vec::new());
+        let parts = [IoSlice::new(b"head"), IoSlice::new(b""), IoSlice::new(b"body"), IoSlice::new(b"!")];
+        write_all_vectored(&mut trickle, &parts).unwrap();
+        assert_eq!(trickle.0, b"headbody!");
+    }
+
+    #[test]
+    fn socket_files_get_their_permissions_before_they_appear() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = socket_path();
+        // SAFETY: getegid cannot fail.
+        let gid = unsafe { libc::getegid() };
+        let mut server = Server::bind_with_permissions(&path, &SocketPermissions::new().mode(0o600).group(gid)).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.permissions().mode() & 0o777, metadata.gid()), (0o600, gid));
+        let mut client = Client::new(&path, 5).unwrap();
+        assert_eq!(&client.do_request("echo", b"private").unwrap()[..], b"private");
+
+        let taken = Server::bind_with_permissions(&path, &SocketPermissions::new().mode(0o600));
+        assert!(matches!(taken, Err(crate::Error::Io(e)) if e.kind() == io::ErrorKind::AddrInUse));
+        let staged = format!("{}.", Path::new(&path).file_name().unwrap().to_string_lossy());
+        let mut entries = fs::read_dir(std::env::temp_dir()).unwrap().filter_map(|entry| entry.ok());
+        assert!(!entries.any(|entry| entry.file_name().to_string_lossy().starts_with(&staged)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn staging_sockets_cannot_be_reached_by_other_users() {
+        let path = socket_path();
+        let dir = create_staging_dir(Path::new(&path)).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::remove_dir(&dir).unwrap();
+
+        let listener = bind_unix_with(&path, &SocketPermissions::new().mode(0o666)).unwrap();
+        let staging = listener.local_addr().unwrap().as_pathname().unwrap().to_owned();
+        assert_eq!(staging.parent().unwrap().parent(), Path::new(&path).parent());
+        assert!(!staging.parent().unwrap().exists());
+        assert!(UnixStream::connect(&staging).is_err());
+        UnixStream::connect(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_socket_files_are_replaced_and_live_ones_kept() {
+        let path = socket_path();
+        drop(UnixListener::bind(&path).unwrap());
+        let server = Server::bind(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+
+        let taken = Server::bind(&path);
+        assert!(matches!(taken, Err(crate::Error::Io(e)) if e.kind() == io::ErrorKind::AddrInUse));
+        drop(server);
+        assert!(!Path::new(&path).exists());
+
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(Server::bind(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn clients_speak_the_protocol_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(conn.try_clone().unwrap());
+            while let Ok(request) = Framing::Delimited.read(&mut reader) {
+                conn.write_all(&Framing::Delimited.encode(&request)).unwrap();
+            }
+        });
+
+        let mut client = Client::connect_tcp(&address, 5).unwrap();
+        assert_eq!(&client.do_request("echo", b"over tcp").unwrap()[..], b"over tcp");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn abstract_sockets_leave_no_file() {
+        let name = format!("@unixconn-{}", uuid::Uuid::new_v4());
+        let mut server = Server::bind(&name).unwrap();
+        server.register("echo", |body| Ok::<_, Status>(body.to_vec()));
+        thread::spawn(move || server.run());
+
+        let mut client = Client::new(&name, 5).unwrap();
+        assert_eq!(&client.do_request("echo", b"abstract").unwrap()[..], b"abstract");
+        assert!(!std::path::Path::new(&name).exists());
+    }
+}
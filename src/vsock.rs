@@ -0,0 +1,101 @@
+//! AF_VSOCK connections between a virtual machine and its host.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::transport::Transport;
+
+/// The context ID of the host, as seen from a guest.
+pub const VMADDR_CID_HOST: u32 = libc::VMADDR_CID_HOST;
+
+/// A connected vsock stream socket.
+pub struct VsockStream {
+    // The std socket calls used on it (read, write, the timeout options,
+    // shutdown, dup) do not depend on the address family, so UnixStream
+    // serves as the owner of the descriptor. Nothing address-specific,
+    // such as peer_addr, is ever called on it.
+    inner: UnixStream,
+}
+
+impl VsockStream {
+    /// Connects to `port` on the machine with context ID `cid`, e.g.
+    /// [`VMADDR_CID_HOST`] from inside a guest.
+    pub fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        // SAFETY: plain socket(2) call; the result is checked before use.
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and is owned by nothing else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_vm is plain data, valid when zeroed.
+        let mut address: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        address.svm_cid = cid;
+        address.svm_port = port;
+        // SAFETY: `address` is a live sockaddr_vm and the length matches it.
+        let result = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(VsockStream { inner: UnixStream::from(fd) })
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for VsockStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(VsockStream { inner: self.inner.try_clone()? }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Client;
+
+    #[test]
+    #[ignore = "needs a unixconn server listening on vsock port 7070 of the host"]
+    fn connects_to_the_host() {
+        let mut client = Client::connect_vsock(VMADDR_CID_HOST, 7070, 5).unwrap();
+        assert!(client.do_request("echo", b"ping").is_ok());
+    }
+}
@@ -0,0 +1,133 @@
+//! Wire compatibility with the Go implementation of unixconn.
+//!
+//! These tests start the Go reference server named by `UNIXCONN_GO_SERVER`
+//! and are ignored by default:
+//!
+//! ```text
+//! UNIXCONN_GO_SERVER=/path/to/unixconn-interop-server cargo test --test interop -- --ignored
+//! ```
+//!
+//! The binary is run with the socket path to listen on as its only
+//! argument. It must answer `fail` with an error whose text is the request
+//! body, and every other method with the request body unchanged.
+
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use unixconn_rust::{Client, Code, Error};
+
+/// A running Go server, killed when dropped.
+struct GoServer {
+    child: Child,
+    path: PathBuf,
+}
+
+impl GoServer {
+    fn start() -> Self {
+        let binary = std::env::var_os("UNIXCONN_GO_SERVER").expect("UNIXCONN_GO_SERVER names the Go interop server");
+        let path = std::env::temp_dir().join(format!("unixconn-interop-{}-{:?}.sock", std::process::id(), thread::current().id()));
+        let _ = std::fs::remove_file(&path);
+        let child = Command::new(binary).arg(&path).spawn().expect("Go interop server starts");
+
+        let started = Instant::now();
+        while !path.exists() {
+            assert!(started.elapsed() < Duration::from_secs(10), "Go interop server never bound {}", path.display());
+            thread::sleep(Duration::from_millis(20));
+        }
+        GoServer { child, path }
+    }
+
+    fn client(&self) -> Client {
+        Client::new(self.path.to_str().unwrap(), 5).unwrap()
+    }
+}
+
+impl Drop for GoServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Bodies the delimited framing can carry: everything but 0x1E and 0x1F.
+fn bodies() -> Vec<(&'static str, Vec<u8>)> {
+    let binary = (0..=255u8).filter(|b| ![0x1E, 0x1F].contains(b)).collect();
+    let large = (0..4 << 20).map(|i| b"0123456789abcdef"[i % 16]).collect();
+    vec![
+        ("empty", Vec::new()),
+        ("ascii", b"hello".to_vec()),
+        ("unicode", "héllo, 世界 🦀".as_bytes().to_vec()),
+        ("binary", binary),
+        ("large", large),
+    ]
+}
+
+#[test]
+#[ignore = "needs the Go interop server named by UNIXCONN_GO_SERVER"]
+fn bodies_come_back_unchanged() {
+    let server = GoServer::start();
+    let mut client = server.client();
+    for (name, body) in bodies() {
+        let response = client.do_request("echo", &body).unwrap_or_else(|e| panic!("{} body: {}", name, e));
+        assert!(response[..] == body[..], "{} body came back changed", name);
+    }
+}
+
+#[test]
+#[ignore = "needs the Go interop server named by UNIXCONN_GO_SERVER"]
+fn unicode_method_names() {
+    let server = GoServer::start();
+    let mut client = server.client();
+    for method in ["эхо", "回声", "echo/🦀", "with space"] {
+        assert_eq!(&client.do_request(method, method.as_bytes()).unwrap()[..], method.as_bytes(), "{}", method);
+    }
+}
+
+#[test]
+#[ignore = "needs the Go interop server named by UNIXCONN_GO_SERVER"]
+fn server_errors_reach_the_caller() {
+    let server = GoServer::start();
+    let mut client = server.client();
+    for message in ["boom", "ошибка: 失败"] {
+        match client.do_request("fail", message.as_bytes()) {
+            Err(Error::RemoteError { code: Code::Unknown, message: received }) => assert_eq!(received, message),
+            other => panic!("expected the server's error, got {:?}", other.map(|body| body.len())),
+        }
+    }
+    // An error answer leaves the connection usable.
+    assert_eq!(&client.do_request("echo", b"still here").unwrap()[..], b"still here");
+}
+
+#[test]
+#[ignore = "needs the Go interop server named by UNIXCONN_GO_SERVER"]
+fn pipelined_requests_are_matched_to_their_responses() {
+    let server = GoServer::start();
+    let mut client = server.client();
+    let pending: Vec<_> = (0..32).map(|i| client.send("echo", i.to_string().as_bytes()).unwrap()).collect();
+    for (i, request) in pending.into_iter().enumerate() {
+        assert_eq!(request.wait(&mut client).unwrap(), i.to_string().as_bytes());
+    }
+}
+
+#[test]
+#[ignore = "needs the Go interop server named by UNIXCONN_GO_SERVER"]
+fn concurrent_connections() {
+    let server = GoServer::start();
+    let workers: Vec<_> = (0..8)
+        .map(|worker| {
+            let mut client = server.client();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let body = format!("{}-{}", worker, i);
+                    assert_eq!(client.do_request("echo", body.as_bytes()).unwrap(), body.as_bytes());
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}